@@ -5,39 +5,33 @@ in vec2 v_tex_coords;
 out vec4 o_color;
 
 uniform sampler2D stroke_ink_quantity_tex;
+// Arc length and distance from center line of every inked pixel, see ink.fs.
+uniform sampler2D stroke_frame_tex;
 
-uniform sampler2D level_0_brush_tex;
-uniform sampler2D level_1_brush_tex;
-uniform sampler2D level_2_brush_tex;
-uniform sampler2D level_3_brush_tex;
-uniform sampler2D level_4_brush_tex;
+// Levels of one texture set, level 0 is used where least ink is left.
+uniform sampler2DArray brush_texs;
+uniform int level_count;
+// Only pixels painted by strokes of this texture set are drawn.
+uniform float texture_set_slot;
 
-#define BRUSH_NUM 5
+// Size of one brush texture tile on canvas, in pixels.
+uniform float brush_tex_size;
 
 void main() {
-    float ink_quantity = texture(stroke_ink_quantity_tex, v_tex_coords).r;
-    int tex_num = int(ceil(ink_quantity * BRUSH_NUM));
-    if (ink_quantity == 0.0) {
+    vec4 ink = texture(stroke_ink_quantity_tex, v_tex_coords);
+    float ink_quantity = ink.r;
+    if (ink_quantity == 0.0 || abs(ink.g - texture_set_slot) > 0.5 / 255.0) {
         discard;
     }
 
-    switch (tex_num) {
-    case 1:
-        o_color = 2 * ink_quantity * texture(level_0_brush_tex, v_tex_coords * 900.0 / 32.0);
-        break;
-    case 2:
-        o_color = 2 * ink_quantity * texture(level_1_brush_tex, v_tex_coords * 900.0 / 32.0);
-        break;
-    case 3:
-        o_color = 2 * ink_quantity * texture(level_2_brush_tex, v_tex_coords * 900.0 / 32.0);
-        break;
-    case 4:
-        o_color = 2 * ink_quantity * texture(level_3_brush_tex, v_tex_coords * 900.0 / 32.0);
-        break;
-    case 5:
-        o_color = 2 * ink_quantity * texture(level_4_brush_tex, v_tex_coords * 900.0 / 32.0);
-        break;
-    default:
+    int level = int(ceil(ink_quantity * level_count)) - 1;
+    if (level < 0 || level >= level_count) {
         discard;
     }
+
+    // Texture x axis follows the stroke, so streaks of a texture set run along the brush path.
+    vec2 stroke_frame = texture(stroke_frame_tex, v_tex_coords).rg;
+    vec2 brush_tex_coords = stroke_frame / brush_tex_size;
+
+    o_color = 2 * ink_quantity * texture(brush_texs, vec3(brush_tex_coords, level));
 }
@@ -5,10 +5,11 @@ out vec4 o_color;
 uniform float radius;
 uniform vec2 center;
 uniform vec4 brush_color;
+uniform vec2 canvas_size;
 
 void main() {
     vec2 pos = gl_FragCoord.xy;
-    pos.y = 900 - pos.y;
+    pos.y = canvas_size.y - pos.y;
 
     float dist = distance(pos, center);
 
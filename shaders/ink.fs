@@ -5,6 +5,8 @@
 in vec2 v_tex_coords;
 
 out vec4 o_color;
+// r: arc length, g: signed distance from center line, b: direction of segment.
+out vec4 o_stroke_frame;
 
 uniform sampler2D stroke_outline_tex;
 uniform sampler2D stroke_ink_quantity_tmp_tex;
@@ -18,6 +20,12 @@ uniform float end_radius;
 uniform float start_ink_quantity;
 uniform float end_ink_quantity;
 
+// Arc length of stroke at start_pos, in pixels.
+uniform float start_arc_length;
+
+// Texture set of the stroke, stored in green channel for black_n_white_brush.fs.
+uniform float texture_set_slot;
+
 float cross2d(vec2 a, vec2 b) {
     return (a.x * b.y) - (a.y * b.x);
 }
@@ -118,5 +126,14 @@ void main() {
         discard;
     }
 
-    o_color = vec4(new_ink_quantity, 0, 1, 1);
+    o_color = vec4(new_ink_quantity, texture_set_slot, 1, 1);
+
+    // Caps continue the stroke frame of their segment.
+    float along = clamp(dot(start_to_pos_v, start_to_end_v_norm),
+                        0.0,
+                        distance(start_pos, end_pos));
+    float across = dot(start_to_pos_v, start_to_end_n_norm);
+    float direction = atan(start_to_end_v_norm.y, start_to_end_v_norm.x);
+
+    o_stroke_frame = vec4(start_arc_length + along, across, direction, 1);
 }
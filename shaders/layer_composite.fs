@@ -0,0 +1,50 @@
+#version 150 core
+
+in vec2 v_tex_coords;
+
+out vec4 o_color;
+
+// Layers below, already composited.
+uniform sampler2D backdrop_tex;
+uniform sampler2D layer_tex;
+
+uniform float opacity;
+// Same order as `BlendMode`.
+uniform int blend_mode;
+
+#define BLEND_MULTIPLY 1
+#define BLEND_SCREEN 2
+#define BLEND_DARKEN 3
+#define BLEND_LIGHTEN 4
+
+vec3 blend(vec3 backdrop, vec3 source) {
+    switch (blend_mode) {
+    case BLEND_MULTIPLY:
+        return backdrop * source;
+    case BLEND_SCREEN:
+        return backdrop + source - backdrop * source;
+    case BLEND_DARKEN:
+        return min(backdrop, source);
+    case BLEND_LIGHTEN:
+        return max(backdrop, source);
+    default:
+        return source;
+    }
+}
+
+void main() {
+    vec4 backdrop = texture(backdrop_tex, v_tex_coords);
+    vec4 source = texture(layer_tex, v_tex_coords);
+    source.a = clamp(source.a, 0.0, 1.0) * opacity;
+
+    // Blend mode only applies where there is something below, see W3C Compositing and Blending.
+    vec3 color = mix(source.rgb, blend(backdrop.rgb, source.rgb), backdrop.a);
+
+    float alpha = source.a + backdrop.a * (1.0 - source.a);
+    if (alpha == 0.0) {
+        discard;
+    }
+
+    o_color = vec4((color * source.a + backdrop.rgb * backdrop.a * (1.0 - source.a)) / alpha,
+                   alpha);
+}
@@ -0,0 +1,32 @@
+#version 150 core
+
+// Move suspended pigment along the velocity field with a semi-Lagrangian step.
+//
+// pigment_tex: r = suspended pigment, g = deposited pigment.
+
+in vec2 v_tex_coords;
+
+out vec4 o_color;
+
+uniform sampler2D shallow_water_tex;
+uniform sampler2D pigment_tex;
+
+uniform vec2 texel_size;
+
+uniform float dt;
+
+void main() {
+    vec4 water = texture(shallow_water_tex, v_tex_coords);
+    vec4 pigment = texture(pigment_tex, v_tex_coords);
+
+    if (water.a == 0.0) {
+        o_color = pigment;
+        return;
+    }
+
+    vec2 from_coords = v_tex_coords - water.xy * dt * texel_size;
+    vec4 from_water = texture(shallow_water_tex, from_coords);
+    float suspended = texture(pigment_tex, from_coords).r * from_water.a;
+
+    o_color = vec4(suspended, pigment.gba);
+}
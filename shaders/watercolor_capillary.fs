@@ -0,0 +1,55 @@
+#version 150 core
+
+// Capillary flow of water through the paper (Curtis et al. 1997, section 4.6).
+//
+// capillary_tex: r = water saturation, g = fluid-holding capacity,
+//                b = paper height.
+
+in vec2 v_tex_coords;
+
+out vec4 o_color;
+
+uniform sampler2D shallow_water_tex;
+uniform sampler2D capillary_tex;
+
+uniform vec2 texel_size;
+
+uniform float absorption;
+uniform float diffusion_threshold;
+uniform float receive_threshold;
+
+vec4 fetch(vec2 offset) {
+    return texture(capillary_tex, v_tex_coords + offset * texel_size);
+}
+
+// Amount of water flowing from `from` into `to`.
+float flow(vec4 from, vec4 to) {
+    if (from.r < diffusion_threshold || from.r <= to.r || to.r < receive_threshold) {
+        return 0.0;
+    }
+
+    return max(0.0, min(from.r - to.r, to.g - to.r) / 4.0);
+}
+
+void main() {
+    vec4 center = fetch(vec2(0.0, 0.0));
+    float wet = texture(shallow_water_tex, v_tex_coords).a;
+
+    float saturation = center.r;
+
+    // Absorb water from the shallow-water layer.
+    saturation += absorption * wet;
+    saturation = clamp(saturation, 0.0, center.g);
+
+    vec4 neighbours[4] = vec4[](fetch(vec2(-1.0, 0.0)),
+                                fetch(vec2(1.0, 0.0)),
+                                fetch(vec2(0.0, -1.0)),
+                                fetch(vec2(0.0, 1.0)));
+
+    for (int i = 0; i < 4; ++i) {
+        saturation += flow(neighbours[i], center);
+        saturation -= flow(center, neighbours[i]);
+    }
+
+    o_color = vec4(clamp(saturation, 0.0, 1.0), center.gba);
+}
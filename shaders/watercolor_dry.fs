@@ -0,0 +1,28 @@
+#version 150 core
+
+// Dry the current wash: every pigment still in the water settles on the paper,
+// and the settled pigment is glazed over the passive layer.
+//
+// pigment_tex: r = suspended pigment, g = deposited pigment.
+
+in vec2 v_tex_coords;
+
+out vec4 o_color;
+
+uniform sampler2D passive_layer_tex;
+uniform sampler2D pigment_tex;
+
+uniform vec4 brush_color;
+
+void main() {
+    vec4 passive = texture(passive_layer_tex, v_tex_coords);
+    vec4 pigment = texture(pigment_tex, v_tex_coords);
+
+    float coverage = clamp((pigment.r + pigment.g) * brush_color.a, 0.0, 1.0);
+
+    // Subtractive glazing: pigment filters the light reflected by the layers
+    // below it.
+    vec3 glazed = passive.rgb * mix(vec3(1.0), brush_color.rgb, coverage);
+
+    o_color = vec4(glazed, max(passive.a, coverage));
+}
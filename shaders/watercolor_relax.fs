@@ -0,0 +1,47 @@
+#version 150 core
+
+// Relax the divergence of the velocity field into water pressure, grow the
+// wet area where the paper got saturated, and push water towards the edges of
+// the wet area to produce edge darkening.
+
+in vec2 v_tex_coords;
+
+out vec4 o_color;
+
+uniform sampler2D shallow_water_tex;
+uniform sampler2D capillary_tex;
+
+uniform vec2 texel_size;
+
+uniform float relaxation;
+uniform float edge_darkening;
+uniform float saturation_threshold;
+
+vec4 fetch(vec2 offset) {
+    return texture(shallow_water_tex, v_tex_coords + offset * texel_size);
+}
+
+void main() {
+    vec4 center = fetch(vec2(0.0, 0.0));
+    vec4 left = fetch(vec2(-1.0, 0.0));
+    vec4 right = fetch(vec2(1.0, 0.0));
+    vec4 down = fetch(vec2(0.0, -1.0));
+    vec4 up = fetch(vec2(0.0, 1.0));
+
+    float saturation = texture(capillary_tex, v_tex_coords).r;
+
+    float wet = center.a;
+    if (saturation > saturation_threshold) {
+        wet = 1.0;
+    }
+
+    float divergence = (right.x - left.x + up.y - down.y) * 0.5;
+    float pressure = center.b - relaxation * divergence;
+
+    // Wet texels close to dry texels lose pressure, so water keeps flowing
+    // outward and carries pigment to the boundary.
+    float blurred_wet = (left.a + right.a + down.a + up.a + center.a) / 5.0;
+    pressure -= edge_darkening * (1.0 - blurred_wet) * wet;
+
+    o_color = vec4(center.xy, max(pressure, 0.0) * wet, wet);
+}
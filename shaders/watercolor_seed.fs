@@ -0,0 +1,25 @@
+#version 150 core
+
+in vec2 v_tex_coords;
+
+out vec4 o_color;
+
+uniform sampler2D current_tex;
+uniform sampler2D stroke_mask_tex;
+
+// Added to every texel covered by the stroke.
+uniform vec4 seed_add;
+// Lower bound of every texel covered by the stroke.
+uniform vec4 seed_min;
+
+void main() {
+    vec4 old_value = texture(current_tex, v_tex_coords);
+    float mask = texture(stroke_mask_tex, v_tex_coords).a;
+
+    if (mask == 0.0) {
+        o_color = old_value;
+        return;
+    }
+
+    o_color = max(old_value + seed_add * mask, seed_min * mask);
+}
@@ -0,0 +1,45 @@
+#version 150 core
+
+// Pigment adsorption and desorption between the shallow-water layer and the
+// pigment-deposition layer (Curtis et al. 1997, section 4.5).
+//
+// pigment_tex: r = suspended pigment, g = deposited pigment.
+
+in vec2 v_tex_coords;
+
+out vec4 o_color;
+
+uniform sampler2D shallow_water_tex;
+uniform sampler2D pigment_tex;
+uniform sampler2D capillary_tex;
+
+uniform float density;
+uniform float staining_power;
+uniform float granulation;
+
+void main() {
+    float wet = texture(shallow_water_tex, v_tex_coords).a;
+    vec4 pigment = texture(pigment_tex, v_tex_coords);
+
+    if (wet == 0.0) {
+        o_color = pigment;
+        return;
+    }
+
+    float height = texture(capillary_tex, v_tex_coords).b;
+
+    float suspended = pigment.r;
+    float deposited = pigment.g;
+
+    float down = suspended * (1.0 - height * granulation) * density;
+    float up = deposited * (1.0 + (height - 1.0) * granulation) * density / staining_power;
+
+    if (deposited + down > 1.0) {
+        down = max(0.0, 1.0 - deposited);
+    }
+    if (suspended + up > 1.0) {
+        up = max(0.0, 1.0 - suspended);
+    }
+
+    o_color = vec4(suspended + up - down, deposited + down - up, pigment.ba);
+}
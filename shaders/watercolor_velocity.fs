@@ -0,0 +1,70 @@
+#version 150 core
+
+// Shallow-water layer velocity update (Curtis et al. 1997, section 4.3.1).
+//
+// shallow_water_tex: r = u, g = v, b = water pressure, a = wet-area mask.
+
+in vec2 v_tex_coords;
+
+out vec4 o_color;
+
+uniform sampler2D shallow_water_tex;
+uniform sampler2D capillary_tex;
+
+uniform vec2 texel_size;
+
+uniform float dt;
+uniform float viscosity;
+uniform float viscous_drag;
+uniform float paper_slope;
+
+vec4 fetch(vec2 offset) {
+    return texture(shallow_water_tex, v_tex_coords + offset * texel_size);
+}
+
+float paper_height(vec2 offset) {
+    return texture(capillary_tex, v_tex_coords + offset * texel_size).b;
+}
+
+void main() {
+    vec4 center = fetch(vec2(0.0, 0.0));
+    float wet = center.a;
+
+    if (wet == 0.0) {
+        o_color = vec4(0.0, 0.0, center.b, 0.0);
+        return;
+    }
+
+    vec4 left = fetch(vec2(-1.0, 0.0));
+    vec4 right = fetch(vec2(1.0, 0.0));
+    vec4 down = fetch(vec2(0.0, -1.0));
+    vec4 up = fetch(vec2(0.0, 1.0));
+
+    vec2 vel = center.xy;
+
+    // Self advection, -(u . grad) u.
+    vec2 du_dx = (right.xy - left.xy) * 0.5;
+    vec2 du_dy = (up.xy - down.xy) * 0.5;
+    vec2 advection = -(vel.x * du_dx + vel.y * du_dy);
+
+    // Viscous diffusion, mu * laplacian(u).
+    vec2 laplacian = left.xy + right.xy + down.xy + up.xy - 4.0 * vel;
+
+    // Pressure gradient, -grad(p).
+    vec2 pressure_gradient = vec2(right.b - left.b, up.b - down.b) * 0.5;
+
+    // Water runs downhill on the paper surface.
+    vec2 slope = vec2(paper_height(vec2(1.0, 0.0)) - paper_height(vec2(-1.0, 0.0)),
+                      paper_height(vec2(0.0, 1.0)) - paper_height(vec2(0.0, -1.0))) * 0.5;
+
+    vec2 new_vel = vel + dt * (advection +
+                               viscosity * laplacian -
+                               viscous_drag * vel -
+                               pressure_gradient -
+                               paper_slope * slope);
+
+    // Keep the explicit scheme stable.
+    new_vel = clamp(new_vel, vec2(-1.0), vec2(1.0));
+
+    o_color = vec4(new_vel * wet, center.b, wet);
+}
@@ -14,6 +14,10 @@ uniform vec2 stroke_end_pos;
 uniform float start_radius;
 uniform float end_radius;
 
+uniform vec2 canvas_size;
+// Longest distance pigment is wiped along, in pixels.
+uniform float wipe_distance;
+
 float cross2d(vec2 a, vec2 b) {
     return (a.x * b.y) - (a.y * b.x);
 }
@@ -117,7 +121,7 @@ main() {
     vec2 start_to_end_v_norm = normalize(stroke_vector);
 
     float alpha = 1;
-    float beta = 120;
+    float beta = wipe_distance;
     vec2 wipe_vector = start_to_end_v_norm * min(beta, length(stroke_vector)) * alpha;
     float wipe_dist = length(wipe_vector);
 
@@ -148,7 +152,7 @@ main() {
     int y = int(round(pos.y));
     for (int x = int(round(a.x)); x < int(round(b.x)); ++x) {
         vec2 wipe_pos = vec2(x, y);
-        vec2 wipe_uv = wipe_pos / 900;
+        vec2 wipe_uv = wipe_pos / canvas_size;
 
         float dist = distance(pos, wipe_pos);
 
@@ -0,0 +1,183 @@
+use rand::{Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+use vecmath;
+
+use ink;
+use states::{OneStroke, StrokeAnchor};
+
+/// Most bristles a brush can have.
+pub const MAX_BRISTLE_COUNT: u32 = 64;
+
+/// Bristles stick together in clumps of this many once the brush runs dry.
+const CLUMP_SIZE: usize = 4;
+
+/// Same for every stroke, so one brush always has the same bristles.
+const BRISTLE_SEED: [u8; 16] = [0x54, 0x67, 0x3a, 0x19, 0x69, 0xd4, 0xa7, 0xa8,
+                                0x05, 0x0e, 0x83, 0x97, 0xbb, 0xa7, 0x3b, 0x11];
+
+/// Ink laid down by one bristle between two consecutive anchors, in canvas units.
+///
+/// Ink quantity is a fraction of the initial ink of a bristle, like the ink quantity of a
+/// solid brush.
+pub struct BristleSegment {
+    // Index of anchor segment starts at.
+    pub anchor: usize,
+
+    pub start_pos: [f32; 2],
+    pub start_radius: f32,
+    pub start_ink_quantity: f32,
+
+    pub end_pos: [f32; 2],
+    pub end_radius: f32,
+    pub end_ink_quantity: f32,
+}
+
+struct Bristle {
+    // Position across brush tip, from -1 to 1.
+    offset: f32,
+    // Center of clump this bristle joins when drying out, same scale as `offset`.
+    clump_offset: f32,
+    // How fast this bristle gives off ink compared to the others.
+    wear: f32,
+    // Fraction of initial ink left.
+    ink: f32,
+}
+
+fn build_bristles(count: usize) -> Vec<Bristle> {
+    let mut rng = XorShiftRng::from_seed(BRISTLE_SEED);
+
+    let offsets: Vec<f32> = (0..count)
+        .map(|i| {
+            let jitter = rng.gen_range(-0.3..0.3);
+            2.0 * (i as f32 + 0.5 + jitter) / count as f32 - 1.0
+        })
+        .collect();
+
+    (0..count)
+        .map(|i| {
+            let clump = i / CLUMP_SIZE * CLUMP_SIZE;
+            let clump_end = (clump + CLUMP_SIZE).min(count);
+            let clump_offset = offsets[clump..clump_end].iter().sum::<f32>() /
+                               (clump_end - clump) as f32;
+
+            Bristle {
+                offset: offsets[i],
+                clump_offset,
+                // Outer bristles touch paper first and wear out sooner.
+                wear: rng.gen_range(0.8..1.2) + 0.5 * offsets[i].abs(),
+                ink: rng.gen_range(0.85..1.0),
+            }
+        })
+        .collect()
+}
+
+/// Direction across stroke at every anchor, averaged over both neighbouring segments.
+fn anchor_normals(anchors: &[StrokeAnchor]) -> Vec<[f32; 2]> {
+    (0..anchors.len())
+        .map(|i| {
+            let prev = &anchors[i.saturating_sub(1)].pos;
+            let next = &anchors[(i + 1).min(anchors.len() - 1)].pos;
+
+            let tangent = vecmath::vec2_sub(*next, *prev);
+            if vecmath::vec2_len(tangent) < 1e-4 {
+                return [0.0, 1.0];
+            }
+
+            let tangent = vecmath::vec2_normalized(tangent);
+            [-tangent[1], tangent[0]]
+        })
+        .collect()
+}
+
+/// Split `stroke` into the trajectories of the bristles of its brush.
+///
+/// `ink_load` is the fraction of a full load the brush starts with, `radius` the radius of the
+/// whole brush at an anchor and `ink_cost` the ink a solid brush of the same size would lose
+/// between two anchors, see `ink::segment_cost`. Every bristle carries its own share
+/// of that ink.
+///
+/// Bristles spread apart with pressure. Once the brush runs low on ink, they thin out and
+/// gather into clumps, and bristles which ran dry leave paper white, i.e. flying white.
+pub fn bristle_segments<R, C>(stroke: &OneStroke,
+                              ink_load: f32,
+                              radius: R,
+                              ink_cost: C)
+                              -> Vec<BristleSegment>
+    where R: Fn(&StrokeAnchor) -> f32,
+          C: Fn(&StrokeAnchor, f32, &StrokeAnchor, f32) -> f32
+{
+    let brush = &stroke.brush;
+    let anchors = &stroke.anchors;
+    if brush.bristle_count == 0 || anchors.len() < 2 {
+        return Vec::new();
+    }
+
+    let count = brush.bristle_count as usize;
+    let mut bristles = build_bristles(count);
+    for bristle in &mut bristles {
+        bristle.ink *= ink_load;
+    }
+    let normals = anchor_normals(anchors);
+
+    // Where bristle sits at `anchor`, and how wide a mark it leaves there.
+    let place = |bristle: &Bristle, anchor: usize, mean_ink: f32| {
+        let stroke_anchor = &anchors[anchor];
+        let brush_radius = radius(stroke_anchor);
+
+        let spread = 1.0 - brush.bristle_spread * (1.0 - stroke_anchor.pressure.min(1.0));
+        let split = if brush.bristle_split > 0.0 {
+            ((brush.bristle_split - mean_ink) / brush.bristle_split).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        let offset = bristle.offset + (bristle.clump_offset - bristle.offset) * split;
+
+        let pos = vecmath::vec2_add(stroke_anchor.pos,
+                                    vecmath::vec2_scale(normals[anchor],
+                                                        offset * spread * brush_radius));
+
+        // Bristles overlap a little while wet, so a full brush leaves a solid mark.
+        let wet_radius = 1.5 * brush_radius / count as f32;
+        let radius = wet_radius * (0.4 + 0.6 * bristle.ink.clamp(0.0, 1.0)) *
+                     (1.0 - 0.5 * split);
+
+        (pos, radius)
+    };
+
+    let mut segments = Vec::new();
+    for anchor in 0..anchors.len() - 1 {
+        let (start_anchor, end_anchor) = (&anchors[anchor], &anchors[anchor + 1]);
+
+        let start_radius = radius(start_anchor);
+        let end_radius = radius(end_anchor);
+        let cost = ink::load_fraction(ink_cost(start_anchor, start_radius, end_anchor, end_radius),
+                                      brush.initial_ink_quantity);
+
+        let mean_ink = bristles.iter().map(|bristle| bristle.ink).sum::<f32>() / count as f32;
+
+        for bristle in &mut bristles {
+            let start_ink = bristle.ink;
+            let (start_pos, start_bristle_radius) = place(&*bristle, anchor, mean_ink);
+
+            bristle.ink -= cost * bristle.wear;
+
+            // Dry bristles leave paper white.
+            if start_ink <= 0.0 {
+                continue;
+            }
+
+            let (end_pos, end_bristle_radius) = place(&*bristle, anchor + 1, mean_ink);
+            segments.push(BristleSegment {
+                anchor,
+                start_pos,
+                start_radius: start_bristle_radius,
+                start_ink_quantity: start_ink,
+                end_pos,
+                end_radius: end_bristle_radius,
+                end_ink_quantity: bristle.ink.max(0.0),
+            });
+        }
+    }
+
+    segments
+}
@@ -0,0 +1,187 @@
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use gif;
+use glium::backend::Facade;
+use image::RgbaImage;
+
+use renderer::Renderer;
+use replay::Replay;
+use states::States;
+
+/// How frames of a build-up are written.
+#[derive(Clone, Copy, PartialEq)]
+pub enum FrameFormat {
+    // One looping animated GIF.
+    Gif,
+    // `frame0000.png`, `frame0001.png`, ... in a directory.
+    PngSequence,
+}
+
+/// Which moments of painting become frames.
+#[derive(Clone, Copy, PartialEq)]
+pub enum FrameTiming {
+    // Whenever a stroke is finished.
+    EveryStroke,
+    // Every `BuildUpSettings::interval` seconds of replay, following anchor timing.
+    FixedInterval,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct BuildUpSettings {
+    pub format: FrameFormat,
+    pub timing: FrameTiming,
+    // Seconds of replay between frames, only used with `FrameTiming::FixedInterval`.
+    pub interval: f32,
+    // Seconds every frame is shown in a GIF.
+    pub frame_delay: f32,
+}
+
+impl Default for BuildUpSettings {
+    fn default() -> Self {
+        BuildUpSettings {
+            format: FrameFormat::Gif,
+            timing: FrameTiming::EveryStroke,
+            interval: 0.5,
+            frame_delay: 0.2,
+        }
+    }
+}
+
+pub enum BuildUpError {
+    Io(io::Error),
+    Gif(gif::EncodingError),
+    Image(image::ImageError),
+    NoStrokes,
+    // GIF frames are at most 65535 pixels wide and tall.
+    TooLarge(u32, u32),
+}
+
+impl fmt::Display for BuildUpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BuildUpError::Io(ref err) => write!(f, "io error: {}", err),
+            BuildUpError::Gif(ref err) => write!(f, "gif error: {}", err),
+            BuildUpError::Image(ref err) => write!(f, "image error: {}", err),
+            BuildUpError::NoStrokes => write!(f, "painting has no strokes"),
+            BuildUpError::TooLarge(w, h) => write!(f, "{}x{} is too large for gif", w, h),
+        }
+    }
+}
+
+impl fmt::Debug for BuildUpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl From<io::Error> for BuildUpError {
+    fn from(err: io::Error) -> Self {
+        BuildUpError::Io(err)
+    }
+}
+
+impl From<gif::EncodingError> for BuildUpError {
+    fn from(err: gif::EncodingError) -> Self {
+        BuildUpError::Gif(err)
+    }
+}
+
+impl From<image::ImageError> for BuildUpError {
+    fn from(err: image::ImageError) -> Self {
+        BuildUpError::Image(err)
+    }
+}
+
+/// Replay times of every frame, in order.
+///
+/// Fixed intervals always end with a frame of the finished painting.
+pub fn frame_times(replay: &Replay, settings: &BuildUpSettings) -> Vec<f32> {
+    let mut times = match settings.timing {
+        FrameTiming::EveryStroke => replay.stroke_end_times(),
+        FrameTiming::FixedInterval => {
+            // Too short an interval would never end.
+            let interval = settings.interval.max(0.01);
+            let count = (replay.duration / interval).ceil() as usize;
+
+            (0..count + 1).map(|i| (i as f32 * interval).min(replay.duration)).collect()
+        }
+    };
+    times.dedup();
+
+    times
+}
+
+/// Render how `states` was painted frame by frame and write the frames into `path`, a GIF
+/// file or a directory of PNG files.
+///
+/// Frames go through `Renderer::construct_brush_tex_of`, so they look exactly like the live
+/// preview. Layer caches of `renderer` are left holding the last frame. Returns number of
+/// frames written.
+pub fn export<F: Facade, P: AsRef<Path>>(renderer: &mut Renderer<F>,
+                                         states: &States,
+                                         settings: &BuildUpSettings,
+                                         path: P)
+                                         -> Result<usize, BuildUpError> {
+    let replay = Replay::new(&states.layers);
+    if replay.stroke_progress().1 == 0 {
+        return Err(BuildUpError::NoStrokes);
+    }
+    let times = frame_times(&replay, settings);
+
+    let (w, h) = renderer.brush_preview_tex.dimensions();
+    let mut render_frame = |time: f32| {
+        renderer.construct_brush_tex_of(states, &replay.layers_at(time));
+        renderer.read_brush_preview_image()
+    };
+
+    match settings.format {
+        FrameFormat::Gif => {
+            if w > u16::MAX as u32 || h > u16::MAX as u32 {
+                return Err(BuildUpError::TooLarge(w, h));
+            }
+
+            let mut encoder = gif::Encoder::new(File::create(path)?, w as u16, h as u16, &[])?;
+            encoder.set_repeat(gif::Repeat::Infinite)?;
+
+            // Centiseconds.
+            let delay = (settings.frame_delay * 100.0).round().max(1.0) as u16;
+            for &time in &times {
+                let mut pixels = onto_paper(render_frame(time)).into_raw();
+
+                let mut frame = gif::Frame::from_rgba(w as u16, h as u16, &mut pixels);
+                frame.delay = delay;
+                encoder.write_frame(&frame)?;
+            }
+        }
+        FrameFormat::PngSequence => {
+            let dir = path.as_ref();
+            fs::create_dir_all(dir)?;
+
+            for (index, &time) in times.iter().enumerate() {
+                render_frame(time).save(dir.join(format!("frame{:04}.png", index)))?;
+            }
+        }
+    }
+
+    Ok(times.len())
+}
+
+/// `image` composited over white paper.
+///
+/// GIF only has fully transparent or fully opaque pixels, so soft ink edges would turn
+/// jagged against anything but the paper they were painted on.
+fn onto_paper(mut image: RgbaImage) -> RgbaImage {
+    for pixel in image.pixels_mut() {
+        let alpha = pixel[3] as u32;
+        for channel in 0..3 {
+            pixel[channel] = ((pixel[channel] as u32 * alpha + 255 * (255 - alpha)) / 255) as u8;
+        }
+        pixel[3] = 255;
+    }
+
+    image
+}
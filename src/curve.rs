@@ -0,0 +1,206 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+/// Piecewise linear mapping from raw pressure to a brush property, both in range 0.0 to 1.0.
+///
+/// Control points are sorted by input, first one at input 0.0 and last one at input 1.0.
+/// Curves read from documents and presets are checked against this, see `CurveError`.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "UncheckedPressureCurve")]
+pub struct PressureCurve {
+    points: Vec<[f32; 2]>,
+}
+
+// Same layout as `PressureCurve`, before its points are checked.
+#[derive(Deserialize)]
+struct UncheckedPressureCurve {
+    points: Vec<[f32; 2]>,
+}
+
+pub enum CurveError {
+    TooFewPoints(usize),
+    NotFinite,
+    Unsorted,
+    // First point is not at input 0.0 or last point is not at input 1.0.
+    OpenEnds,
+    // Output of a point is outside 0.0 to 1.0.
+    OutOfRange(f32),
+}
+
+impl fmt::Display for CurveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CurveError::TooFewPoints(count) => {
+                write!(f, "pressure curve has {} points, at least 2 are needed", count)
+            }
+            CurveError::NotFinite => write!(f, "pressure curve has a point that is not finite"),
+            CurveError::Unsorted => write!(f, "pressure curve points are not sorted by input"),
+            CurveError::OpenEnds => write!(f, "pressure curve does not span inputs 0 to 1"),
+            CurveError::OutOfRange(value) => {
+                write!(f, "pressure curve output {} is out of range 0 to 1", value)
+            }
+        }
+    }
+}
+
+impl TryFrom<UncheckedPressureCurve> for PressureCurve {
+    type Error = CurveError;
+
+    fn try_from(unchecked: UncheckedPressureCurve) -> Result<Self, CurveError> {
+        let points = unchecked.points;
+
+        if points.len() < 2 {
+            return Err(CurveError::TooFewPoints(points.len()));
+        }
+        if points.iter().any(|point| !point[0].is_finite() || !point[1].is_finite()) {
+            return Err(CurveError::NotFinite);
+        }
+        if points.windows(2).any(|segment| segment[0][0] > segment[1][0]) {
+            return Err(CurveError::Unsorted);
+        }
+        if points[0][0] != 0.0 || points[points.len() - 1][0] != 1.0 {
+            return Err(CurveError::OpenEnds);
+        }
+        if let Some(point) = points.iter().find(|point| point[1] < 0.0 || point[1] > 1.0) {
+            return Err(CurveError::OutOfRange(point[1]));
+        }
+
+        Ok(PressureCurve { points })
+    }
+}
+
+impl PressureCurve {
+    pub fn linear() -> Self {
+        PressureCurve { points: vec![[0.0, 0.0], [1.0, 1.0]] }
+    }
+
+    pub fn constant(value: f32) -> Self {
+        PressureCurve { points: vec![[0.0, value], [1.0, value]] }
+    }
+
+    pub fn points(&self) -> &[[f32; 2]] {
+        &self.points
+    }
+
+    pub fn evaluate(&self, pressure: f32) -> f32 {
+        let pressure = pressure.clamp(0.0, 1.0);
+
+        for segment in self.points.windows(2) {
+            let (start, end) = (segment[0], segment[1]);
+
+            if pressure <= end[0] {
+                let span = end[0] - start[0];
+                if span <= 0.0 {
+                    return end[1];
+                }
+
+                let t = (pressure - start[0]) / span;
+                return start[1] + t * (end[1] - start[1]);
+            }
+        }
+
+        self.points[self.points.len() - 1][1]
+    }
+
+    /// Move control point, keeping points sorted and both end points at their input.
+    pub fn set_point(&mut self, index: usize, point: [f32; 2]) {
+        let last = self.points.len() - 1;
+
+        let x = if index == 0 {
+            0.0
+        } else if index == last {
+            1.0
+        } else {
+            point[0].max(self.points[index - 1][0]).min(self.points[index + 1][0])
+        };
+
+        self.points[index] = [x, point[1].clamp(0.0, 1.0)];
+    }
+
+    /// Split widest gap between control points with a point on the curve.
+    pub fn insert_point(&mut self) {
+        let index = (1..self.points.len())
+            .max_by(|&a, &b| {
+                let gap_a = self.points[a][0] - self.points[a - 1][0];
+                let gap_b = self.points[b][0] - self.points[b - 1][0];
+                gap_a.partial_cmp(&gap_b).unwrap()
+            })
+            .unwrap();
+
+        let x = (self.points[index - 1][0] + self.points[index][0]) / 2.0;
+        let y = self.evaluate(x);
+        self.points.insert(index, [x, y]);
+    }
+
+    /// End points can not be removed.
+    pub fn remove_point(&mut self, index: usize) {
+        if index > 0 && index < self.points.len() - 1 {
+            self.points.remove(index);
+        }
+    }
+}
+
+/// How a brush responds to pressure.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct PressureResponse {
+    /// Fraction of max brush width.
+    pub width: PressureCurve,
+    /// Multiplier of ink spent per area.
+    pub ink_flow: PressureCurve,
+    /// Alpha of stroke.
+    pub opacity: PressureCurve,
+}
+
+impl Default for PressureResponse {
+    fn default() -> Self {
+        // Same as before curves existed: width follows pressure, nothing else does.
+        PressureResponse {
+            width: PressureCurve::linear(),
+            ink_flow: PressureCurve::constant(1.0),
+            opacity: PressureCurve::constant(1.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json;
+
+    fn parse(points: &str) -> Result<PressureCurve, serde_json::Error> {
+        serde_json::from_str(&format!(r#"{{ "points": {} }}"#, points))
+    }
+
+    #[test]
+    fn saved_curve_parses_back() {
+        let mut curve = PressureCurve::linear();
+        curve.insert_point();
+        let json = serde_json::to_string(&curve).unwrap();
+
+        assert!(serde_json::from_str::<PressureCurve>(&json).unwrap() == curve);
+    }
+
+    #[test]
+    fn curve_needs_two_points() {
+        assert!(parse("[]").is_err());
+        assert!(parse("[[0.0, 0.5]]").is_err());
+    }
+
+    #[test]
+    fn curve_must_span_whole_input() {
+        assert!(parse("[[0.1, 0.0], [1.0, 1.0]]").is_err());
+        assert!(parse("[[0.0, 0.0], [0.9, 1.0]]").is_err());
+    }
+
+    #[test]
+    fn curve_points_must_be_sorted() {
+        assert!(parse("[[0.0, 0.0], [0.7, 0.5], [0.3, 0.5], [1.0, 1.0]]").is_err());
+    }
+
+    #[test]
+    fn curve_outputs_must_be_in_range() {
+        assert!(parse("[[0.0, 0.0], [1.0, 1.5]]").is_err());
+        assert!(parse("[[0.0, -0.5], [1.0, 1.0]]").is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use serde_json;
+
+use history::History;
+use states::{BrushParameters, Layer, OneStroke, RenderMode, States};
+
+/// Bump this whenever layout of `Document` changes.
+///
+/// Version history:
+/// 1. Initial version.
+/// 2. Add `canvas_size`.
+/// 3. Replace `stroke_records` and `brush.render_mode` with `layers`.
+/// 4. Add `OneStroke::brush`.
+/// 5. Add `texture_set` to brush, older documents use the built-in set.
+/// 6. Add bristles to brush, older documents are painted with a solid tip.
+/// 7. Add `OneStroke::ink_load`, strokes of older documents start with a full brush.
+/// 8. Add `StrokeAnchor::time` and `paper_absorbency` to brush.
+/// 9. Add `OneStroke::start_time`, older strokes are replayed in layer order.
+pub const DOCUMENT_VERSION: u32 = 9;
+
+/// Largest canvas side a document may ask for, most GPUs can not make larger textures.
+pub const MAX_CANVAS_SIZE: u32 = 8192;
+
+#[derive(Serialize, Deserialize)]
+pub struct Document {
+    pub version: u32,
+    #[serde(default = "default_canvas_size")]
+    pub canvas_size: [u32; 2],
+    pub brush: BrushParameters,
+    pub layers: Vec<Layer>,
+}
+
+// Documents before version 2 were always drawn on 900x900 canvas.
+fn default_canvas_size() -> [u32; 2] {
+    [900, 900]
+}
+
+// Only used to check version before parsing the whole document.
+#[derive(Deserialize)]
+struct DocumentHeader {
+    version: u32,
+}
+
+// Fields of documents before version 3 which moved into `Layer`.
+#[derive(Deserialize)]
+struct LegacyStrokes {
+    brush: LegacyBrush,
+    stroke_records: Vec<OneStroke>,
+}
+
+#[derive(Deserialize)]
+struct LegacyBrush {
+    render_mode: RenderMode,
+}
+
+pub enum DocumentError {
+    Io(io::Error),
+    Parse(serde_json::Error),
+    UnsupportedVersion(u32),
+    NoLayers,
+    // Canvas size with a side of 0 or larger than `MAX_CANVAS_SIZE`.
+    CanvasSize(u32, u32),
+    // Setting with its value, e.g. of a hand edited document, which can not be painted with.
+    OutOfRange(&'static str, f32),
+}
+
+impl fmt::Display for DocumentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DocumentError::Io(ref err) => write!(f, "io error: {}", err),
+            DocumentError::Parse(ref err) => write!(f, "parse error: {}", err),
+            DocumentError::UnsupportedVersion(version) => {
+                write!(f,
+                       "unsupported document version {} (newest supported is {})",
+                       version,
+                       DOCUMENT_VERSION)
+            }
+            DocumentError::NoLayers => write!(f, "document has no layers"),
+            DocumentError::CanvasSize(w, h) => {
+                write!(f,
+                       "canvas size {}x{} is not between 1 and {}",
+                       w,
+                       h,
+                       MAX_CANVAS_SIZE)
+            }
+            DocumentError::OutOfRange(name, value) => {
+                write!(f, "{} of {} is out of range", name, value)
+            }
+        }
+    }
+}
+
+impl fmt::Debug for DocumentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl From<io::Error> for DocumentError {
+    fn from(err: io::Error) -> Self {
+        DocumentError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for DocumentError {
+    fn from(err: serde_json::Error) -> Self {
+        DocumentError::Parse(err)
+    }
+}
+
+impl Document {
+    pub fn from_states(states: &States) -> Self {
+        Document {
+            version: DOCUMENT_VERSION,
+            canvas_size: states.canvas_size,
+            brush: BrushParameters::from_states(states),
+            layers: states.layers.clone(),
+        }
+    }
+
+    pub fn apply_to(self, states: &mut States) {
+        self.brush.apply_to(states);
+
+        states.canvas_size = self.canvas_size;
+        states.canvas_size_input = [self.canvas_size[0] as i32, self.canvas_size[1] as i32];
+        states.need_resize_canvas = true;
+
+        states.layers = self.layers;
+        states.active_layer = states.layers.len() - 1;
+        states.selected_stroke = None;
+        states.hovered_anchor = None;
+        states.replay = None;
+        states.history = History::default();
+        states.need_update_brush_preview = true;
+    }
+}
+
+pub fn save<P: AsRef<Path>>(states: &States, path: P) -> Result<(), DocumentError> {
+    let mut file = File::create(path)?;
+    serde_json::to_writer_pretty(&mut file, &Document::from_states(states))?;
+
+    Ok(())
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Document, DocumentError> {
+    let value: serde_json::Value = serde_json::from_reader(File::open(path)?)?;
+
+    let header: DocumentHeader = serde_json::from_value(value.clone())?;
+    if header.version > DOCUMENT_VERSION {
+        return Err(DocumentError::UnsupportedVersion(header.version));
+    }
+
+    let mut document = if header.version < 3 {
+        upgrade_to_layers(value)?
+    } else {
+        serde_json::from_value(value)?
+    };
+
+    if header.version < 4 {
+        snapshot_brush_into_strokes(&mut document);
+    }
+
+    if document.layers.is_empty() {
+        return Err(DocumentError::NoLayers);
+    }
+    check_values(&document)?;
+
+    Ok(document)
+}
+
+/// Reject settings the renderer can not work with, JSON only checks their types.
+fn check_values(document: &Document) -> Result<(), DocumentError> {
+    let (w, h) = (document.canvas_size[0], document.canvas_size[1]);
+    if w == 0 || h == 0 || w > MAX_CANVAS_SIZE || h > MAX_CANVAS_SIZE {
+        return Err(DocumentError::CanvasSize(w, h));
+    }
+
+    if let Some((name, value)) = document.brush.stroke_brush().invalid_value() {
+        return Err(DocumentError::OutOfRange(name, value));
+    }
+
+    for layer in &document.layers {
+        let opacity = layer.properties.opacity;
+        if !(0.0..=1.0).contains(&opacity) {
+            return Err(DocumentError::OutOfRange("layer opacity", opacity));
+        }
+
+        for stroke in &layer.strokes {
+            if let Some((name, value)) = stroke.brush.invalid_value() {
+                return Err(DocumentError::OutOfRange(name, value));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Put every stroke of a document before version 3 into one layer.
+fn upgrade_to_layers(value: serde_json::Value) -> Result<Document, DocumentError> {
+    let legacy: LegacyStrokes = serde_json::from_value(value.clone())?;
+
+    let mut layer = Layer::new("layer 0".to_string(), legacy.brush.render_mode);
+    layer.strokes = legacy.stroke_records;
+
+    Ok(Document {
+        version: DOCUMENT_VERSION,
+        canvas_size: serde_json::from_value(value["canvas_size"].clone())
+            .unwrap_or_else(|_| default_canvas_size()),
+        brush: serde_json::from_value(value["brush"].clone())?,
+        layers: vec![layer],
+    })
+}
+
+/// Strokes of documents before version 4 were all drawn with the document brush.
+fn snapshot_brush_into_strokes(document: &mut Document) {
+    let brush = document.brush.stroke_brush();
+
+    for layer in &mut document.layers {
+        for stroke in &mut layer.strokes {
+            stroke.brush = brush.clone();
+        }
+    }
+}
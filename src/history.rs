@@ -0,0 +1,378 @@
+use states::{BrushParameters, Layer, LayerProperties, OneStroke, States};
+
+/// One reversible edit on `States`.
+///
+/// Stroke commands carry the index of the layer they edit, which need not be the active layer
+/// any more when they are undone.
+#[derive(Clone)]
+pub enum Command {
+    AddStroke {
+        layer: usize,
+        stroke: OneStroke,
+    },
+    DeleteStroke {
+        layer: usize,
+        index: usize,
+        stroke: OneStroke,
+    },
+    ClearStrokes {
+        layer: usize,
+        strokes: Vec<OneStroke>,
+    },
+    // Anchors of one stroke changed, e.g. moved by a transform handle.
+    EditStroke {
+        layer: usize,
+        index: usize,
+        old: OneStroke,
+        new: OneStroke,
+    },
+    // Every stroke of a layer rewritten at once, e.g. by simplification.
+    ReplaceStrokes {
+        layer: usize,
+        old: Vec<OneStroke>,
+        new: Vec<OneStroke>,
+    },
+    EditPressure {
+        layer: usize,
+        stroke_index: usize,
+        anchor_index: usize,
+        old: f32,
+        new: f32,
+    },
+    // `stroke_index` is `None` for color of the brush, `layer` is ignored then.
+    ChangeColor {
+        layer: usize,
+        stroke_index: Option<usize>,
+        old: [f32; 4],
+        new: [f32; 4],
+    },
+    // Boxed, parameters are much larger than any other command.
+    ChangeParameters {
+        old: Box<BrushParameters>,
+        new: Box<BrushParameters>,
+    },
+    AddLayer {
+        index: usize,
+        layer: Layer,
+    },
+    DeleteLayer {
+        index: usize,
+        layer: Layer,
+    },
+    // Layer at `from` ends up at `to`, layers in between shift by one.
+    MoveLayer {
+        from: usize,
+        to: usize,
+    },
+    // Strokes of layer at `index` are appended to the layer below, which keeps its properties.
+    // Both layers have the same render mode, see `build_layer_order_buttons`.
+    MergeLayerDown {
+        index: usize,
+        upper: Layer,
+        lower: Layer,
+    },
+    ChangeLayer {
+        index: usize,
+        old: LayerProperties,
+        new: LayerProperties,
+    },
+}
+
+impl Command {
+    pub fn apply(&self, states: &mut States) {
+        match *self {
+            Command::AddStroke { layer, ref stroke } => {
+                states.layers[layer].strokes.push(stroke.clone())
+            }
+            Command::DeleteStroke { layer, index, .. } => {
+                states.layers[layer].strokes.remove(index);
+            }
+            Command::ClearStrokes { layer, .. } => states.layers[layer].strokes.clear(),
+            Command::ReplaceStrokes { layer, ref new, .. } => {
+                states.layers[layer].strokes = new.clone()
+            }
+            Command::EditStroke { layer, index, ref new, .. } => {
+                states.layers[layer].strokes[index] = new.clone();
+            }
+            Command::EditPressure { layer, stroke_index, anchor_index, new, .. } => {
+                states.layers[layer].strokes[stroke_index].anchors[anchor_index].pressure = new;
+            }
+            Command::ChangeColor { layer, stroke_index, new, .. } => {
+                *color_of(states, layer, stroke_index) = new;
+            }
+            Command::ChangeParameters { ref new, .. } => new.apply_to(states),
+            Command::AddLayer { index, ref layer } => {
+                states.layers.insert(index, layer.clone());
+                states.set_active_layer(index);
+            }
+            Command::DeleteLayer { index, .. } => remove_layer(states, index),
+            Command::MoveLayer { from, to } => move_layer(states, from, to),
+            Command::MergeLayerDown { index, ref upper, .. } => {
+                let was_active = states.active_layer == index;
+
+                remove_layer(states, index);
+                states.layers[index - 1].strokes.extend_from_slice(&upper.strokes);
+
+                if was_active {
+                    states.active_layer = index - 1;
+                }
+            }
+            Command::ChangeLayer { index, ref new, .. } => {
+                states.layers[index].properties = new.clone()
+            }
+        }
+
+        states.need_update_brush_preview = true;
+    }
+
+    pub fn revert(&self, states: &mut States) {
+        match *self {
+            Command::AddStroke { layer, .. } => {
+                states.layers[layer].strokes.pop();
+            }
+            Command::DeleteStroke { layer, index, ref stroke } => {
+                states.layers[layer].strokes.insert(index, stroke.clone());
+            }
+            Command::ClearStrokes { layer, ref strokes } => {
+                states.layers[layer].strokes = strokes.clone()
+            }
+            Command::ReplaceStrokes { layer, ref old, .. } => {
+                states.layers[layer].strokes = old.clone()
+            }
+            Command::EditStroke { layer, index, ref old, .. } => {
+                states.layers[layer].strokes[index] = old.clone();
+            }
+            Command::EditPressure { layer, stroke_index, anchor_index, old, .. } => {
+                states.layers[layer].strokes[stroke_index].anchors[anchor_index].pressure = old;
+            }
+            Command::ChangeColor { layer, stroke_index, old, .. } => {
+                *color_of(states, layer, stroke_index) = old;
+            }
+            Command::ChangeParameters { ref old, .. } => old.apply_to(states),
+            Command::AddLayer { index, .. } => remove_layer(states, index),
+            Command::DeleteLayer { index, ref layer } => {
+                states.layers.insert(index, layer.clone());
+                states.set_active_layer(index);
+            }
+            Command::MoveLayer { from, to } => move_layer(states, to, from),
+            Command::MergeLayerDown { index, ref upper, ref lower } => {
+                states.layers[index - 1] = lower.clone();
+                states.layers.insert(index, upper.clone());
+                states.set_active_layer(index);
+            }
+            Command::ChangeLayer { index, ref old, .. } => {
+                states.layers[index].properties = old.clone()
+            }
+        }
+
+        states.need_update_brush_preview = true;
+    }
+
+    pub fn description(&self) -> String {
+        match *self {
+            Command::AddStroke { .. } => "add stroke".to_string(),
+            Command::DeleteStroke { index, .. } => format!("delete stroke no.{}", index),
+            Command::ClearStrokes { ref strokes, .. } => {
+                format!("clear {} strokes", strokes.len())
+            }
+            Command::ReplaceStrokes { .. } => "simplify strokes".to_string(),
+            Command::EditStroke { index, .. } => format!("edit stroke no.{}", index),
+            Command::EditPressure { stroke_index, anchor_index, .. } => {
+                format!("edit pressure {}-{}", stroke_index, anchor_index)
+            }
+            Command::ChangeColor { stroke_index: Some(index), .. } => {
+                format!("change color of stroke no.{}", index)
+            }
+            Command::ChangeColor { stroke_index: None, .. } => "change brush color".to_string(),
+            Command::ChangeParameters { .. } => "change parameters".to_string(),
+            Command::AddLayer { ref layer, .. } => format!("add {}", layer.properties.name),
+            Command::DeleteLayer { ref layer, .. } => {
+                format!("delete {}", layer.properties.name)
+            }
+            Command::MoveLayer { from, to } => format!("move layer {} to {}", from, to),
+            Command::MergeLayerDown { ref upper, ref lower, .. } => {
+                format!("merge {} into {}", upper.properties.name, lower.properties.name)
+            }
+            Command::ChangeLayer { ref new, .. } => format!("change {}", new.name),
+        }
+    }
+
+    /// Continuous edits, e.g. dragging a slider, are folded into one command.
+    fn merge(&mut self, other: &Command) -> bool {
+        match (self, other) {
+            (&mut Command::EditPressure { layer, stroke_index, anchor_index, ref mut new, .. },
+             &Command::EditPressure { layer: other_layer,
+                                      stroke_index: other_stroke_index,
+                                      anchor_index: other_anchor_index,
+                                      new: other_new,
+                                      .. }) => {
+                if layer != other_layer || stroke_index != other_stroke_index ||
+                   anchor_index != other_anchor_index {
+                    return false;
+                }
+
+                *new = other_new;
+                true
+            }
+            (&mut Command::ChangeColor { layer, stroke_index, ref mut new, .. },
+             &Command::ChangeColor { layer: other_layer,
+                                     stroke_index: other_stroke_index,
+                                     new: other_new,
+                                     .. }) => {
+                if layer != other_layer || stroke_index != other_stroke_index {
+                    return false;
+                }
+
+                *new = other_new;
+                true
+            }
+            (&mut Command::ChangeParameters { ref mut new, .. },
+             Command::ChangeParameters { new: other_new, .. }) => {
+                *new = other_new.clone();
+                true
+            }
+            (&mut Command::ChangeLayer { index, ref mut new, .. },
+             &Command::ChangeLayer { index: other_index, new: ref other_new, .. }) => {
+                if index != other_index {
+                    return false;
+                }
+
+                *new = other_new.clone();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+fn color_of(states: &mut States, layer: usize, stroke_index: Option<usize>) -> &mut [f32; 4] {
+    match stroke_index {
+        Some(index) => &mut states.layers[layer].strokes[index].color,
+        None => &mut states.recording_stroke_anchors.color,
+    }
+}
+
+/// Remove layer at `index`, keeping active layer pointing at the same layer when possible.
+fn remove_layer(states: &mut States, index: usize) {
+    states.layers.remove(index);
+
+    if states.active_layer > index {
+        states.active_layer -= 1;
+    } else if states.active_layer == index {
+        // Selection pointed into the removed layer.
+        states.selected_stroke = None;
+        states.hovered_anchor = None;
+        states.active_layer = index.min(states.layers.len() - 1);
+    }
+}
+
+/// Move layer at `from` to `to`, active layer follows the layer it pointed at.
+fn move_layer(states: &mut States, from: usize, to: usize) {
+    let layer = states.layers.remove(from);
+    states.layers.insert(to, layer);
+
+    let active_layer = states.active_layer;
+    states.active_layer = if active_layer == from {
+        to
+    } else if from < active_layer && active_layer <= to {
+        active_layer - 1
+    } else if to <= active_layer && active_layer < from {
+        active_layer + 1
+    } else {
+        active_layer
+    };
+}
+
+#[derive(Default)]
+pub struct History {
+    commands: Vec<Command>,
+    // Number of commands currently applied.
+    cursor: usize,
+
+    // Edit still in progress, committed once the mouse button is released.
+    pending: Option<Command>,
+}
+
+impl History {
+    pub fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    fn push(&mut self, command: Command) {
+        self.commands.truncate(self.cursor);
+        self.commands.push(command);
+        self.cursor += 1;
+    }
+
+    /// Record a continuous edit which has already been applied on `States`.
+    pub fn record(&mut self, command: Command) {
+        if let Some(ref mut pending) = self.pending {
+            if pending.merge(&command) {
+                return;
+            }
+        }
+
+        self.commit_pending();
+        self.pending = Some(command);
+    }
+
+    /// Record a one-off edit which has already been applied on `States`.
+    pub fn record_applied(&mut self, command: Command) {
+        self.commit_pending();
+        self.push(command);
+    }
+
+    pub fn commit_pending(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            self.push(pending);
+        }
+    }
+}
+
+/// Apply `command` and make it undoable.
+pub fn execute(states: &mut States, command: Command) {
+    states.history.commit_pending();
+
+    command.apply(states);
+    states.history.push(command);
+}
+
+pub fn undo(states: &mut States) {
+    states.history.commit_pending();
+
+    if states.history.cursor == 0 {
+        return;
+    }
+
+    states.history.cursor -= 1;
+    let command = states.history.commands[states.history.cursor].clone();
+    command.revert(states);
+}
+
+pub fn redo(states: &mut States) {
+    states.history.commit_pending();
+
+    if states.history.cursor == states.history.commands.len() {
+        return;
+    }
+
+    let command = states.history.commands[states.history.cursor].clone();
+    states.history.cursor += 1;
+    command.apply(states);
+}
+
+/// Undo or redo until exactly `cursor` commands are applied.
+pub fn jump_to(states: &mut States, cursor: usize) {
+    states.history.commit_pending();
+
+    while states.history.cursor > cursor {
+        undo(states);
+    }
+    while states.history.cursor < cursor && states.history.cursor < states.history.commands.len() {
+        redo(states);
+    }
+}
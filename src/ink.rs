@@ -0,0 +1,316 @@
+use vecmath;
+
+use states::{States, StrokeAnchor, StrokeBrush};
+
+/// Side of the palette square strokes dip into, in canvas units.
+pub const PALETTE_SIZE: f32 = 60.0;
+
+/// Fraction of a full load an empty brush still leaves, so strokes fade into dry brush
+/// instead of stopping.
+pub const DRY_BRUSH_INK: f32 = 0.05;
+
+/// Speed in canvas units per second at which ink cost only depends on area.
+pub const REFERENCE_SPEED: f32 = 300.0;
+
+/// Ink cost is multiplied by `(REFERENCE_SPEED / speed)` to this power.
+const SPEED_EXPONENT: f32 = 0.5;
+/// Ink deposit is multiplied by speed multiplier of cost to this power, so a crawling brush
+/// leaves at most twice as much ink as one moving at `REFERENCE_SPEED`.
+const DEPOSIT_SPEED_EXPONENT: f32 = 0.5;
+/// Bounds of speed multiplier, so a brush resting on paper does not drain at once and a flick
+/// still leaves some ink.
+const MIN_SPEED_FACTOR: f32 = 0.25;
+const MAX_SPEED_FACTOR: f32 = 4.0;
+
+/// Ink a brush loses between `start` and `end`.
+///
+/// Cost grows with area covered, as the paper soaks up ink under the whole footprint, and with
+/// pressure squeezing ink out of the brush. Slow strokes drain the brush faster, as the ink
+/// they leave behind pools, see `segment_deposit`. More absorbent paper draws more ink out of
+/// the brush.
+///
+/// Anchors without capture time are costed as if drawn at `REFERENCE_SPEED`.
+pub fn segment_cost(brush: &StrokeBrush,
+                    start: &StrokeAnchor,
+                    start_radius: f32,
+                    end: &StrokeAnchor,
+                    end_radius: f32,
+                    paper_absorbency: f32)
+                    -> f32 {
+    let offset = vecmath::vec2_sub(end.pos, start.pos);
+    let length = vecmath::vec2_len(offset);
+    let area = (start_radius + end_radius) * length / 2.0;
+
+    let pressure = (start.pressure + end.pressure) / 2.0;
+    let flow = brush.pressure_response.ink_flow.evaluate(pressure);
+    let squeeze = pressure.max(0.0).sqrt();
+
+    brush.ink_quantity_friction * area * flow * squeeze * speed_factor(start, end) *
+    absorbency_factor(paper_absorbency)
+}
+
+/// Multiplier of ink quantity the brush leaves on paper between `start` and `end`.
+///
+/// Unlike `segment_cost`, which drains the reservoir, this darkens the segment itself: ink
+/// pools under a slow brush and soaks into absorbent paper, a fast brush only grazes it. 1 at
+/// `REFERENCE_SPEED` on half absorbent paper, anchors without capture time count as that speed.
+pub fn segment_deposit(start: &StrokeAnchor, end: &StrokeAnchor, paper_absorbency: f32) -> f32 {
+    speed_factor(start, end).powf(DEPOSIT_SPEED_EXPONENT) * absorbency_factor(paper_absorbency)
+}
+
+/// Multiplier of ink for `paper_absorbency`, 1 on half absorbent paper.
+fn absorbency_factor(paper_absorbency: f32) -> f32 {
+    0.5 + paper_absorbency.clamp(0.0, 1.0)
+}
+
+/// Multiplier of ink cost for speed brush moved from `start` to `end` with.
+fn speed_factor(start: &StrokeAnchor, end: &StrokeAnchor) -> f32 {
+    let duration = match (start.time, end.time) {
+        (Some(start_time), Some(end_time)) if end_time > start_time => end_time - start_time,
+        _ => return 1.0,
+    };
+
+    let length = vecmath::vec2_len(vecmath::vec2_sub(end.pos, start.pos));
+    let speed = length / duration;
+    if speed <= 0.0 {
+        return MAX_SPEED_FACTOR;
+    }
+
+    (REFERENCE_SPEED / speed).powf(SPEED_EXPONENT).clamp(MIN_SPEED_FACTOR, MAX_SPEED_FACTOR)
+}
+
+/// Palette area as `[x, y, w, h]`, at bottom left corner of canvas.
+pub fn palette_rect(canvas_size: [u32; 2]) -> [f32; 4] {
+    const MARGIN: f32 = 10.0;
+
+    [MARGIN, canvas_size[1] as f32 - PALETTE_SIZE - MARGIN, PALETTE_SIZE, PALETTE_SIZE]
+}
+
+pub fn is_in_palette(canvas_size: [u32; 2], pos: [f32; 2]) -> bool {
+    let rect = palette_rect(canvas_size);
+
+    pos[0] >= rect[0] && pos[0] <= rect[0] + rect[2] && pos[1] >= rect[1] &&
+    pos[1] <= rect[1] + rect[3]
+}
+
+/// Ink a full brush holds, i.e. `initial_ink_quantity` of current brush.
+pub fn capacity(states: &States) -> f32 {
+    states.initial_ink_quantity
+}
+
+/// Fraction of a full brush `ink_quantity` is.
+///
+/// A brush of 0 capacity never holds ink, so it is always empty and paints dry brush marks.
+pub fn load_fraction(ink_quantity: f32, capacity: f32) -> f32 {
+    if capacity <= 0.0 {
+        return 0.0;
+    }
+
+    ink_quantity / capacity
+}
+
+/// Refill reservoir for `dt` seconds of dwelling in ink.
+///
+/// An empty brush is full after `dip_refill_time` seconds, shorter dips refill partially.
+pub fn dip(states: &mut States, dt: f32) {
+    let capacity = capacity(states);
+    let refill = if states.dip_refill_time > 0.0 {
+        capacity * dt / states.dip_refill_time
+    } else {
+        capacity
+    };
+
+    states.ink_reservoir = (states.ink_reservoir + refill).min(capacity);
+}
+
+/// Ink the next stroke starts with.
+///
+/// Reservoir never holds more than current brush does, e.g. after switching to a smaller one.
+pub fn load_brush(states: &mut States) -> f32 {
+    states.ink_reservoir = states.ink_reservoir.min(capacity(states));
+    states.ink_reservoir
+}
+
+/// Take ink a finished stroke used out of reservoir.
+///
+/// Undoing the stroke does not give it back, ink already on paper is gone.
+pub fn spend(states: &mut States, ink: f32) {
+    states.ink_reservoir = (states.ink_reservoir - ink).max(0.0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use curve::PressureCurve;
+    use states::{StrokeAnchor, StrokeBrush};
+
+    const RADIUS: f32 = 10.0;
+
+    fn anchor(x: f32, pressure: f32, time: Option<f32>) -> StrokeAnchor {
+        let anchor = StrokeAnchor::new(&[x, 0.0], pressure);
+        match time {
+            Some(time) => anchor.with_time(time),
+            None => anchor,
+        }
+    }
+
+    /// Cost of a straight segment of `length` drawn in `duration` seconds.
+    fn cost(length: f32, pressure: f32, duration: Option<f32>, absorbency: f32) -> f32 {
+        let brush = StrokeBrush::default();
+        let start = anchor(0.0, pressure, duration.map(|_| 0.0));
+        let end = anchor(length, pressure, duration);
+
+        segment_cost(&brush, &start, RADIUS, &end, RADIUS, absorbency)
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() <= 1e-4 * a.abs().max(b.abs()),
+                "{} is not close to {}",
+                a,
+                b);
+    }
+
+    #[test]
+    fn untimed_anchors_cost_area_times_friction() {
+        // Full pressure, half absorbent paper and unknown speed leave area as only factor.
+        let brush = StrokeBrush::default();
+        // Trapezoid of equal sides.
+        let area = RADIUS * 30.0;
+
+        assert_close(cost(30.0, 1.0, None, 0.5), brush.ink_quantity_friction * area);
+    }
+
+    #[test]
+    fn reference_speed_costs_same_as_untimed() {
+        let duration = 30.0 / REFERENCE_SPEED;
+
+        assert_close(cost(30.0, 1.0, Some(duration), 0.5), cost(30.0, 1.0, None, 0.5));
+    }
+
+    #[test]
+    fn zero_length_segment_costs_nothing() {
+        assert_eq!(cost(0.0, 1.0, None, 0.5), 0.0);
+        assert_eq!(cost(0.0, 1.0, Some(1.0), 0.5), 0.0);
+    }
+
+    #[test]
+    fn brush_without_capacity_is_empty() {
+        assert_eq!(load_fraction(0.0, 0.0), 0.0);
+        assert_eq!(load_fraction(10.0, 0.0), 0.0);
+        assert_eq!(load_fraction(25.0, 100.0), 0.25);
+    }
+
+    #[test]
+    fn cost_grows_with_length() {
+        assert_close(cost(60.0, 1.0, None, 0.5), 2.0 * cost(30.0, 1.0, None, 0.5));
+    }
+
+    /// Deposit of a straight segment of `length` drawn in `duration` seconds.
+    fn deposit(length: f32, duration: Option<f32>, absorbency: f32) -> f32 {
+        let start = anchor(0.0, 1.0, duration.map(|_| 0.0));
+        let end = anchor(length, 1.0, duration);
+
+        segment_deposit(&start, &end, absorbency)
+    }
+
+    #[test]
+    fn slow_strokes_cost_more_than_fast_ones() {
+        let fast = cost(30.0, 1.0, Some(0.01), 0.5);
+        let reference = cost(30.0, 1.0, Some(30.0 / REFERENCE_SPEED), 0.5);
+        let slow = cost(30.0, 1.0, Some(1.0), 0.5);
+
+        assert!(fast < reference);
+        assert!(reference < slow);
+    }
+
+    #[test]
+    fn quadruple_speed_halves_cost() {
+        let reference = cost(30.0, 1.0, Some(30.0 / REFERENCE_SPEED), 0.5);
+        let fast = cost(30.0, 1.0, Some(30.0 / REFERENCE_SPEED / 4.0), 0.5);
+
+        assert_close(fast, reference / 2.0);
+    }
+
+    #[test]
+    fn speed_factor_is_bounded() {
+        let untimed = cost(30.0, 1.0, None, 0.5);
+
+        // Crawling pools ink, but never more than MAX_SPEED_FACTOR times.
+        assert_close(cost(30.0, 1.0, Some(1000.0), 0.5), MAX_SPEED_FACTOR * untimed);
+        assert_close(cost(30.0, 1.0, Some(1e-6), 0.5), MIN_SPEED_FACTOR * untimed);
+    }
+
+    #[test]
+    fn anchors_out_of_order_are_treated_as_untimed() {
+        let brush = StrokeBrush::default();
+        let start = anchor(0.0, 1.0, Some(1.0));
+        let end = anchor(30.0, 1.0, Some(0.5));
+
+        assert_close(segment_cost(&brush, &start, RADIUS, &end, RADIUS, 0.5),
+                     cost(30.0, 1.0, None, 0.5));
+    }
+
+    #[test]
+    fn harder_pressure_costs_more() {
+        let light = cost(30.0, 0.25, None, 0.5);
+        let hard = cost(30.0, 1.0, None, 0.5);
+
+        assert!(light < hard);
+        assert_close(light, hard / 2.0);
+    }
+
+    #[test]
+    fn ink_flow_curve_scales_cost() {
+        let mut brush = StrokeBrush::default();
+        brush.pressure_response.ink_flow = PressureCurve::constant(0.5);
+
+        let start = anchor(0.0, 1.0, None);
+        let end = anchor(30.0, 1.0, None);
+
+        assert_close(segment_cost(&brush, &start, RADIUS, &end, RADIUS, 0.5),
+                     cost(30.0, 1.0, None, 0.5) / 2.0);
+    }
+
+    #[test]
+    fn absorbent_paper_draws_more_ink() {
+        let sized = cost(30.0, 1.0, None, 0.0);
+        let blotting = cost(30.0, 1.0, None, 1.0);
+
+        assert_close(sized, cost(30.0, 1.0, None, 0.5) / 2.0);
+        assert_close(blotting, 3.0 * sized);
+    }
+
+    #[test]
+    fn absorbency_is_clamped() {
+        assert_close(cost(30.0, 1.0, None, 2.0), cost(30.0, 1.0, None, 1.0));
+        assert_close(cost(30.0, 1.0, None, -1.0), cost(30.0, 1.0, None, 0.0));
+    }
+
+    #[test]
+    fn reference_speed_deposits_same_as_untimed() {
+        assert_close(deposit(30.0, None, 0.5), 1.0);
+        assert_close(deposit(30.0, Some(30.0 / REFERENCE_SPEED), 0.5), 1.0);
+    }
+
+    #[test]
+    fn slower_segment_deposits_more() {
+        let fast = deposit(30.0, Some(0.01), 0.5);
+        let reference = deposit(30.0, Some(30.0 / REFERENCE_SPEED), 0.5);
+        let slow = deposit(30.0, Some(1.0), 0.5);
+
+        assert!(fast < reference);
+        assert!(reference < slow);
+    }
+
+    #[test]
+    fn deposit_is_bounded() {
+        assert_close(deposit(30.0, Some(1000.0), 0.5), 2.0);
+        assert_close(deposit(30.0, Some(1e-6), 0.5), 0.5);
+    }
+
+    #[test]
+    fn absorbent_paper_deposits_more() {
+        assert_close(deposit(30.0, None, 1.0), 3.0 * deposit(30.0, None, 0.0));
+    }
+}
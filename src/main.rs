@@ -1,84 +1,56 @@
+extern crate gif;
 #[macro_use]
 extern crate glium;
 extern crate glium_graphics;
 extern crate graphics;
 extern crate image;
-#[macro_use]
 extern crate imgui;
+extern crate imgui_glium_renderer;
 extern crate interpolation;
 extern crate piston;
 extern crate vecmath;
 extern crate rand;
+extern crate rand_xorshift;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 
 mod toolkits;
 mod states;
+mod renderer;
+mod document;
+mod history;
+mod pressure;
+mod curve;
+mod spline;
+mod simplify;
+mod selection;
+mod preset;
+mod texture_set;
+mod texture_generator;
+mod bristle;
+mod ink;
+mod replay;
+mod build_up;
+mod svg;
+
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use std::io::Read;
-use std::io::Cursor;
-
-use piston::window::Size;
-use glium::Blend;
-use glium::Smooth;
-use piston::window::Window;
-use glium::index::NoIndices;
-use glium::index::PrimitiveType;
-use glium::draw_parameters::DrawParameters;
-use glium::VertexBuffer;
-use glium::Surface;
-use glium::texture::texture2d::Texture2d;
 use glium_graphics::{Flip, Glium2d, GliumWindow, OpenGL, Texture, TextureSettings};
 use graphics::Graphics;
 use graphics::types::Matrix2d;
-use graphics::math;
 use piston::event_loop::EventLoop;
-use piston::window::WindowSettings;
-use glium::Program;
+use piston::window::{AdvancedWindow, Window, WindowSettings};
 
+use history::Command;
 use states::StrokeAnchor;
-use states::RenderMode;
-use states::OneStroke;
+use states::StrokeBrush;
 
 const OPENGL: OpenGL = OpenGL::V3_2;
 
-fn load_string(path: &str) -> String {
-    let mut f = std::fs::File::open(path).unwrap();
-    let mut buf = String::new();
-
-    f.read_to_string(&mut buf).unwrap();
-
-    buf
-}
-
-fn load_texture<F: glium::backend::Facade>(window: &F, bytes: &[u8]) -> Texture2d {
-    let image = image::load(Cursor::new(&bytes[..]), image::PNG)
-        .unwrap()
-        .to_rgba();
-    let image_dimensions = image.dimensions();
-    let image = glium::texture::RawImage2d::from_raw_rgba(image.into_raw(), image_dimensions);
-
-    glium::texture::Texture2d::new(window, image).unwrap()
-}
-
-#[derive(Copy, Clone)]
-struct Vertex {
-    pos: [f32; 2],
-}
-implement_vertex!(Vertex, pos);
-
-#[derive(Copy, Clone)]
-struct NormalVertex {
-    pos: [f32; 2],
-    tex_coords: [f32; 2],
-}
-implement_vertex!(NormalVertex, pos, tex_coords);
-
-#[derive(Copy, Clone)]
-struct CircleData {
-    pos: [f32; 2],
-    center: [f32; 2],
-    radius: f32,
-}
-implement_vertex!(CircleData, pos, center, radius);
+/// Pressure change of an anchor per scroll wheel notch.
+const PRESSURE_SCROLL_STEP: f32 = 0.05;
 
 struct App {
     window: GliumWindow,
@@ -86,186 +58,85 @@ struct App {
 
     states: states::States,
 
-    final_vertex_buffer: VertexBuffer<NormalVertex>,
-
-    brush_preview_tex: Texture2d,
-    stroke_outline_tex: Texture2d,
-    stroke_outline_tmp_tex: Texture2d,
-    stroke_ink_quantity_tex: Texture2d,
-    stroke_ink_quantity_tmp_tex: Texture2d,
-
-    // Watercolor textures.
-    shallow_water_tex: Texture2d,
-    pidment_deposition_tex: Texture2d,
-    capillary_tex: Texture2d,
-    passive_layer_tex: Texture2d,
+    renderer: renderer::Renderer<GliumWindow>,
 
-    wipe_tmp_tex: Texture2d,
-    diffusion_tmp_tex: Texture2d,
+    rust_logo: Texture,
 
-    final_program: Program,
-    stroke_ink_quantity_program: Program,
-    circle_program: Program,
-    triangle_program: Program,
-    black_n_white_brush_program: Program,
-    watercolor_brush_program: Program,
-    wipe_program: Program,
-    diffusion_program: Program,
+    is_ctrl_pressed: bool,
+    is_shift_pressed: bool,
+    is_alt_pressed: bool,
 
-    rust_logo: Texture,
-    doge_image: Texture2d,
+    pressure_simulator: pressure::PressureSimulator,
+    touch_pressure: pressure::TouchPressure,
+    // Anchors record their capture time relative to this.
+    stroke_start: Instant,
 
-    level0_tex: Texture2d,
-    level1_tex: Texture2d,
-    level2_tex: Texture2d,
-    level3_tex: Texture2d,
-    level4_tex: Texture2d,
+    cursor_pos: [f32; 2],
+    drag: Option<selection::Drag>,
 }
 
 impl App {
     pub fn new() -> Self {
-        let (w, h) = (900, 900);
+        let states = states::States::default();
+
+        let (w, h) = (states.canvas_size[0], states.canvas_size[1]);
         let mut window: GliumWindow = WindowSettings::new("npr homework1", [w, h])
             .exit_on_esc(true)
-            .opengl(OPENGL)
+            .graphics_api(OPENGL)
             .build()
             .unwrap();
         window.set_ups(60);
 
         let toolkits = toolkits::Toolkits::new(&window).unwrap();
 
-        let final_vertex_buffer = glium::VertexBuffer::new(&window,
-                                                           &[NormalVertex {
-                                                                 pos: [-1.0, 1.0],
-                                                                 tex_coords: [0.0, 1.0],
-                                                             },
-                                                             NormalVertex {
-                                                                 pos: [1.0, 1.0],
-                                                                 tex_coords: [1.0, 1.0],
-                                                             },
-                                                             NormalVertex {
-                                                                 pos: [-1.0, -1.0],
-                                                                 tex_coords: [0.0, 0.0],
-                                                             },
-                                                             NormalVertex {
-                                                                 pos: [1.0, -1.0],
-                                                                 tex_coords: [1.0, 0.0],
-                                                             }])
-            .unwrap();
-
         let rust_logo = Texture::from_path(&mut window,
                                            "assets/rust.png",
                                            Flip::None,
                                            &TextureSettings::new())
             .unwrap();
 
+        // Textures follow real pixels, which may differ from canvas size on HiDPI screens.
+        let draw_size = window.draw_size();
+
+        App {
+            renderer: renderer::Renderer::new(window.clone(),
+                                              draw_size.width as u32,
+                                              draw_size.height as u32),
 
+            rust_logo,
 
-        let final_program = Program::from_source(&window,
-                                                 &load_string("shaders/final.vs"),
-                                                 &load_string("shaders/final.fs"),
-                                                 None)
-            .expect("failed to initialize textured shader");
-        let circle_program = Program::from_source(&window,
-                                                  &load_string("shaders/final.vs"),
-                                                  &load_string("shaders/circle.fs"),
-                                                  None)
-            .expect("failed to initialize textured shader");
-        let triangle_program = Program::from_source(&window,
-                                                    &load_string("shaders/triangle.vs"),
-                                                    &load_string("shaders/triangle.fs"),
-                                                    None)
-            .expect("failed to initialize textured shader");
-        let stroke_ink_quantity_program = Program::from_source(&window,
-                                                               &load_string("shaders/final.vs"),
-                                                               &load_string("shaders/ink.fs"),
-                                                               None)
-            .expect("failed to initialize textured shader");
-        let black_n_white_brush_program =
-            Program::from_source(&window,
-                                 &load_string("shaders/final.vs"),
-                                 &load_string("shaders/black_n_white_brush.fs"),
-                                 None)
-                .expect("failed to initialize textured shader");
-        let watercolor_brush_program = Program::from_source(&window,
-                                                            &load_string("shaders/final.vs"),
-                                                            &load_string("shaders/watercolor_brush.\
-                                                                          fs"),
-                                                            None)
-            .expect("failed to initialize textured shader");
-        let wipe_program = Program::from_source(&window,
-                                                &load_string("shaders/final.vs"),
-                                                &load_string("shaders/wipe.fs"),
-                                                None)
-            .expect("failed to initialize textured shader");
-        let diffusion_program = Program::from_source(&window,
-                                                     &load_string("shaders/final.vs"),
-                                                     &load_string("shaders/diffusion.fs"),
-                                                     None)
-            .expect("failed to initialize textured shader");
-
-        let doge_image = load_texture(&window, include_bytes!("assets/doge.png"));
-
-        let level0_tex = load_texture(&window, include_bytes!("assets/level0.png"));
-        let level1_tex = load_texture(&window, include_bytes!("assets/level1.png"));
-        let level2_tex = load_texture(&window, include_bytes!("assets/level2.png"));
-        let level3_tex = load_texture(&window, include_bytes!("assets/level3.png"));
-        let level4_tex = load_texture(&window, include_bytes!("assets/level4.png"));
+            is_ctrl_pressed: false,
+            is_shift_pressed: false,
+            is_alt_pressed: false,
 
-        App {
-            final_vertex_buffer: final_vertex_buffer,
-
-            brush_preview_tex: Texture2d::empty(&window, w, h).unwrap(),
-            stroke_outline_tex: Texture2d::empty(&window, w, h).unwrap(),
-            stroke_outline_tmp_tex: Texture2d::empty(&window, w, h).unwrap(),
-            stroke_ink_quantity_tex: Texture2d::empty(&window, w, h).unwrap(),
-            stroke_ink_quantity_tmp_tex: Texture2d::empty(&window, w, h).unwrap(),
-            black_n_white_brush_program: black_n_white_brush_program,
-            watercolor_brush_program: watercolor_brush_program,
-
-            shallow_water_tex: Texture2d::empty(&window, w, h).unwrap(),
-            pidment_deposition_tex: Texture2d::empty(&window, w, h).unwrap(),
-            capillary_tex: Texture2d::empty(&window, w, h).unwrap(),
-            passive_layer_tex: Texture2d::empty(&window, w, h).unwrap(),
-
-            wipe_tmp_tex: Texture2d::empty(&window, w, h).unwrap(),
-            diffusion_tmp_tex: Texture2d::empty(&window, w, h).unwrap(),
-
-            final_program: final_program,
-            stroke_ink_quantity_program: stroke_ink_quantity_program,
-            circle_program: circle_program,
-            triangle_program: triangle_program,
-            wipe_program: wipe_program,
-            diffusion_program: diffusion_program,
-
-            rust_logo: rust_logo,
-            doge_image: doge_image,
-
-            level0_tex: level0_tex,
-            level1_tex: level1_tex,
-            level2_tex: level2_tex,
-            level3_tex: level3_tex,
-            level4_tex: level4_tex,
-
-            window: window,
-            toolkits: toolkits,
-            states: states::States::default(),
+            pressure_simulator: pressure::PressureSimulator::default(),
+            touch_pressure: pressure::TouchPressure::default(),
+            stroke_start: Instant::now(),
+
+            cursor_pos: [0., 0.],
+            drag: None,
+
+            window,
+            toolkits,
+            states,
         }
     }
 
     pub fn run(mut self) -> Result<(), ()> {
-        let mut g2d = Glium2d::new(OPENGL, &mut self.window);
+        let mut g2d = Glium2d::new(OPENGL, &self.window);
 
         while let Some(event) = self.window.next() {
             use piston::input::*;
 
-            self.toolkits.handle_event(&event);
-
             match event {
-                Input::Update(ref args) => self.update(&args.dt),
-                Input::Render(ref args) => self.render(args, &mut g2d),
+                Event::Loop(Loop::Update(ref args)) => self.update(&args.dt),
+                Event::Loop(Loop::Render(ref args)) => self.render(args, &mut g2d),
 
-                _ => self.handle_inputs(&event),
+                Event::Input(ref input, _) => {
+                    self.toolkits.handle_event(input);
+                    self.handle_inputs(input);
+                }
+                _ => {}
             }
         }
 
@@ -276,569 +147,725 @@ impl App {
         if self.states.is_recording_trajectory {
             self.states.current_recording_cooldown += *dt as f32;
         }
+
+        if self.states.is_dipping {
+            ink::dip(&mut self.states, *dt as f32);
+        }
+
+        let replay_changed = match self.states.replay {
+            Some(ref mut replay) => replay.advance(*dt as f32),
+            None => false,
+        };
+        if replay_changed {
+            self.states.need_update_brush_preview = true;
+        }
     }
 
     fn render(&mut self, args: &piston::input::RenderArgs, g2d: &mut Glium2d) {
         use graphics::*;
 
+        if self.states.need_resize_canvas {
+            self.states.need_resize_canvas = false;
+
+            let size = self.states.canvas_size_input;
+            self.window.set_size([size[0] as u32, size[1] as u32]);
+        }
+
         let mut target = self.window.draw();
 
         g2d.draw(&mut target,
                  args.viewport(),
                  |_c, g| { clear(color::WHITE, g); });
 
+        if self.states.need_reload_texture_sets {
+            self.states.need_reload_texture_sets = false;
+
+            self.renderer.reload_texture_sets();
+            self.states.texture_set_error = None;
+            self.states.need_update_brush_preview = true;
+        }
+
+        if self.states.need_export_build_up {
+            self.states.need_export_build_up = false;
+
+            let path = self.states.build_up_path_input.to_str().to_string();
+            let status = if path.is_empty() {
+                "no path to export to".to_string()
+            } else {
+                match build_up::export(&mut self.renderer,
+                                       &self.states,
+                                       &self.states.build_up_settings,
+                                       &path) {
+                    Ok(count) => format!("exported {} frames to {}", count, path),
+                    Err(err) => format!("failed to export: {}", err),
+                }
+            };
+            self.states.build_up_status = Some(status);
+
+            // Layer caches hold the last frame now.
+            self.states.need_update_brush_preview = true;
+        }
+
+        if self.states.need_export_svg {
+            self.states.need_export_svg = false;
+
+            let path = self.states.svg_path_input.to_str().to_string();
+            let status = if path.is_empty() {
+                "no path to export to".to_string()
+            } else {
+                match svg::export(&self.renderer, &self.states, &path) {
+                    Ok(_) => format!("exported outlines to {}", path),
+                    Err(err) => format!("failed to export: {}", err),
+                }
+            };
+            self.states.svg_export_status = Some(status);
+        }
+
         if self.states.need_update_brush_preview {
             self.states.need_update_brush_preview = false;
 
-            self.construct_brush_tex();
+            let errors = self.renderer.load_texture_sets(&self.states);
+            if let Some((name, err)) = errors.first() {
+                self.states.texture_set_error = Some(format!("texture set \"{}\": {}",
+                                                             name,
+                                                             err));
+            }
+
+            match self.states.replay {
+                Some(ref replay) => {
+                    let layers = replay.layers_so_far();
+                    self.renderer.construct_brush_tex_of(&self.states, &layers);
+                }
+                None => self.renderer.construct_brush_tex(&self.states),
+            }
         }
 
         if self.states.show_brush_preview {
-            self.draw_texture_on(&self.brush_preview_tex, &mut target);
+            self.renderer.draw_texture_on(&self.renderer.brush_preview_tex, &mut target);
         }
 
-        if self.states.show_stroke_outline_preview {
-            self.draw_texture_on(&self.stroke_outline_tex, &mut target);
-        }
+        // Intermediate textures are previewed for active layer only.
+        if let Some(layer) = self.renderer.layer_textures(self.states.active_layer) {
+            if self.states.show_stroke_outline_preview {
+                self.renderer.draw_texture_on(&layer.stroke_outline_tex, &mut target);
+            }
 
-        if self.states.show_ink_quantity_preview {
-            self.draw_texture_on(&self.stroke_ink_quantity_tex, &mut target);
+            if self.states.show_ink_quantity_preview {
+                self.renderer.draw_texture_on(&layer.stroke_ink_quantity_tex, &mut target);
+            }
         }
 
         g2d.draw(&mut target, args.viewport(), |c, g| {
             self.render_rust_logo_and_demo(c.transform, g);
-            self.render_stroke_anchor_points(c.transform, g);
+            self.render_ink_palette(c.transform, g);
+
+            // Anchors and selection belong to the painting as it is, not as replayed.
+            if self.states.replay.is_none() {
+                self.render_stroke_anchor_points(c.transform, g);
+                self.render_selection(c.transform, g);
+            }
         });
 
-        {
-            let window = &self.window.window.borrow().window;
-            self.toolkits.render(&mut target, window, &mut self.states);
-        }
+        self.toolkits.render(&mut target, args, &mut self.states);
 
         target.finish().unwrap();
     }
 
-    fn draw_texture_on<S: Surface>(&self, tex: &Texture2d, target: &mut S) {
-        let draw_state =
-            glium::DrawParameters { blend: Blend::alpha_blending(), ..Default::default() };
-        target.draw(&self.final_vertex_buffer,
-                  &NoIndices(PrimitiveType::TriangleStrip),
-                  &self.final_program,
-                  &uniform!{
-                        tex: tex,
-                    },
-                  &draw_state)
-            .expect("failed to draw triangle list");
-    }
-
     fn handle_inputs(&mut self, event: &piston::input::Input) {
         use piston::input::*;
 
-        match event {
-            &Input::Press(Button::Mouse(button)) => {
+        match *event {
+            Input::Button(ButtonArgs { state: ButtonState::Press,
+                                       button: Button::Mouse(button),
+                                       .. }) => {
                 match button {
-                    MouseButton::Right => {
-                        self.states.is_recording_trajectory = true;
-                        self.states.current_recording_cooldown = 0.;
+                    MouseButton::Left if !self.toolkits.want_capture_mouse() => {
+                        self.begin_drag();
                     }
+                    MouseButton::Right => self.begin_stroke(),
                     _ => {}
                 }
             }
 
-            &Input::Release(Button::Mouse(button)) => {
+            Input::Button(ButtonArgs { state: ButtonState::Release,
+                                       button: Button::Mouse(button),
+                                       .. }) => {
                 match button {
-                    MouseButton::Right => {
-                        self.states.is_recording_trajectory = false;
-                        self.states.need_update_brush_preview = true;
+                    MouseButton::Left => self.end_drag(),
+                    MouseButton::Right => self.end_stroke(),
+                    _ => {}
+                }
+            }
 
-                        self.states
-                            .stroke_records
-                            .push(self.states.recording_stroke_anchors.clone());
-                        self.states.recording_stroke_anchors.clear();
+            Input::Button(ButtonArgs { state: ButtonState::Press,
+                                       button: Button::Keyboard(key),
+                                       .. }) => {
+                // Keys typed into a text field of gui are not canvas shortcuts.
+                let is_shortcut = !self.toolkits.want_capture_keyboard();
+
+                match key {
+                    Key::LCtrl | Key::RCtrl => self.is_ctrl_pressed = true,
+                    Key::LShift | Key::RShift => self.is_shift_pressed = true,
+                    Key::LAlt | Key::RAlt => self.is_alt_pressed = true,
+                    Key::Z if self.is_ctrl_pressed && is_shortcut => {
+                        history::undo(&mut self.states)
+                    }
+                    Key::Y if self.is_ctrl_pressed && is_shortcut => {
+                        history::redo(&mut self.states)
+                    }
+                    Key::D if self.is_ctrl_pressed && is_shortcut => {
+                        selection::duplicate_selected(&mut self.states)
                     }
+                    // Brush soaks up ink as long as key is held.
+                    Key::D if is_shortcut => self.states.is_dipping = true,
+                    Key::Delete if is_shortcut => selection::delete_selected(&mut self.states),
+                    _ => {}
+                }
+            }
+
+            Input::Button(ButtonArgs { state: ButtonState::Release,
+                                       button: Button::Keyboard(key),
+                                       .. }) => {
+                match key {
+                    Key::LCtrl | Key::RCtrl => self.is_ctrl_pressed = false,
+                    Key::LShift | Key::RShift => self.is_shift_pressed = false,
+                    Key::LAlt | Key::RAlt => self.is_alt_pressed = false,
+                    Key::D => self.states.is_dipping = false,
                     _ => {}
                 }
             }
 
-            &Input::Move(Motion::MouseCursor(x, y)) => {
-                let states = &mut self.states;
+            Input::Resize(ResizeArgs { window_size: [w, h], draw_size }) => {
+                // Canvas always fills the window.
+                self.renderer.resize(draw_size[0], draw_size[1]);
 
-                if states.is_recording_trajectory {
-                    while states.current_recording_cooldown >= states.max_recording_cooldown {
-                        states.current_recording_cooldown -= states.max_recording_cooldown;
+                self.states.canvas_size = [w as u32, h as u32];
+                self.states.canvas_size_input = [w as i32, h as i32];
+                self.states.need_update_brush_preview = true;
+            }
+
+            Input::Move(Motion::MouseCursor([x, y])) => {
+                let pos = [x as f32, y as f32];
+                self.cursor_pos = pos;
 
-                        let new_stroke_anchor = StrokeAnchor::new(&[x as f32, y as f32], 1.);
-                        states.recording_stroke_anchors.add_anchor(new_stroke_anchor);
+                // Show stroke where it is being dragged to, not only once it is dropped.
+                if let Some(ref drag) = self.drag {
+                    if let Some(stroke) = drag.stroke_mut(&mut self.states) {
+                        *stroke = drag.transformed(pos);
+                        self.states.need_update_brush_preview = true;
                     }
                 }
-            }
 
-            _ => {}
-        }
-    }
+                self.states.hovered_anchor = if self.states.show_anchors && self.drag.is_none() {
+                    selection::hit_anchor(&self.states, pos)
+                } else {
+                    None
+                };
 
-    fn render_rust_logo_and_demo(&self,
-                                 transform: Matrix2d,
-                                 g: &mut glium_graphics::GliumGraphics<glium::Frame>) {
-        use graphics::*;
+                if !self.can_record_anchor_at(pos) {
+                    return;
+                }
 
-        rectangle([1.0, 0.0, 0.0, 1.0], [0.0, 0.0, 100.0, 100.0], transform, g);
-        rectangle([0.0, 1.0, 0.0, 0.3],
-                  [50.0, 50.0, 100.0, 100.0],
-                  transform,
-                  g);
-        image(&self.rust_logo, transform.trans(100.0, 100.0), g);
-    }
+                let pressure = self.simulate_pressure(pos);
 
-    fn render_stroke_anchor_points<G: Graphics>(&self, transform: Matrix2d, g: &mut G) {
-        use graphics::*;
-        use graphics::ellipse::circle;
+                self.record_anchor(StrokeAnchor::new(&pos, pressure));
+            }
 
-        let mut draw_one_stroke = |one_stroke: &states::OneStroke| {
-            let mut stroke_records_iter = one_stroke.anchors.iter();
+            // Scroll over an anchor to change its pressure.
+            Input::Move(Motion::MouseScroll([_, y])) => {
+                if self.toolkits.want_capture_mouse() {
+                    return;
+                }
 
-            if let Some(mut prev_stroke_anchor) = stroke_records_iter.next() {
-                for stroke_anchor in stroke_records_iter {
-                    let prev_anchor_pos = &prev_stroke_anchor.pos;
-                    let anchor_pos = &stroke_anchor.pos;
+                if let Some((stroke_index, anchor_index)) = self.states.hovered_anchor {
+                    selection::adjust_anchor_pressure(&mut self.states,
+                                                      stroke_index,
+                                                      anchor_index,
+                                                      y as f32 * PRESSURE_SCROLL_STEP);
+                }
+            }
 
-                    // Draw line between dots
-                    if self.states.show_stroke_lines {
-                        line([0., 0.2, 1., 1.0],
-                             self.states.stroke_line_radius as f64,
-                             [prev_anchor_pos[0] as f64,
-                              prev_anchor_pos[1] as f64,
-                              anchor_pos[0] as f64,
-                              anchor_pos[1] as f64],
-                             transform,
-                             g);
+            // Tablets show up as touches, pressure is simulated if backend does not report it.
+            Input::Move(Motion::Touch(ref args)) => {
+                // Glutin reports touch position in window coordinates, same as mouse.
+                let position = args.position();
+                let pos = [position[0] as f32, position[1] as f32];
+
+                match args.touch {
+                    // No cursor moves before a touch, so palette check needs its position.
+                    Touch::Start => {
+                        self.cursor_pos = pos;
+                        self.begin_stroke();
                     }
-
-                    // Draw dot
-                    if self.states.show_anchors {
-                        ellipse([1.0, 0.0, 0.0, 0.8],
-                                circle(anchor_pos[0] as f64, anchor_pos[1] as f64, 3.0),
-                                transform,
-                                g);
+                    Touch::Move => {
+                        if !self.can_record_anchor_at(pos) {
+                            return;
+                        }
+
+                        let reported_pressure = self.touch_pressure.next(args);
+                        let pressure = match reported_pressure {
+                            Some(pressure) => pressure,
+                            None => self.simulate_pressure(pos),
+                        };
+
+                        self.record_anchor(StrokeAnchor::new(&pos, pressure));
                     }
-
-                    prev_stroke_anchor = stroke_anchor;
+                    Touch::End | Touch::Cancel => self.end_stroke(),
                 }
             }
-        };
 
-        for one_stroke in &self.states.stroke_records {
-            draw_one_stroke(one_stroke);
+            _ => {}
         }
-
-        draw_one_stroke(&self.states.recording_stroke_anchors);
     }
 
-    fn render_circle(&self,
-                     target_tex: &Texture2d,
-                     center: [f32; 2],
-                     radius: f32,
-                     brush_color: &[f32; 4]) {
-        target_tex.as_surface()
-            .draw(&self.final_vertex_buffer,
-                  &NoIndices(PrimitiveType::TriangleStrip),
-                  &self.circle_program,
-                  &uniform!{
-                      center: center,
-                      radius: radius,
-                      brush_color: *brush_color,
-                  },
-                  &DrawParameters::default())
-            .expect("failed to draw triangle list");
-    }
+    fn begin_stroke(&mut self) {
+        // Pressing on palette dips brush instead of drawing.
+        if ink::is_in_palette(self.states.canvas_size, self.cursor_pos) {
+            self.states.is_dipping = true;
+            return;
+        }
 
-    fn render_triangle_lists_on(&self,
-                                triangles: &[Vertex],
-                                target_tex: &Texture2d,
-                                brush_color: &[f32; 4]) {
-        let Size { width, height } = self.window.draw_size();
-
-        let triangles: Vec<_> = triangles.iter()
-            .map(|v| {
-                Vertex {
-                    pos: [2.0 * v.pos[0] / width as f32 - 1.0,
-                          2.0 * (900.0 - v.pos[1]) / height as f32 - 1.0],
-                }
-            })
-            .collect();
-
-        let vertex_buffer = glium::VertexBuffer::new(&self.window, &triangles).unwrap();
-        target_tex.as_surface()
-            .draw(&vertex_buffer,
-                  &NoIndices(PrimitiveType::TriangleStrip),
-                  &self.triangle_program,
-                  &uniform!{
-                      brush_color: *brush_color,
-                  },
-                  &DrawParameters::default())
-            .expect("failed to draw triangle list");
+        // Hidden or locked layers are never drawn on, nor is a painting being replayed.
+        if !self.states.active_layer().is_editable() || self.states.replay.is_some() {
+            return;
+        }
+
+        self.states.is_recording_trajectory = true;
+        self.states.current_recording_cooldown = 0.;
+        self.states.recording_stroke_anchors.brush = StrokeBrush::from_states(&self.states);
+        self.states.recording_stroke_anchors.ink_load = Some(ink::load_brush(&mut self.states));
+        self.stroke_start = Instant::now();
+        self.states.recording_stroke_anchors.start_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|since| since.as_secs() as f64 + since.subsec_nanos() as f64 / 1_000_000_000.0);
+
+        self.pressure_simulator.reset();
+        self.touch_pressure.reset();
     }
 
-    fn caculate_anchor_polygon(&self,
-                               prev_stroke_anchor: &StrokeAnchor,
-                               stroke_anchor: &StrokeAnchor)
-                               -> [Vertex; 4] {
-        let prev_anchor_pos = &prev_stroke_anchor.pos;
-        let anchor_pos = &stroke_anchor.pos;
+    fn end_stroke(&mut self) {
+        self.states.is_dipping = false;
 
-        let start_pos = math::cast([prev_anchor_pos[0], prev_anchor_pos[1]]);
-        let end_pos = math::cast([anchor_pos[0], anchor_pos[1]]);
+        if !self.states.is_recording_trajectory {
+            return;
+        }
 
-        let norm_v = vecmath::vec2_normalized([(anchor_pos[0] - prev_anchor_pos[0]) as f32,
-                                               (anchor_pos[1] - prev_anchor_pos[1]) as f32]);
+        self.states.is_recording_trajectory = false;
+
+        let new_stroke = simplify::simplify_stroke(&self.states.recording_stroke_anchors,
+                                                   self.states.simplify_tolerance);
+        let ink_cost = self.renderer.stroke_ink_cost(&self.states, &new_stroke);
+        ink::spend(&mut self.states, ink_cost);
+        let layer = self.states.active_layer;
+        history::execute(&mut self.states,
+                         Command::AddStroke {
+                             layer,
+                             stroke: new_stroke,
+                         });
+        self.states.recording_stroke_anchors.clear();
+    }
 
-        let start_brush_width = self.caculate_brush_radius(prev_stroke_anchor.pressure);
-        let end_brush_width = self.caculate_brush_radius(stroke_anchor.pressure);
+    fn simulate_pressure(&mut self, pos: [f32; 2]) -> f32 {
+        let states = &self.states;
 
-        let start_v = math::mul_scalar(norm_v, start_brush_width);
-        let end_v = math::mul_scalar(norm_v, end_brush_width);
+        if !states.simulate_pressure {
+            return 1.;
+        }
 
-        let rotate_right = math::rotate_radians(std::f32::consts::PI / 2.);
-        let rotate_left = math::rotate_radians(std::f32::consts::PI / -2.);
+        // Cooldown is reset whenever an anchor is recorded.
+        self.pressure_simulator.next(pos,
+                                     states.current_recording_cooldown,
+                                     states.stroke_speed_factor)
+    }
 
-        let start_a_mat = math::translate(math::transform_vec(rotate_left, start_v));
-        let start_b_mat = math::translate(math::transform_vec(rotate_right, start_v));
-        let end_a_mat = math::translate(math::transform_vec(rotate_left, end_v));
-        let end_b_mat = math::translate(math::transform_vec(rotate_right, end_v));
+    /// Left click on canvas.
+    ///
+    /// With anchors shown, alt click deletes an anchor, shift click inserts one on a segment
+    /// and dragging an anchor moves it. Otherwise grab handle of selected stroke under cursor,
+    /// or select stroke under cursor.
+    fn begin_drag(&mut self) {
+        use selection::Handle;
 
-        [Vertex { pos: math::transform_pos(start_a_mat, start_pos) },
-         Vertex { pos: math::transform_pos(end_a_mat, end_pos) },
-         Vertex { pos: math::transform_pos(start_b_mat, start_pos) },
-         Vertex { pos: math::transform_pos(end_b_mat, end_pos) }]
-    }
+        if self.states.replay.is_some() {
+            return;
+        }
 
-    fn render_stroke_ink_outline_tex(&self) {
-        self.stroke_outline_tex.as_surface().clear_color(1.0, 1.0, 1.0, 1.0);
+        let pos = self.cursor_pos;
 
-        let render_circle_part = |stroke_anchor: &StrokeAnchor, stroke_color| {
-            let radius = self.caculate_brush_radius(stroke_anchor.pressure);
-            self.render_circle(&self.stroke_outline_tmp_tex,
-                               [stroke_anchor.pos[0], stroke_anchor.pos[1]],
-                               radius,
-                               &stroke_color);
+        let hit_anchor = if self.states.show_anchors {
+            selection::hit_anchor(&self.states, pos)
+        } else {
+            None
         };
 
-        // Render polygon part between each anchor.
-        for stroke in &self.states.stroke_records {
-            if stroke.anchors.is_empty() {
-                continue;
+        if self.states.show_anchors && self.is_alt_pressed {
+            if let Some((stroke_index, anchor_index)) = hit_anchor {
+                selection::delete_anchor(&mut self.states, stroke_index, anchor_index);
+                self.states.hovered_anchor = None;
             }
+            return;
+        }
 
-            // Texture used for storing new stroke.
-            self.stroke_outline_tmp_tex.as_surface().clear_color(0.0, 0.0, 0.0, 0.0);
-
-            let mut stroke_anchors_iter = stroke.anchors.iter();
-            let mut prev_stroke_anchor = stroke_anchors_iter.next().unwrap();
-
-            let stroke_color = match self.states.render_mode {
-                RenderMode::Colored => stroke.color,
-                _ => [1.0, 0.0, 0.0, 1.0],
-            };
-
-            // Draw circle of start anchor.
-            render_circle_part(prev_stroke_anchor, stroke_color);
-
-            // Draw outline form by all anchor.
-            for stroke_anchor in stroke_anchors_iter {
-                let polygon_points =
-                    self.caculate_anchor_polygon(prev_stroke_anchor, stroke_anchor);
+        if self.states.show_anchors && self.is_shift_pressed {
+            if let Some((index, new)) = selection::insert_anchor_at(&self.states, pos) {
+                let layer = self.states.active_layer;
+                let old = self.states.strokes()[index].clone();
+                history::execute(&mut self.states,
+                                 Command::EditStroke {
+                                     layer,
+                                     index,
+                                     old,
+                                     new,
+                                 });
+            }
+            return;
+        }
 
-                render_circle_part(stroke_anchor, stroke_color);
-                self.render_triangle_lists_on(&polygon_points,
-                                              &self.stroke_outline_tmp_tex,
-                                              &stroke_color);
+        let handle = selection::selected_stroke(&self.states)
+            .and_then(|index| selection::hit_handle(&self.states, index, pos).map(|h| (index, h)))
+            .and_then(|(index, handle)| match handle {
+                // Anchors inside bounding box take priority over moving whole stroke.
+                Handle::Move if hit_anchor.is_some() => None,
+                _ => Some((index, handle)),
+            });
+        let target = handle.or_else(|| {
+                hit_anchor.map(|(stroke_index, anchor_index)| {
+                    (stroke_index, Handle::Anchor(anchor_index))
+                })
+            })
+            .or_else(|| selection::hit_test(&self.states, pos).map(|index| (index, Handle::Move)));
 
-                prev_stroke_anchor = &stroke_anchor;
-            }
+        self.states.selected_stroke = target.map(|(index, _)| index);
+        self.drag = target.map(|(index, handle)| {
+            selection::Drag::new(&self.states, index, handle, pos)
+        });
+    }
 
-            // Wipe previous pigment on canvas according to current new stroke.
-            self.wipe_pigment_by_stroke(&self.stroke_outline_tex, stroke);
+    fn end_drag(&mut self) {
+        let drag = match self.drag.take() {
+            Some(drag) => drag,
+            None => return,
+        };
 
-            // Fake diffusion on canvas according to current new stroke.
-            self.render_fake_stroke_diffusion(&self.stroke_outline_tex, stroke);
+        let new = match drag.stroke_mut(&mut self.states) {
+            Some(stroke) => stroke.clone(),
+            None => return,
+        };
 
-            // Blit new stroke onto previous canvas.
-            self.draw_texture_on(&self.stroke_outline_tmp_tex,
-                                 &mut self.stroke_outline_tex.as_surface());
+        if new != drag.original {
+            self.states.history.record_applied(Command::EditStroke {
+                layer: drag.layer,
+                index: drag.index,
+                old: drag.original,
+                new,
+            });
+            self.states.need_update_brush_preview = true;
         }
     }
 
-    fn render_fake_stroke_diffusion(&self, canvas: &Texture2d, stroke: &OneStroke) {
-        if stroke.anchors.is_empty() {
-            return;
-        }
-
-        let mut canvas_surface = canvas.as_surface();
-        let mut diffusion_tmp_surface = self.diffusion_tmp_tex.as_surface();
-
-        let mut haha = |brush_color, radius_offset| {
-            let mut stroke_anchors_iter = stroke.anchors.iter();
-            let mut prev_stroke_anchor = stroke_anchors_iter.next().unwrap();
+    /// Whether poke interval has passed and pointer moved far enough from last anchor.
+    fn can_record_anchor_at(&self, pos: [f32; 2]) -> bool {
+        let states = &self.states;
 
-            diffusion_tmp_surface.clear_color(0.0, 0.0, 0.0, 0.0);
+        if !states.is_recording_trajectory ||
+           states.current_recording_cooldown < states.max_recording_cooldown {
+            return false;
+        }
 
-            for stroke_anchor in stroke_anchors_iter {
-                let stroke_start_pos = &prev_stroke_anchor.pos;
-                let stroke_end_pos = &stroke_anchor.pos;
+        match states.recording_stroke_anchors.anchors.last() {
+            Some(last) => {
+                vecmath::vec2_len(vecmath::vec2_sub(pos, last.pos)) >= states.min_anchor_distance
+            }
+            None => true,
+        }
+    }
 
-                let start_radius = self.caculate_brush_radius(prev_stroke_anchor.pressure);
-                let end_radius = self.caculate_brush_radius(stroke_anchor.pressure);
+    fn record_anchor(&mut self, anchor: StrokeAnchor) {
+        let elapsed = self.stroke_start.elapsed();
+        let time = elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1_000_000_000.0;
 
-                canvas_surface.fill(&diffusion_tmp_surface,
-                                    glium::uniforms::MagnifySamplerFilter::Nearest);
+        self.states.current_recording_cooldown = 0.;
+        self.states.recording_stroke_anchors.add_anchor(anchor.with_time(time));
+    }
 
-                canvas_surface.draw(&self.final_vertex_buffer,
-                          &NoIndices(PrimitiveType::TriangleStrip),
-                          &self.diffusion_program,
-                          &uniform!{
-                              current_tex: &self.diffusion_tmp_tex,
+    fn render_rust_logo_and_demo(&self,
+                                 transform: Matrix2d,
+                                 g: &mut glium_graphics::GliumGraphics<glium::Frame>) {
+        use graphics::*;
 
-                              brush_color: brush_color,
+        rectangle([1.0, 0.0, 0.0, 1.0], [0.0, 0.0, 100.0, 100.0], transform, g);
+        rectangle([0.0, 1.0, 0.0, 0.3],
+                  [50.0, 50.0, 100.0, 100.0],
+                  transform,
+                  g);
+        image(&self.rust_logo, transform.trans(100.0, 100.0), g);
+    }
 
-                              stroke_start_pos: *stroke_start_pos,
-                              stroke_end_pos: *stroke_end_pos,
+    /// Palette strokes dip into, filled as far as brush reservoir is.
+    fn render_ink_palette<G: Graphics>(&self, transform: Matrix2d, g: &mut G) {
+        use graphics::*;
 
-                              start_radius: start_radius + radius_offset,
-                              end_radius: end_radius + radius_offset,
-                          },
-                          &DrawParameters::default())
-                    .expect("failed to draw triangle list");
+        let rect = ink::palette_rect(self.states.canvas_size);
+        let rect = [rect[0] as f64, rect[1] as f64, rect[2] as f64, rect[3] as f64];
 
-                prev_stroke_anchor = stroke_anchor;
-            }
+        let border = if self.states.is_dipping {
+            [1.0, 0.6, 0.0, 1.0]
+        } else {
+            [0.5, 0.5, 0.5, 1.0]
         };
+        rectangle([0.9, 0.9, 0.9, 1.0], rect, transform, g);
+        Rectangle::new_border(border, 1.0).draw(rect, &Default::default(), transform, g);
+
+        let fill = ink::load_fraction(self.states.ink_reservoir, ink::capacity(&self.states))
+            .clamp(0.0, 1.0);
+        let height = rect[3] * fill as f64;
+        rectangle([0.1, 0.1, 0.1, 0.8],
+                  [rect[0], rect[1] + rect[3] - height, rect[2], height],
+                  transform,
+                  g);
+    }
 
-        let inner_brush_color = {
-            let mut c = stroke.color;
+    /// Highlight selected stroke with its bounding box and transform handles.
+    fn render_selection<G: Graphics>(&self, transform: Matrix2d, g: &mut G) {
+        use graphics::*;
+        use graphics::ellipse::circle;
 
-            c[0] *= 0.7;
-            c[1] *= 0.7;
-            c[2] *= 0.7;
+        const HIGHLIGHT_COLOR: [f32; 4] = [1.0, 0.6, 0.0, 1.0];
 
-            c
+        let stroke = match selection::selected_stroke(&self.states) {
+            Some(index) => &self.states.strokes()[index],
+            None => return,
         };
-        let outter_brush_color = {
-            let mut c = stroke.color;
-
-            c[0] *= 1.3;
-            c[1] *= 1.3;
-            c[2] *= 1.3;
 
-            c
-        };
+        for segment in stroke.anchors.windows(2) {
+            let (start, end) = (segment[0].pos, segment[1].pos);
+            line(HIGHLIGHT_COLOR,
+                 1.5,
+                 [start[0] as f64, start[1] as f64, end[0] as f64, end[1] as f64],
+                 transform,
+                 g);
+        }
 
-        haha(outter_brush_color, 10.0);
-        haha(inner_brush_color, 2.5);
-    }
+        if let Some((min, max)) = selection::stroke_bounds(stroke) {
+            let (min, max) = ([min[0] as f64, min[1] as f64], [max[0] as f64, max[1] as f64]);
+            let edges = [[min[0], min[1], max[0], min[1]],
+                         [max[0], min[1], max[0], max[1]],
+                         [max[0], max[1], min[0], max[1]],
+                         [min[0], max[1], min[0], min[1]]];
 
-    fn wipe_pigment_by_stroke(&self, canvas: &Texture2d, stroke: &OneStroke) {
-        if stroke.anchors.is_empty() {
-            return;
+            for edge in &edges {
+                line(HIGHLIGHT_COLOR, 0.5, *edge, transform, g);
+            }
         }
 
-        let mut wipe_tmp_surface = self.wipe_tmp_tex.as_surface();
-        let mut canvas_surface = canvas.as_surface();
+        if let Some(handles) = selection::handle_positions(stroke) {
+            for &(_, pos) in &handles {
+                ellipse(HIGHLIGHT_COLOR,
+                        circle(pos[0] as f64, pos[1] as f64, selection::HANDLE_RADIUS as f64),
+                        transform,
+                        g);
+            }
+        }
+    }
 
-        wipe_tmp_surface.clear_color(0.0, 0.0, 0.0, 0.0);
+    fn render_stroke_anchor_points<G: Graphics>(&self, transform: Matrix2d, g: &mut G) {
+        use graphics::*;
+        use graphics::ellipse::circle;
 
-        let mut stroke_anchors_iter = stroke.anchors.iter();
-        let mut prev_stroke_anchor = stroke_anchors_iter.next().unwrap();
+        let mut draw_one_stroke = |one_stroke: &states::OneStroke| {
+            let mut stroke_records_iter = one_stroke.anchors.iter();
 
-        for stroke_anchor in stroke_anchors_iter {
-            let stroke_start_pos = &prev_stroke_anchor.pos;
-            let stroke_end_pos = &stroke_anchor.pos;
+            if let Some(mut prev_stroke_anchor) = stroke_records_iter.next() {
+                for stroke_anchor in stroke_records_iter {
+                    let prev_anchor_pos = &prev_stroke_anchor.pos;
+                    let anchor_pos = &stroke_anchor.pos;
 
-            let start_radius = self.caculate_brush_radius(prev_stroke_anchor.pressure);
-            let end_radius = self.caculate_brush_radius(stroke_anchor.pressure);
+                    // Draw line between dots
+                    if self.states.show_stroke_lines {
+                        line([0., 0.2, 1., 1.0],
+                             self.states.stroke_line_radius as f64,
+                             [prev_anchor_pos[0] as f64,
+                              prev_anchor_pos[1] as f64,
+                              anchor_pos[0] as f64,
+                              anchor_pos[1] as f64],
+                             transform,
+                             g);
+                    }
 
-            // Copy canvas to wipe_tmp_tex.
-            canvas_surface.fill(&wipe_tmp_surface,
-                                glium::uniforms::MagnifySamplerFilter::Nearest);
+                    // Draw dot
+                    if self.states.show_anchors {
+                        ellipse([1.0, 0.0, 0.0, 0.8],
+                                circle(anchor_pos[0] as f64,
+                                       anchor_pos[1] as f64,
+                                       selection::ANCHOR_RADIUS as f64),
+                                transform,
+                                g);
+                    }
 
-            // Do actual wipe opeartion.
-            canvas_surface.draw(&self.final_vertex_buffer,
-                      &NoIndices(PrimitiveType::TriangleStrip),
-                      &self.wipe_program,
-                      &uniform!{
-                          current_tex: &self.wipe_tmp_tex,
+                    prev_stroke_anchor = stroke_anchor;
+                }
+            }
+        };
 
-                          stroke_start_pos: *stroke_start_pos,
-                          stroke_end_pos: *stroke_end_pos,
+        // Only strokes of active layer can be edited.
+        for one_stroke in self.states.strokes() {
+            draw_one_stroke(one_stroke);
+        }
 
-                          start_radius: start_radius,
-                          end_radius: end_radius,
-                      },
-                      &DrawParameters::default())
-                .expect("failed to draw triangle list");
+        draw_one_stroke(&self.states.recording_stroke_anchors);
 
-            prev_stroke_anchor = stroke_anchor;
+        // Emphasize anchor under cursor
+        if let Some((stroke_index, anchor_index)) = self.states.hovered_anchor {
+            let anchor = self.states
+                .strokes()
+                .get(stroke_index)
+                .and_then(|stroke| stroke.anchors.get(anchor_index));
+            if let Some(anchor) = anchor {
+                ellipse([1.0, 0.6, 0.0, 1.0],
+                        circle(anchor.pos[0] as f64,
+                               anchor.pos[1] as f64,
+                               2.0 * selection::ANCHOR_RADIUS as f64),
+                        transform,
+                        g);
+            }
         }
     }
+}
 
-    fn render_stroke_ink_quantity_tex(&self) {
-        let draw = |start_pos: [f32; 2],
-                    start_radius,
-                    start_ink_quantity,
-                    end_pos: [f32; 2],
-                    end_radius,
-                    end_ink_quantity| {
-
-            self.stroke_ink_quantity_tex
-                .as_surface()
-                .draw(&self.final_vertex_buffer,
-                      &NoIndices(PrimitiveType::TriangleStrip),
-                      &self.stroke_ink_quantity_program,
-                      &uniform!{
-                          stroke_outline_tex: &self.stroke_outline_tex,
-                          stroke_ink_quantity_tmp_tex: &self.stroke_ink_quantity_tmp_tex,
-
-                          start_pos: start_pos,
-                          end_pos: end_pos,
-
-                          start_radius: start_radius,
-                          end_radius: end_radius,
-
-                          start_ink_quantity: start_ink_quantity,
-                          end_ink_quantity: end_ink_quantity,
-                      },
-                      &DrawParameters::default())
-                .expect("failed to draw triangle list");
-
-            // Copy to tmp texture for future reference
-            self.stroke_ink_quantity_tex
-                .as_surface()
-                .fill(&self.stroke_ink_quantity_tmp_tex.as_surface(),
-                      glium::uniforms::MagnifySamplerFilter::Nearest);
-        };
+/// Render document without opening any window and save brush preview as PNG.
+///
+/// Output is `scale` times larger than canvas of the document, e.g. for printing.
+fn render_headless(document_path: &str, output_path: &str, scale: f32) {
+    let mut states = states::States::default();
+    document::load(document_path)
+        .expect("failed to load document")
+        .apply_to(&mut states);
+
+    let w = (states.canvas_size[0] as f32 * scale).round() as u32;
+    let h = (states.canvas_size[1] as f32 * scale).round() as u32;
+    let mut renderer = renderer::build_headless_renderer(w, h);
+
+    renderer.construct_brush_tex(&states);
+    renderer.save_brush_preview_png(output_path).expect("failed to save png");
+}
 
-        self.stroke_ink_quantity_tex.as_surface().clear_color(0.0, 0.0, 0.0, 0.0);
-        self.stroke_ink_quantity_tmp_tex.as_surface().clear_color(0.0, 0.0, 0.0, 0.0);
+/// Render how document was painted into an animated GIF, or a directory of PNG files if
+/// `output_path` does not end with `.gif`.
+///
+/// Frames are rendered whenever a stroke is finished, or every `interval` seconds if given.
+fn export_build_up_headless(document_path: &str, output_path: &str, interval: Option<f32>) {
+    let mut states = states::States::default();
+    document::load(document_path)
+        .expect("failed to load document")
+        .apply_to(&mut states);
+
+    let mut settings = build_up::BuildUpSettings::default();
+    if !output_path.ends_with(".gif") {
+        settings.format = build_up::FrameFormat::PngSequence;
+    }
+    if let Some(interval) = interval {
+        settings.timing = build_up::FrameTiming::FixedInterval;
+        settings.interval = interval;
+        settings.frame_delay = interval;
+    }
 
-        for stroke in &self.states.stroke_records {
-            let mut stroke_iter = stroke.anchors.iter();
-            let mut current_ink_quantity = self.states.initial_ink_quantity;
+    let mut renderer = renderer::build_headless_renderer(states.canvas_size[0],
+                                                         states.canvas_size[1]);
 
-            if let Some(mut prev_stroke_anchor) = stroke_iter.next() {
-                for stroke_anchor in stroke_iter {
-                    let start_pos = &prev_stroke_anchor.pos;
-                    let end_pos = &stroke_anchor.pos;
+    let count = build_up::export(&mut renderer, &states, &settings, output_path)
+        .expect("failed to export build-up");
+    println!("exported {} frames", count);
+}
 
-                    let start_radius = self.caculate_brush_radius(prev_stroke_anchor.pressure);
-                    let end_radius = self.caculate_brush_radius(stroke_anchor.pressure);
+/// Export ink outlines of document as SVG without opening any window.
+fn export_svg_headless(document_path: &str, output_path: &str) {
+    let mut states = states::States::default();
+    document::load(document_path)
+        .expect("failed to load document")
+        .apply_to(&mut states);
 
-                    let ink_cost =
-                        self.caculate_ink_cost(start_pos, start_radius, end_pos, end_radius);
+    let renderer = renderer::build_headless_renderer(states.canvas_size[0],
+                                                     states.canvas_size[1]);
 
-                    let start_ink_quantity = current_ink_quantity /
-                                             self.states.initial_ink_quantity;
-                    let end_ink_quantity = (current_ink_quantity - ink_cost) /
-                                           self.states.initial_ink_quantity;
+    svg::export(&renderer, &states, output_path).expect("failed to export svg");
+}
 
-                    current_ink_quantity -= ink_cost;
+/// Time `construct_brush_tex` with full-screen segment passes and with bounded ones.
+fn run_benchmark(document_path: &str, iterations: u32) {
+    let mut states = states::States::default();
+    document::load(document_path)
+        .expect("failed to load document")
+        .apply_to(&mut states);
+
+    let mut renderer = renderer::build_headless_renderer(states.canvas_size[0],
+                                                         states.canvas_size[1]);
+
+    let mut timings = Vec::new();
+    for &bounded in &[false, true] {
+        renderer.bounded_stroke_geometry = bounded;
+
+        // Warm up, so shader compilation and allocation are not measured.
+        renderer.invalidate_checkpoint();
+        renderer.construct_brush_tex(&states);
+        renderer.finish();
+
+        let start = Instant::now();
+        for _ in 0..iterations {
+            // Measure full rebuilds, not the incremental path.
+            renderer.invalidate_checkpoint();
+            renderer.construct_brush_tex(&states);
+        }
+        renderer.finish();
 
-                    draw(*start_pos,
-                         start_radius,
-                         start_ink_quantity,
-                         *end_pos,
-                         end_radius,
-                         end_ink_quantity);
+        let elapsed = start.elapsed();
+        let ms = (elapsed.as_secs() as f64 * 1000.0 + elapsed.subsec_nanos() as f64 / 1e6) /
+                 iterations as f64;
+        timings.push(ms);
 
-                    prev_stroke_anchor = stroke_anchor;
-                }
-            }
-        }
+        println!("{:>11}: {:.2} ms per frame",
+                 if bounded { "bounded" } else { "full-screen" },
+                 ms);
     }
 
-    fn render_brush_tex(&self) {
-        use glium::uniforms::Sampler;
+    println!("    speedup: {:.2}x", timings[0] / timings[1]);
+}
 
-        self.brush_preview_tex.as_surface().clear_color(0.0, 0.0, 0.0, 0.0);
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
 
-        let apply_sampler = |tex| {
-            Sampler::new(tex)
-                .wrap_function(glium::uniforms::SamplerWrapFunction::Repeat)
-                .minify_filter(glium::uniforms::MinifySamplerFilter::Nearest)
-                .magnify_filter(glium::uniforms::MagnifySamplerFilter::Nearest)
-        };
+    // Usage: npr_homework1 --headless <document.json> <output.png> [scale]
+    if (args.len() == 4 || args.len() == 5) && args[1] == "--headless" {
+        let scale = args.get(4).map_or(1., |s| s.parse().expect("invalid scale"));
 
-        self.brush_preview_tex
-            .as_surface()
-            .draw(&self.final_vertex_buffer,
-                  &NoIndices(PrimitiveType::TriangleStrip),
-                  &self.black_n_white_brush_program,
-                  &uniform!{
-                      stroke_ink_quantity_tex: &self.stroke_ink_quantity_tex,
-
-                      level_0_brush_tex: apply_sampler(&self.level0_tex),
-                      level_1_brush_tex: apply_sampler(&self.level1_tex),
-                      level_2_brush_tex: apply_sampler(&self.level2_tex),
-                      level_3_brush_tex: apply_sampler(&self.level3_tex),
-                      level_4_brush_tex: apply_sampler(&self.level4_tex),
-                  },
-                  &glium::DrawParameters { smooth: Some(Smooth::Fastest), ..Default::default() })
-            .expect("failed to draw triangle list");
+        render_headless(&args[2], &args[3], scale);
+        return;
     }
 
-    fn render_watercolor_brush_tex(&self) {
-        self.brush_preview_tex.as_surface().clear_color(0.0, 0.0, 0.0, 0.0);
-
-        self.brush_preview_tex
-            .as_surface()
-            .draw(&self.final_vertex_buffer,
-                  &NoIndices(PrimitiveType::TriangleStrip),
-                  &self.watercolor_brush_program,
-                  &uniform!{
-                      stroke_outline_tex: &self.stroke_outline_tex,
-                      stroke_ink_quantity_tex: &self.stroke_ink_quantity_tex,
-                  },
-                  &DrawParameters::default())
-            .expect("failed to draw triangle list");
-    }
+    // Usage: npr_homework1 --build-up <document.json> <output.gif | output dir> [interval]
+    if (args.len() == 4 || args.len() == 5) && args[1] == "--build-up" {
+        let interval = args.get(4).map(|s| s.parse().expect("invalid interval"));
 
-    fn construct_brush_tex(&self) {
-        // TODO Use struct to wrap these up.
-        match self.states.render_mode {
-            RenderMode::BlackAndWhite => {
-                self.render_stroke_ink_outline_tex();
-                self.render_stroke_ink_quantity_tex();
-                self.render_brush_tex();
-            }
-            RenderMode::Colored => {
-                self.render_stroke_ink_outline_tex();
-                self.render_stroke_ink_quantity_tex();
-                self.render_watercolor_brush_tex();
-            }
-        }
+        export_build_up_headless(&args[2], &args[3], interval);
+        return;
     }
 
-    fn caculate_brush_radius(&self, pressure: f32) -> f32 {
-        pressure * self.states.max_brush_width
+    // Usage: npr_homework1 --svg <document.json> <output.svg>
+    if args.len() == 4 && args[1] == "--svg" {
+        export_svg_headless(&args[2], &args[3]);
+        return;
     }
 
-    fn caculate_ink_cost(&self,
-                         start_pos: &[f32; 2],
-                         start_radius: f32,
-                         end_pos: &[f32; 2],
-                         end_radius: f32)
-                         -> f32 {
-        let offset = vecmath::vec2_sub(*end_pos, *start_pos);
-        let stroke_len = vecmath::vec2_len(offset);
-        let area = (start_radius + end_radius) * stroke_len / 2.0;
-
-        self.states.ink_quantity_friction * area
+    // Usage: npr_homework1 --benchmark <document.json> [iterations]
+    if (args.len() == 3 || args.len() == 4) && args[1] == "--benchmark" {
+        let iterations = args.get(3).map_or(10, |s| s.parse().expect("invalid iterations"));
+        assert!(iterations > 0, "iterations must be positive");
+
+        run_benchmark(&args[2], iterations);
+        return;
     }
-}
 
-fn main() {
     let app = App::new();
 
-    match app.run() {
-        Ok(_) => {}
-        Err(_) => {}
-    }
+    let _ = app.run();
 }
@@ -0,0 +1,220 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use serde_json;
+
+use curve::{PressureCurve, PressureResponse};
+use history;
+use history::Command;
+use states::{BrushParameters, RenderMode, States, StrokeBrush};
+
+/// Bump this whenever layout of `PresetFile` changes.
+///
+/// Version history:
+/// 1. Initial version.
+pub const PRESET_FILE_VERSION: u32 = 1;
+
+/// Named brush, shared between painters as part of a preset file.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct BrushPreset {
+    pub name: String,
+    pub brush: StrokeBrush,
+    // Applied to active layer, render mode is a property of the whole layer.
+    pub render_mode: RenderMode,
+}
+
+impl BrushPreset {
+    /// Current brush of `states` and render mode of its active layer.
+    pub fn from_states(states: &States, name: String) -> Self {
+        BrushPreset {
+            name,
+            brush: StrokeBrush::from_states(states),
+            render_mode: states.active_layer().properties.render_mode,
+        }
+    }
+}
+
+/// One or more presets, e.g. a whole brush kit.
+#[derive(Serialize, Deserialize)]
+pub struct PresetFile {
+    pub version: u32,
+    pub presets: Vec<BrushPreset>,
+}
+
+// Only used to check version before parsing the whole file.
+#[derive(Deserialize)]
+struct PresetFileHeader {
+    version: u32,
+}
+
+pub enum PresetError {
+    Io(io::Error),
+    Parse(serde_json::Error),
+    UnsupportedVersion(u32),
+    // Name of preset with the setting and its value which can not be painted with.
+    OutOfRange(String, &'static str, f32),
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PresetError::Io(ref err) => write!(f, "io error: {}", err),
+            PresetError::Parse(ref err) => write!(f, "parse error: {}", err),
+            PresetError::UnsupportedVersion(version) => {
+                write!(f,
+                       "unsupported preset file version {} (newest supported is {})",
+                       version,
+                       PRESET_FILE_VERSION)
+            }
+            PresetError::OutOfRange(ref preset, name, value) => {
+                write!(f, "preset \"{}\": {} of {} is out of range", preset, name, value)
+            }
+        }
+    }
+}
+
+impl fmt::Debug for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl From<io::Error> for PresetError {
+    fn from(err: io::Error) -> Self {
+        PresetError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for PresetError {
+    fn from(err: serde_json::Error) -> Self {
+        PresetError::Parse(err)
+    }
+}
+
+/// Presets every library starts with.
+pub fn builtin_presets() -> Vec<BrushPreset> {
+    let preset = |name: &str,
+                  max_brush_width: f32,
+                  initial_ink_quantity: f32,
+                  ink_quantity_friction: f32,
+                  width: PressureCurve,
+                  render_mode: RenderMode| {
+        BrushPreset {
+            name: name.to_string(),
+            brush: StrokeBrush {
+                max_brush_width,
+                initial_ink_quantity,
+                ink_quantity_friction,
+                pressure_response: PressureResponse {
+                    width,
+                    ..PressureResponse::default()
+                },
+                ..StrokeBrush::default()
+            },
+            render_mode,
+        }
+    };
+
+    // Bristles run dry one by one, leaving flying white at the end of the stroke.
+    let mut chinese_brush = preset("chinese brush",
+                                   25.,
+                                   80.,
+                                   1.,
+                                   PressureCurve::linear(),
+                                   RenderMode::BlackAndWhite);
+    chinese_brush.brush.bristle_count = 24;
+
+    vec![preset("default",
+                15.,
+                100.,
+                1.,
+                PressureCurve::linear(),
+                RenderMode::BlackAndWhite),
+         // Thin line whose width barely follows pressure.
+         preset("fine liner",
+                4.,
+                100.,
+                0.2,
+                PressureCurve::constant(0.8),
+                RenderMode::BlackAndWhite),
+         // Runs out of ink quickly, leaving streaks.
+         preset("dry brush",
+                20.,
+                60.,
+                2.,
+                PressureCurve::linear(),
+                RenderMode::BlackAndWhite),
+         chinese_brush,
+         preset("wash", 40., 100., 0.5, PressureCurve::linear(), RenderMode::Colored)]
+}
+
+/// Make `preset` the brush new strokes are drawn with and switch active layer to its render
+/// mode, both undoable.
+pub fn apply(states: &mut States, preset: &BrushPreset) {
+    let old = BrushParameters::from_states(states);
+    preset.brush.apply_to(states);
+    let new = BrushParameters::from_states(states);
+
+    if old != new {
+        states.history.record_applied(Command::ChangeParameters {
+            old: Box::new(old),
+            new: Box::new(new),
+        });
+    }
+
+    let index = states.active_layer;
+    let old = states.active_layer().properties.clone();
+    if old.render_mode != preset.render_mode {
+        let mut new = old.clone();
+        new.render_mode = preset.render_mode;
+
+        history::execute(states,
+                         Command::ChangeLayer {
+                             index,
+                             old,
+                             new,
+                         });
+    }
+}
+
+pub fn export<P: AsRef<Path>>(presets: &[BrushPreset], path: P) -> Result<(), PresetError> {
+    let file = PresetFile {
+        version: PRESET_FILE_VERSION,
+        presets: presets.to_vec(),
+    };
+
+    let mut out = File::create(path)?;
+    serde_json::to_writer_pretty(&mut out, &file)?;
+
+    Ok(())
+}
+
+pub fn import<P: AsRef<Path>>(path: P) -> Result<Vec<BrushPreset>, PresetError> {
+    let value: serde_json::Value = serde_json::from_reader(File::open(path)?)?;
+
+    let header: PresetFileHeader = serde_json::from_value(value.clone())?;
+    if header.version > PRESET_FILE_VERSION {
+        return Err(PresetError::UnsupportedVersion(header.version));
+    }
+
+    let file: PresetFile = serde_json::from_value(value)?;
+    for preset in &file.presets {
+        if let Some((name, value)) = preset.brush.invalid_value() {
+            return Err(PresetError::OutOfRange(preset.name.clone(), name, value));
+        }
+    }
+
+    Ok(file.presets)
+}
+
+/// Add `presets` to `library`, replacing presets with the same name.
+pub fn merge_into(library: &mut Vec<BrushPreset>, presets: Vec<BrushPreset>) {
+    for preset in presets {
+        match library.iter().position(|existing| existing.name == preset.name) {
+            Some(index) => library[index] = preset,
+            None => library.push(preset),
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use interpolation;
+use piston::input::TouchArgs;
+use vecmath;
+
+/// Speed in canvas units per second at which a simulated stroke is half as heavy.
+const REFERENCE_SPEED: f32 = 1000.0;
+/// How much slowing down presses the brush harder.
+const ACCELERATION_WEIGHT: f32 = 0.2;
+/// Weight of new sample, the rest comes from last pressure so strokes do not jitter.
+const SMOOTHING: f32 = 0.5;
+const MIN_PRESSURE: f32 = 0.1;
+
+/// Guess pen pressure from mouse movement, for when no tablet is present.
+///
+/// Like a real brush, fast strokes are light and slowing down presses harder.
+#[derive(Default)]
+pub struct PressureSimulator {
+    last_pos: Option<[f32; 2]>,
+    last_speed: f32,
+    last_pressure: Option<f32>,
+}
+
+impl PressureSimulator {
+    /// Forget previous stroke.
+    pub fn reset(&mut self) {
+        *self = PressureSimulator::default();
+    }
+
+    /// Pressure of pointer at `pos`, `dt` seconds after previous sample.
+    pub fn next(&mut self, pos: [f32; 2], dt: f32, speed_factor: f32) -> f32 {
+        let speed = match self.last_pos {
+            Some(last_pos) => {
+                vecmath::vec2_len(vecmath::vec2_sub(pos, last_pos)) / dt.max(1e-3) /
+                REFERENCE_SPEED
+            }
+            None => 0.0,
+        };
+        let acceleration = speed - self.last_speed;
+
+        let target = 1.0 / (1.0 + speed_factor * speed) - ACCELERATION_WEIGHT * acceleration;
+        let pressure = match self.last_pressure {
+            Some(last_pressure) => interpolation::lerp(&last_pressure, &target, &SMOOTHING),
+            None => target,
+        };
+        let pressure = pressure.clamp(MIN_PRESSURE, 1.0);
+
+        self.last_pos = Some(pos);
+        self.last_speed = speed;
+        self.last_pressure = Some(pressure);
+
+        pressure
+    }
+}
+
+/// Pen pressure reported by the touches of one stroke.
+///
+/// Not every backend knows it, glutin reports every touch with a pressure of exactly 1. So
+/// pressure is taken as unknown until it changes within a stroke.
+#[derive(Default)]
+pub struct TouchPressure {
+    first_pressure: Option<f32>,
+    is_varying: bool,
+}
+
+impl TouchPressure {
+    /// Forget previous stroke.
+    pub fn reset(&mut self) {
+        *self = TouchPressure::default();
+    }
+
+    /// Pressure of touch clamped to the range `StrokeAnchor` accepts, `None` as long as every
+    /// touch of stroke reported the same.
+    pub fn next(&mut self, args: &TouchArgs) -> Option<f32> {
+        let pressure = (args.pressure() as f32).clamp(0.0, 1.0);
+
+        match self.first_pressure {
+            Some(first_pressure) => self.is_varying |= pressure != first_pressure,
+            None => self.first_pressure = Some(pressure),
+        }
+
+        if self.is_varying { Some(pressure) } else { None }
+    }
+}
@@ -0,0 +1,1847 @@
+use std;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+
+use glium;
+use glium::Blend;
+use glium::BlitTarget;
+use glium::Rect;
+use glium::Smooth;
+use glium::index::NoIndices;
+use glium::index::PrimitiveType;
+use glium::draw_parameters::DrawParameters;
+use glium::VertexBuffer;
+use glium::Surface;
+use glium::HeadlessRenderer;
+use glium::Program;
+use glium::backend::Facade;
+use glium::texture::texture2d::Texture2d;
+use glium::texture::texture2d_array::Texture2dArray;
+use glium::texture::{MipmapsOption, UncompressedFloatFormat};
+use glium::glutin::{Context, CreationError, NotCurrent};
+use graphics::math;
+use image;
+use interpolation;
+use rand::{Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+use vecmath;
+
+use states::Layer;
+use states::States;
+use states::StrokeAnchor;
+use states::StrokeBrush;
+use states::RenderMode;
+use states::OneStroke;
+use bristle;
+use ink;
+use spline;
+use texture_set;
+use texture_set::TextureSetError;
+
+fn load_string(path: &str) -> String {
+    let mut f = std::fs::File::open(path).unwrap();
+    let mut buf = String::new();
+
+    f.read_to_string(&mut buf).unwrap();
+
+    buf
+}
+
+fn load_watercolor_program<F: Facade>(window: &F, fs_path: &str) -> Program {
+    Program::from_source(window,
+                         &load_string("shaders/final.vs"),
+                         &load_string(fs_path),
+                         None)
+        .expect("failed to initialize watercolor shader")
+}
+
+fn empty_float_texture<F: Facade>(window: &F, w: u32, h: u32) -> Texture2d {
+    Texture2d::empty_with_format(window,
+                                 UncompressedFloatFormat::F32F32F32F32,
+                                 MipmapsOption::NoMipmap,
+                                 w,
+                                 h)
+        .unwrap()
+}
+
+/// Same for every paper, so a painting looks the same every time it is rendered at one size.
+const PAPER_SEED: [u8; 16] = [0xf5, 0x9c, 0x8e, 0x6c, 0x37, 0x1d, 0x4a, 0x0b,
+                              0x91, 0x6a, 0xf0, 0xd2, 0xb2, 0xc8, 0x57, 0x3e];
+
+/// Generate paper for the watercolor simulation.
+///
+/// r: water saturation, g: fluid-holding capacity, b: paper height.
+fn generate_paper_texture<F: Facade>(window: &F, w: u32, h: u32) -> Texture2d {
+    const CELL_SIZE: usize = 8;
+    const MIN_CAPACITY: f32 = 0.3;
+    const MAX_CAPACITY: f32 = 0.7;
+
+    let mut rng = XorShiftRng::from_seed(PAPER_SEED);
+
+    let (w, h) = (w as usize, h as usize);
+    let (grid_w, grid_h) = (w / CELL_SIZE + 2, h / CELL_SIZE + 2);
+    let grid: Vec<Vec<f32>> = (0..grid_h)
+        .map(|_| (0..grid_w).map(|_| rng.gen_range(0.0..1.0)).collect())
+        .collect();
+
+    let data: Vec<Vec<(f32, f32, f32, f32)>> = (0..h)
+        .map(|y| {
+            (0..w)
+                .map(|x| {
+                    let (gx, gy) = (x / CELL_SIZE, y / CELL_SIZE);
+                    let tx = (x % CELL_SIZE) as f32 / CELL_SIZE as f32;
+                    let ty = (y % CELL_SIZE) as f32 / CELL_SIZE as f32;
+
+                    let top = interpolation::lerp(&grid[gy][gx], &grid[gy][gx + 1], &tx);
+                    let bottom =
+                        interpolation::lerp(&grid[gy + 1][gx], &grid[gy + 1][gx + 1], &tx);
+                    let coarse = interpolation::lerp(&top, &bottom, &ty);
+
+                    let fine: f32 = rng.gen_range(0.0..1.0);
+                    let height = 0.8 * coarse + 0.2 * fine;
+                    let capacity = MIN_CAPACITY + height * (MAX_CAPACITY - MIN_CAPACITY);
+
+                    (0.0, capacity, height, 1.0)
+                })
+                .collect()
+        })
+        .collect();
+
+    Texture2d::with_format(window,
+                           data,
+                           UncompressedFloatFormat::F32F32F32F32,
+                           MipmapsOption::NoMipmap)
+        .unwrap()
+}
+
+#[derive(Copy, Clone)]
+struct Vertex {
+    pos: [f32; 2],
+}
+implement_vertex!(Vertex, pos);
+
+#[derive(Copy, Clone)]
+struct NormalVertex {
+    pos: [f32; 2],
+    tex_coords: [f32; 2],
+}
+implement_vertex!(NormalVertex, pos, tex_coords);
+
+/// How much wider a brush lying flat is than one held upright.
+const TILT_SPREAD: f32 = 1.0;
+
+fn with_opacity(color: [f32; 4], opacity: f32) -> [f32; 4] {
+    [color[0], color[1], color[2], color[3] * opacity]
+}
+
+/// Extra pixels around every segment quad, so anti-aliased edges are never clipped.
+const SEGMENT_MARGIN: f32 = 2.0;
+
+/// Part of the outline of a stroke in canvas units, the outline is the union of every part.
+pub enum OutlineShape {
+    Circle { center: [f32; 2], radius: f32 },
+    // Corners of the quad around one segment, in order around it.
+    Quad([[f32; 2]; 4]),
+}
+
+/// What cached textures of one layer were rendered from.
+///
+/// Layer textures are built one stroke on top of the previous, so they can only grow. Every
+/// watercolor wash starts from dry paper, so `passive_layer_tex` is all colored layers carry
+/// from one stroke to the next. To rebuild a layer from a changed stroke, textures are also
+/// snapshotted after the strokes left unchanged by the last change, e.g. finished strokes of a
+/// replay or strokes before the one being edited. As long as those still lead `Layer::strokes`,
+/// only strokes after them have to be rendered.
+struct CanvasCheckpoint {
+    parameters: SharedRenderParameters,
+    render_mode: RenderMode,
+    canvas_size: [u32; 2],
+    // Strokes in layer textures.
+    strokes: Vec<OneStroke>,
+    // `strokes` after spline smoothing, which is what every pass draws.
+    smoothed_strokes: Vec<OneStroke>,
+    // Number of leading `strokes` in `LayerCache::snapshot`, which is empty if 0.
+    snapshot_stroke_count: usize,
+}
+
+impl CanvasCheckpoint {
+    /// Number of leading strokes of `layer` same as rendered ones, `None` if nothing can be
+    /// reused.
+    fn unchanged_stroke_count(&self, states: &States, layer: &Layer) -> Option<usize> {
+        if self.parameters == SharedRenderParameters::from_states(states) &&
+           self.render_mode == layer.properties.render_mode &&
+           self.canvas_size == states.canvas_size {
+            Some(self.strokes
+                .iter()
+                .zip(&layer.strokes)
+                .take_while(|&(rendered, stroke)| rendered == stroke)
+                .count())
+        } else {
+            None
+        }
+    }
+}
+
+/// Settings of `states` which every stroke of a layer is rendered with.
+///
+/// Brush parameters snapshotted into `StrokeBrush` are left out, they only affect strokes drawn
+/// afterwards and those are compared along with the strokes themselves. So are settings only
+/// used for display, e.g. `stroke_line_radius`.
+#[derive(PartialEq)]
+struct SharedRenderParameters {
+    stroke_interpolation_accuracy: f32,
+    paper_absorbency: f32,
+
+    watercolor_iterations: i32,
+    watercolor_water_quantity: f32,
+    watercolor_pigment_quantity: f32,
+    watercolor_viscosity: f32,
+    watercolor_viscous_drag: f32,
+    watercolor_edge_darkening: f32,
+    watercolor_pigment_density: f32,
+    watercolor_staining_power: f32,
+    watercolor_granulation: f32,
+    watercolor_capillary_absorption: f32,
+}
+
+impl SharedRenderParameters {
+    fn from_states(states: &States) -> Self {
+        SharedRenderParameters {
+            stroke_interpolation_accuracy: states.stroke_interpolation_accuracy,
+            paper_absorbency: states.paper_absorbency,
+
+            watercolor_iterations: states.watercolor_iterations,
+            watercolor_water_quantity: states.watercolor_water_quantity,
+            watercolor_pigment_quantity: states.watercolor_pigment_quantity,
+            watercolor_viscosity: states.watercolor_viscosity,
+            watercolor_viscous_drag: states.watercolor_viscous_drag,
+            watercolor_edge_darkening: states.watercolor_edge_darkening,
+            watercolor_pigment_density: states.watercolor_pigment_density,
+            watercolor_staining_power: states.watercolor_staining_power,
+            watercolor_granulation: states.watercolor_granulation,
+            watercolor_capillary_absorption: states.watercolor_capillary_absorption,
+        }
+    }
+}
+
+/// Level textures of one brush texture set, lowest level first.
+struct BrushTextureSet {
+    levels: Texture2dArray,
+    level_count: u32,
+}
+
+impl BrushTextureSet {
+    fn new<F: Facade>(facade: &F, levels: Vec<image::RgbaImage>) -> Self {
+        let level_count = levels.len() as u32;
+        let levels = levels.into_iter()
+            .map(|level| {
+                let dimensions = level.dimensions();
+                glium::texture::RawImage2d::from_raw_rgba(level.into_raw(), dimensions)
+            })
+            .collect();
+
+        BrushTextureSet {
+            levels: Texture2dArray::new(facade, levels).unwrap(),
+            level_count,
+        }
+    }
+}
+
+/// Smallest rect holding both `a` and `b`.
+fn union_rect(a: Rect, b: Rect) -> Rect {
+    let left = a.left.min(b.left);
+    let bottom = a.bottom.min(b.bottom);
+    let right = (a.left + a.width).max(b.left + b.width);
+    let top = (a.bottom + a.height).max(b.bottom + b.height);
+
+    Rect {
+        left,
+        bottom,
+        width: right - left,
+        height: top - bottom,
+    }
+}
+
+/// Names of texture sets used by `strokes`, in order of first use.
+///
+/// Index of a name is the slot its strokes are tagged with in `stroke_ink_quantity_tex`.
+fn texture_set_slots(strokes: &[OneStroke]) -> Vec<&str> {
+    let mut slots: Vec<&str> = Vec::new();
+    for stroke in strokes {
+        if !slots.contains(&&stroke.brush.texture_set[..]) {
+            slots.push(&stroke.brush.texture_set);
+        }
+    }
+
+    slots
+}
+
+/// Canvas-sized textures one layer is rendered into.
+pub struct LayerTextures {
+    pub stroke_outline_tex: Texture2d,
+    pub stroke_ink_quantity_tex: Texture2d,
+    // Where every inked pixel lies along its stroke, written with `stroke_ink_quantity_tex`.
+    //
+    // r: arc length from stroke start, g: signed distance from stroke center line, both in
+    // pixels, b: stroke direction in radians.
+    pub stroke_frame_tex: Texture2d,
+    passive_layer_tex: Texture2d,
+    // Layer on its own, before opacity and blend mode are applied.
+    pub brush_tex: Texture2d,
+}
+
+impl LayerTextures {
+    fn new<F: Facade>(facade: &F, w: u32, h: u32) -> Self {
+        LayerTextures {
+            stroke_outline_tex: Texture2d::empty(facade, w, h).unwrap(),
+            stroke_ink_quantity_tex: Texture2d::empty(facade, w, h).unwrap(),
+            stroke_frame_tex: empty_float_texture(facade, w, h),
+            passive_layer_tex: empty_float_texture(facade, w, h),
+            brush_tex: Texture2d::empty(facade, w, h).unwrap(),
+        }
+    }
+}
+
+/// Textures of one layer kept between frames, so unchanged layers are only composited.
+struct LayerCache {
+    textures: LayerTextures,
+    // Copy of `textures` strokes are drawn on top of, see `CanvasCheckpoint`.
+    snapshot: LayerTextures,
+    checkpoint: Option<CanvasCheckpoint>,
+}
+
+/// Every GPU resource needed to turn `States` into brush textures.
+///
+/// Only a `Facade` is required, so the same pipeline runs inside the piston window and inside a
+/// headless context.
+pub struct Renderer<F: Facade> {
+    facade: F,
+
+    final_vertex_buffer: VertexBuffer<NormalVertex>,
+
+    // Every visible layer composited together.
+    pub brush_preview_tex: Texture2d,
+    composite_tmp_tex: Texture2d,
+
+    stroke_outline_tmp_tex: Texture2d,
+    stroke_ink_quantity_tmp_tex: Texture2d,
+
+    // Watercolor textures.
+    shallow_water_tex: Texture2d,
+    pidment_deposition_tex: Texture2d,
+    capillary_tex: Texture2d,
+    paper_tex: Texture2d,
+    watercolor_tmp_tex: Texture2d,
+
+    wipe_tmp_tex: Texture2d,
+    diffusion_tmp_tex: Texture2d,
+
+    /// Draw stroke segments as quads around their capsule instead of full-screen passes. Only
+    /// turned off to benchmark against the old path.
+    pub bounded_stroke_geometry: bool,
+
+    // Same order as `States::layers`.
+    layers: Vec<LayerCache>,
+
+    final_program: Program,
+    layer_composite_program: Program,
+    stroke_ink_quantity_program: Program,
+    circle_program: Program,
+    triangle_program: Program,
+    black_n_white_brush_program: Program,
+    watercolor_brush_program: Program,
+    wipe_program: Program,
+    diffusion_program: Program,
+
+    watercolor_seed_program: Program,
+    watercolor_velocity_program: Program,
+    watercolor_relax_program: Program,
+    watercolor_advect_program: Program,
+    watercolor_transfer_program: Program,
+    watercolor_capillary_program: Program,
+    watercolor_dry_program: Program,
+
+    // Loaded on first use by name, see `load_texture_sets`.
+    texture_sets: HashMap<String, BrushTextureSet>,
+}
+
+/// Build a renderer on top of an offscreen GL context, e.g. OSMesa or Mesa llvmpipe, so no
+/// display is needed.
+pub fn build_headless_renderer(w: u32, h: u32) -> Renderer<HeadlessRenderer> {
+    let context = build_headless_context(w, h).expect("failed to create headless context");
+    let facade = HeadlessRenderer::new(context).expect("failed to create headless context");
+
+    Renderer::new(facade, w, h)
+}
+
+#[cfg(any(target_os = "linux",
+          target_os = "dragonfly",
+          target_os = "freebsd",
+          target_os = "netbsd",
+          target_os = "openbsd"))]
+fn build_headless_context(w: u32, h: u32) -> Result<Context<NotCurrent>, CreationError> {
+    use glium::glutin::{Api, ContextBuilder, GlRequest};
+    use glium::glutin::dpi::PhysicalSize;
+    use glium::glutin::platform::unix::HeadlessContextExt;
+
+    ContextBuilder::new()
+        .with_gl(GlRequest::Specific(Api::OpenGl, (3, 2)))
+        .build_osmesa(PhysicalSize::new(w, h))
+}
+
+// OSMesa is only wired up by glutin on these platforms.
+#[cfg(not(any(target_os = "linux",
+              target_os = "dragonfly",
+              target_os = "freebsd",
+              target_os = "netbsd",
+              target_os = "openbsd")))]
+fn build_headless_context(_w: u32, _h: u32) -> Result<Context<NotCurrent>, CreationError> {
+    Err(CreationError::NotSupported("headless rendering needs OSMesa, which is only available \
+                                     on linux and bsd"
+        .to_string()))
+}
+
+impl<F: Facade> Renderer<F> {
+    pub fn new(facade: F, w: u32, h: u32) -> Self {
+        let final_vertex_buffer = glium::VertexBuffer::new(&facade,
+                                                           &[NormalVertex {
+                                                                 pos: [-1.0, 1.0],
+                                                                 tex_coords: [0.0, 1.0],
+                                                             },
+                                                             NormalVertex {
+                                                                 pos: [1.0, 1.0],
+                                                                 tex_coords: [1.0, 1.0],
+                                                             },
+                                                             NormalVertex {
+                                                                 pos: [-1.0, -1.0],
+                                                                 tex_coords: [0.0, 0.0],
+                                                             },
+                                                             NormalVertex {
+                                                                 pos: [1.0, -1.0],
+                                                                 tex_coords: [1.0, 0.0],
+                                                             }])
+            .unwrap();
+
+        let final_program = Program::from_source(&facade,
+                                                 &load_string("shaders/final.vs"),
+                                                 &load_string("shaders/final.fs"),
+                                                 None)
+            .expect("failed to initialize textured shader");
+        let layer_composite_program =
+            Program::from_source(&facade,
+                                 &load_string("shaders/final.vs"),
+                                 &load_string("shaders/layer_composite.fs"),
+                                 None)
+                .expect("failed to initialize textured shader");
+        let circle_program = Program::from_source(&facade,
+                                                  &load_string("shaders/final.vs"),
+                                                  &load_string("shaders/circle.fs"),
+                                                  None)
+            .expect("failed to initialize textured shader");
+        let triangle_program = Program::from_source(&facade,
+                                                    &load_string("shaders/triangle.vs"),
+                                                    &load_string("shaders/triangle.fs"),
+                                                    None)
+            .expect("failed to initialize textured shader");
+        let stroke_ink_quantity_program = Program::from_source(&facade,
+                                                               &load_string("shaders/final.vs"),
+                                                               &load_string("shaders/ink.fs"),
+                                                               None)
+            .expect("failed to initialize textured shader");
+        let black_n_white_brush_program =
+            Program::from_source(&facade,
+                                 &load_string("shaders/final.vs"),
+                                 &load_string("shaders/black_n_white_brush.fs"),
+                                 None)
+                .expect("failed to initialize textured shader");
+        let watercolor_brush_program = Program::from_source(&facade,
+                                                            &load_string("shaders/final.vs"),
+                                                            &load_string("shaders/watercolor_brush.\
+                                                                          fs"),
+                                                            None)
+            .expect("failed to initialize textured shader");
+        let wipe_program = Program::from_source(&facade,
+                                                &load_string("shaders/final.vs"),
+                                                &load_string("shaders/wipe.fs"),
+                                                None)
+            .expect("failed to initialize textured shader");
+        let diffusion_program = Program::from_source(&facade,
+                                                     &load_string("shaders/final.vs"),
+                                                     &load_string("shaders/diffusion.fs"),
+                                                     None)
+            .expect("failed to initialize textured shader");
+
+        let watercolor_seed_program = load_watercolor_program(&facade,
+                                                              "shaders/watercolor_seed.fs");
+        let watercolor_velocity_program =
+            load_watercolor_program(&facade, "shaders/watercolor_velocity.fs");
+        let watercolor_relax_program = load_watercolor_program(&facade,
+                                                               "shaders/watercolor_relax.fs");
+        let watercolor_advect_program = load_watercolor_program(&facade,
+                                                                "shaders/watercolor_advect.fs");
+        let watercolor_transfer_program =
+            load_watercolor_program(&facade, "shaders/watercolor_transfer.fs");
+        let watercolor_capillary_program =
+            load_watercolor_program(&facade, "shaders/watercolor_capillary.fs");
+        let watercolor_dry_program = load_watercolor_program(&facade,
+                                                             "shaders/watercolor_dry.fs");
+
+        Renderer {
+            final_vertex_buffer,
+
+            brush_preview_tex: Texture2d::empty(&facade, w, h).unwrap(),
+            composite_tmp_tex: Texture2d::empty(&facade, w, h).unwrap(),
+
+            stroke_outline_tmp_tex: Texture2d::empty(&facade, w, h).unwrap(),
+            stroke_ink_quantity_tmp_tex: Texture2d::empty(&facade, w, h).unwrap(),
+            black_n_white_brush_program,
+            watercolor_brush_program,
+
+            shallow_water_tex: empty_float_texture(&facade, w, h),
+            pidment_deposition_tex: empty_float_texture(&facade, w, h),
+            capillary_tex: empty_float_texture(&facade, w, h),
+            paper_tex: generate_paper_texture(&facade, w, h),
+            watercolor_tmp_tex: empty_float_texture(&facade, w, h),
+
+            wipe_tmp_tex: Texture2d::empty(&facade, w, h).unwrap(),
+            diffusion_tmp_tex: Texture2d::empty(&facade, w, h).unwrap(),
+
+            bounded_stroke_geometry: true,
+
+            layers: Vec::new(),
+
+            final_program,
+            layer_composite_program,
+            stroke_ink_quantity_program,
+            circle_program,
+            triangle_program,
+            wipe_program,
+            diffusion_program,
+
+            watercolor_seed_program,
+            watercolor_velocity_program,
+            watercolor_relax_program,
+            watercolor_advect_program,
+            watercolor_transfer_program,
+            watercolor_capillary_program,
+            watercolor_dry_program,
+
+            texture_sets: HashMap::new(),
+
+            facade,
+        }
+    }
+
+    /// Reallocate every canvas-sized texture, e.g. after window is resized.
+    pub fn resize(&mut self, w: u32, h: u32) {
+        let facade = &self.facade;
+
+        self.brush_preview_tex = Texture2d::empty(facade, w, h).unwrap();
+        self.composite_tmp_tex = Texture2d::empty(facade, w, h).unwrap();
+
+        self.stroke_outline_tmp_tex = Texture2d::empty(facade, w, h).unwrap();
+        self.stroke_ink_quantity_tmp_tex = Texture2d::empty(facade, w, h).unwrap();
+
+        self.shallow_water_tex = empty_float_texture(facade, w, h);
+        self.pidment_deposition_tex = empty_float_texture(facade, w, h);
+        self.capillary_tex = empty_float_texture(facade, w, h);
+        self.paper_tex = generate_paper_texture(facade, w, h);
+        self.watercolor_tmp_tex = empty_float_texture(facade, w, h);
+
+        self.wipe_tmp_tex = Texture2d::empty(facade, w, h).unwrap();
+        self.diffusion_tmp_tex = Texture2d::empty(facade, w, h).unwrap();
+
+        // Layer textures are allocated again at the new size on next render.
+        self.layers.clear();
+    }
+
+    /// Force next `construct_brush_tex` to rebuild every stroke of every layer.
+    pub fn invalidate_checkpoint(&mut self) {
+        for cache in &mut self.layers {
+            cache.checkpoint = None;
+        }
+    }
+
+    /// Load every texture set used by strokes of `states` which is not loaded yet.
+    ///
+    /// A set failing to load is replaced by the built-in one, so it is only reported once.
+    pub fn load_texture_sets(&mut self, states: &States) -> Vec<(String, TextureSetError)> {
+        self.load_texture_sets_of(&states.layers)
+    }
+
+    fn load_texture_sets_of(&mut self, layers: &[Layer]) -> Vec<(String, TextureSetError)> {
+        let mut errors = Vec::new();
+
+        for layer in layers {
+            for stroke in &layer.strokes {
+                let name = &stroke.brush.texture_set;
+                if self.texture_sets.contains_key(name) {
+                    continue;
+                }
+
+                let levels = match texture_set::load_levels(name) {
+                    Ok(levels) => levels,
+                    Err(err) => {
+                        errors.push((name.clone(), err));
+                        texture_set::builtin_levels()
+                    }
+                };
+
+                let set = BrushTextureSet::new(&self.facade, levels);
+                self.texture_sets.insert(name.clone(), set);
+            }
+        }
+
+        errors
+    }
+
+    /// Drop every loaded texture set, e.g. after their files changed on disk.
+    pub fn reload_texture_sets(&mut self) {
+        self.texture_sets.clear();
+        self.invalidate_checkpoint();
+    }
+
+    /// Textures of layer at `index`, `None` if it has not been rendered yet.
+    pub fn layer_textures(&self, index: usize) -> Option<&LayerTextures> {
+        self.layers.get(index).map(|cache| &cache.textures)
+    }
+
+    /// Size of canvas textures in pixels.
+    fn canvas_size(&self) -> [f32; 2] {
+        let (w, h) = self.brush_preview_tex.dimensions();
+
+        [w as f32, h as f32]
+    }
+
+    /// Number of texture pixels per canvas unit, strokes are recorded in canvas units.
+    fn pixel_scale(&self, states: &States) -> f32 {
+        self.canvas_size()[0] / states.canvas_size[0] as f32
+    }
+
+    fn to_pixel_pos(&self, states: &States, pos: &[f32; 2]) -> [f32; 2] {
+        let scale = self.pixel_scale(states);
+
+        [pos[0] * scale, pos[1] * scale]
+    }
+
+    /// Block until every queued GL command has been executed, used for timing.
+    pub fn finish(&self) {
+        self.facade.get_context().finish();
+    }
+
+    /// Draw `program` over the capsule swept by a circle moving from `start_pos` to `end_pos`.
+    ///
+    /// Positions and radii are in `gl_FragCoord` pixels, the same space the stroke shaders
+    /// test against, so pixels outside the quad are exactly the ones those shaders leave alone.
+    #[allow(clippy::too_many_arguments)]
+    fn draw_segment_pass<S, U>(&self,
+                               target: &mut S,
+                               program: &Program,
+                               uniforms: &U,
+                               start_pos: [f32; 2],
+                               start_radius: f32,
+                               end_pos: [f32; 2],
+                               end_radius: f32)
+        where S: Surface,
+              U: glium::uniforms::Uniforms
+    {
+        if !self.bounded_stroke_geometry {
+            target.draw(&self.final_vertex_buffer,
+                      NoIndices(PrimitiveType::TriangleStrip),
+                      program,
+                      uniforms,
+                      &DrawParameters::default())
+                .expect("failed to draw triangle list");
+            return;
+        }
+
+        let offset = vecmath::vec2_sub(end_pos, start_pos);
+        let len = vecmath::vec2_len(offset);
+        // Direction is arbitrary for a dot, quad is a square around it anyway.
+        let dir = if len > 1e-4 { vecmath::vec2_scale(offset, 1.0 / len) } else { [1.0, 0.0] };
+        let normal = [-dir[1], dir[0]];
+
+        let half_width = start_radius.max(end_radius) + SEGMENT_MARGIN;
+        let back = vecmath::vec2_sub(start_pos,
+                                     vecmath::vec2_scale(dir, start_radius + SEGMENT_MARGIN));
+        let front = vecmath::vec2_add(end_pos,
+                                      vecmath::vec2_scale(dir, end_radius + SEGMENT_MARGIN));
+        let side = vecmath::vec2_scale(normal, half_width);
+
+        let size = self.canvas_size();
+        let to_vertex = |p: [f32; 2]| {
+            NormalVertex {
+                pos: [2.0 * p[0] / size[0] - 1.0, 2.0 * p[1] / size[1] - 1.0],
+                tex_coords: [p[0] / size[0], p[1] / size[1]],
+            }
+        };
+
+        let quad = [to_vertex(vecmath::vec2_add(back, side)),
+                    to_vertex(vecmath::vec2_add(front, side)),
+                    to_vertex(vecmath::vec2_sub(back, side)),
+                    to_vertex(vecmath::vec2_sub(front, side))];
+
+        let vertex_buffer = glium::VertexBuffer::new(&self.facade, &quad).unwrap();
+        target.draw(&vertex_buffer,
+                  NoIndices(PrimitiveType::TriangleStrip),
+                  program,
+                  uniforms,
+                  &DrawParameters::default())
+            .expect("failed to draw triangle list");
+    }
+
+    /// Pixels a segment pass may read or write, grown by `margin` and clamped to canvas.
+    fn segment_rect(&self,
+                    start_pos: [f32; 2],
+                    start_radius: f32,
+                    end_pos: [f32; 2],
+                    end_radius: f32,
+                    margin: f32)
+                    -> Rect {
+        let (w, h) = self.brush_preview_tex.dimensions();
+
+        if !self.bounded_stroke_geometry {
+            return Rect { left: 0, bottom: 0, width: w, height: h };
+        }
+
+        let grow = SEGMENT_MARGIN + margin;
+        let min_x = (start_pos[0] - start_radius).min(end_pos[0] - end_radius) - grow;
+        let min_y = (start_pos[1] - start_radius).min(end_pos[1] - end_radius) - grow;
+        let max_x = (start_pos[0] + start_radius).max(end_pos[0] + end_radius) + grow;
+        let max_y = (start_pos[1] + start_radius).max(end_pos[1] + end_radius) + grow;
+
+        let clamp = |v: f32, max: u32| v.max(0.0).min(max as f32) as u32;
+        let (left, right) = (clamp(min_x.floor(), w), clamp(max_x.ceil(), w));
+        let (bottom, top) = (clamp(min_y.floor(), h), clamp(max_y.ceil(), h));
+
+        Rect {
+            left,
+            bottom,
+            width: right - left,
+            height: top - bottom,
+        }
+    }
+
+    /// Copy whole of `from` into `to` of the same size.
+    fn copy_texture(&self, from: &Texture2d, to: &Texture2d) {
+        let (w, h) = from.dimensions();
+        let rect = Rect {
+            left: 0,
+            bottom: 0,
+            width: w,
+            height: h,
+        };
+
+        self.copy_rect(from, to, &rect);
+    }
+
+    /// Copy `rect` of `from` into the same place of `to`.
+    fn copy_rect(&self, from: &Texture2d, to: &Texture2d, rect: &Rect) {
+        if rect.width == 0 || rect.height == 0 {
+            return;
+        }
+
+        from.as_surface().blit_color(rect,
+                                     &to.as_surface(),
+                                     &BlitTarget {
+                                         left: rect.left,
+                                         bottom: rect.bottom,
+                                         width: rect.width as i32,
+                                         height: rect.height as i32,
+                                     },
+                                     glium::uniforms::MagnifySamplerFilter::Nearest);
+    }
+
+    pub fn draw_texture_on<S: Surface>(&self, tex: &Texture2d, target: &mut S) {
+        let draw_state =
+            glium::DrawParameters { blend: Blend::alpha_blending(), ..Default::default() };
+        target.draw(&self.final_vertex_buffer,
+                  NoIndices(PrimitiveType::TriangleStrip),
+                  &self.final_program,
+                  &uniform!{
+                        tex: tex,
+                    },
+                  &draw_state)
+            .expect("failed to draw triangle list");
+    }
+
+    fn render_circle(&self,
+                     target_tex: &Texture2d,
+                     center: [f32; 2],
+                     radius: f32,
+                     brush_color: &[f32; 4]) {
+        // Circle shader flips y itself, quad is placed in `gl_FragCoord` space.
+        let frag_center = [center[0], self.canvas_size()[1] - center[1]];
+
+        self.draw_segment_pass(&mut target_tex.as_surface(),
+                               &self.circle_program,
+                               &uniform!{
+                                   center: center,
+                                   radius: radius,
+                                   brush_color: *brush_color,
+                                   canvas_size: self.canvas_size(),
+                               },
+                               frag_center,
+                               radius,
+                               frag_center,
+                               radius);
+    }
+
+    fn render_triangle_lists_on(&self,
+                                triangles: &[Vertex],
+                                target_tex: &Texture2d,
+                                brush_color: &[f32; 4]) {
+        let (width, height) = target_tex.dimensions();
+
+        let triangles: Vec<_> = triangles.iter()
+            .map(|v| {
+                Vertex {
+                    pos: [2.0 * v.pos[0] / width as f32 - 1.0,
+                          2.0 * (height as f32 - v.pos[1]) / height as f32 - 1.0],
+                }
+            })
+            .collect();
+
+        let vertex_buffer = glium::VertexBuffer::new(&self.facade, &triangles).unwrap();
+        target_tex.as_surface()
+            .draw(&vertex_buffer,
+                  NoIndices(PrimitiveType::TriangleStrip),
+                  &self.triangle_program,
+                  &uniform!{
+                      brush_color: *brush_color,
+                  },
+                  &DrawParameters::default())
+            .expect("failed to draw triangle list");
+    }
+
+    fn caculate_anchor_polygon(&self,
+                               brush: &StrokeBrush,
+                               prev_stroke_anchor: &StrokeAnchor,
+                               stroke_anchor: &StrokeAnchor)
+                               -> [Vertex; 4] {
+        let prev_anchor_pos = &prev_stroke_anchor.pos;
+        let anchor_pos = &stroke_anchor.pos;
+
+        let start_pos = math::cast([prev_anchor_pos[0], prev_anchor_pos[1]]);
+        let end_pos = math::cast([anchor_pos[0], anchor_pos[1]]);
+
+        let norm_v = vecmath::vec2_normalized([(anchor_pos[0] - prev_anchor_pos[0]),
+                                               (anchor_pos[1] - prev_anchor_pos[1])]);
+
+        let start_brush_width = self.caculate_side_radius(brush, prev_stroke_anchor, norm_v);
+        let end_brush_width = self.caculate_side_radius(brush, stroke_anchor, norm_v);
+
+        let start_v = math::mul_scalar(norm_v, start_brush_width);
+        let end_v = math::mul_scalar(norm_v, end_brush_width);
+
+        let rotate_right = math::rotate_radians(std::f32::consts::PI / 2.);
+        let rotate_left = math::rotate_radians(std::f32::consts::PI / -2.);
+
+        let start_a_mat = math::translate(math::transform_vec(rotate_left, start_v));
+        let start_b_mat = math::translate(math::transform_vec(rotate_right, start_v));
+        let end_a_mat = math::translate(math::transform_vec(rotate_left, end_v));
+        let end_b_mat = math::translate(math::transform_vec(rotate_right, end_v));
+
+        [Vertex { pos: math::transform_pos(start_a_mat, start_pos) },
+         Vertex { pos: math::transform_pos(end_a_mat, end_pos) },
+         Vertex { pos: math::transform_pos(start_b_mat, start_pos) },
+         Vertex { pos: math::transform_pos(end_b_mat, end_pos) }]
+    }
+
+    /// Render outline of one stroke onto `target_tex`, which is cleared first.
+    fn render_stroke_outline_on(&self,
+                                states: &States,
+                                target_tex: &Texture2d,
+                                stroke: &OneStroke,
+                                stroke_color: [f32; 4]) {
+        target_tex.as_surface().clear_color(0.0, 0.0, 0.0, 0.0);
+
+        if stroke.anchors.is_empty() {
+            return;
+        }
+
+        let pixel_scale = self.pixel_scale(states);
+        let brush = &stroke.brush;
+
+        let render_circle_part = |stroke_anchor: &StrokeAnchor| {
+            let radius = self.caculate_brush_radius(brush, stroke_anchor);
+            let opacity = self.caculate_opacity(brush, stroke_anchor.pressure);
+            self.render_circle(target_tex,
+                               self.to_pixel_pos(states, &stroke_anchor.pos),
+                               radius * pixel_scale,
+                               &with_opacity(stroke_color, opacity));
+        };
+
+        let mut stroke_anchors_iter = stroke.anchors.iter();
+        let mut prev_stroke_anchor = stroke_anchors_iter.next().unwrap();
+
+        // Draw circle of start anchor.
+        render_circle_part(prev_stroke_anchor);
+
+        // Draw outline form by all anchor.
+        for stroke_anchor in stroke_anchors_iter {
+            let mut polygon_points =
+                self.caculate_anchor_polygon(brush, prev_stroke_anchor, stroke_anchor);
+            for point in &mut polygon_points {
+                point.pos = self.to_pixel_pos(states, &point.pos);
+            }
+
+            let opacity = self.caculate_opacity(brush,
+                                                (prev_stroke_anchor.pressure +
+                                                 stroke_anchor.pressure) / 2.0);
+
+            render_circle_part(stroke_anchor);
+            self.render_triangle_lists_on(&polygon_points,
+                                          target_tex,
+                                          &with_opacity(stroke_color, opacity));
+
+            prev_stroke_anchor = stroke_anchor;
+        }
+    }
+
+    /// Circles and quads `render_stroke_outline_on` draws for `stroke`, in canvas units.
+    ///
+    /// `stroke` is smoothed first, like in every render pass.
+    pub fn stroke_outline_shapes(&self, states: &States, stroke: &OneStroke) -> Vec<OutlineShape> {
+        let spacing = spline::resample_spacing(states.stroke_interpolation_accuracy);
+        let stroke = spline::smooth_stroke(stroke, spacing);
+        let brush = &stroke.brush;
+
+        let mut shapes: Vec<OutlineShape> = stroke.anchors
+            .iter()
+            .map(|anchor| {
+                OutlineShape::Circle {
+                    center: anchor.pos,
+                    radius: self.caculate_brush_radius(brush, anchor),
+                }
+            })
+            .collect();
+
+        for segment in stroke.anchors.windows(2) {
+            // Segment has no direction, its circles already cover it.
+            if segment[0].pos == segment[1].pos {
+                continue;
+            }
+
+            // Polygon is in triangle strip order.
+            let polygon = self.caculate_anchor_polygon(brush, &segment[0], &segment[1]);
+            shapes.push(OutlineShape::Quad([polygon[0].pos,
+                                            polygon[1].pos,
+                                            polygon[3].pos,
+                                            polygon[2].pos]));
+        }
+
+        shapes
+    }
+
+    /// Render `strokes` on top of what is already in `stroke_outline_tex` of `target`.
+    ///
+    /// Only black & white layers have an ink outline, colored ones spread pigment with the
+    /// watercolor simulation instead.
+    fn render_stroke_ink_outline_tex(&self,
+                                     states: &States,
+                                     target: &LayerTextures,
+                                     strokes: &[OneStroke]) {
+        for stroke in strokes {
+            if stroke.anchors.is_empty() {
+                continue;
+            }
+
+            // Texture used for storing new stroke.
+            self.render_stroke_outline_on(states,
+                                          &self.stroke_outline_tmp_tex,
+                                          stroke,
+                                          [1.0, 0.0, 0.0, 1.0]);
+
+            // Wipe previous pigment on canvas according to current new stroke.
+            self.wipe_pigment_by_stroke(states, &target.stroke_outline_tex, stroke);
+
+            // Fake diffusion on canvas according to current new stroke.
+            self.render_fake_stroke_diffusion(states, &target.stroke_outline_tex, stroke);
+
+            // Blit new stroke onto previous canvas.
+            self.draw_texture_on(&self.stroke_outline_tmp_tex,
+                                 &mut target.stroke_outline_tex.as_surface());
+        }
+    }
+
+    fn render_fake_stroke_diffusion(&self,
+                                    states: &States,
+                                    canvas: &Texture2d,
+                                    stroke: &OneStroke) {
+        if stroke.anchors.is_empty() {
+            return;
+        }
+
+        let pixel_scale = self.pixel_scale(states);
+        let brush = &stroke.brush;
+
+        let haha = |brush_color: [f32; 4], radius_offset: f32| {
+            let mut stroke_anchors_iter = stroke.anchors.iter();
+            let mut prev_stroke_anchor = stroke_anchors_iter.next().unwrap();
+
+            for stroke_anchor in stroke_anchors_iter {
+                let stroke_start_pos = self.to_pixel_pos(states, &prev_stroke_anchor.pos);
+                let stroke_end_pos = self.to_pixel_pos(states, &stroke_anchor.pos);
+
+                let start_radius = (self.caculate_brush_radius(brush, prev_stroke_anchor) +
+                                    radius_offset) * pixel_scale;
+                let end_radius = (self.caculate_brush_radius(brush, stroke_anchor) +
+                                  radius_offset) * pixel_scale;
+
+                // Diffusion only reads the pixel it writes.
+                let rect = self.segment_rect(stroke_start_pos,
+                                             start_radius,
+                                             stroke_end_pos,
+                                             end_radius,
+                                             0.0);
+                self.copy_rect(canvas, &self.diffusion_tmp_tex, &rect);
+
+                let opacity = self.caculate_opacity(brush,
+                                                    (prev_stroke_anchor.pressure +
+                                                     stroke_anchor.pressure) /
+                                                    2.0);
+
+                self.draw_segment_pass(&mut canvas.as_surface(),
+                                       &self.diffusion_program,
+                                       &uniform!{
+                                           current_tex: &self.diffusion_tmp_tex,
+
+                                           brush_color: with_opacity(brush_color, opacity),
+
+                                           stroke_start_pos: stroke_start_pos,
+                                           stroke_end_pos: stroke_end_pos,
+
+                                           start_radius: start_radius,
+                                           end_radius: end_radius,
+                                       },
+                                       stroke_start_pos,
+                                       start_radius,
+                                       stroke_end_pos,
+                                       end_radius);
+
+                prev_stroke_anchor = stroke_anchor;
+            }
+        };
+
+        let inner_brush_color = {
+            let mut c = stroke.color;
+
+            c[0] *= 0.7;
+            c[1] *= 0.7;
+            c[2] *= 0.7;
+
+            c
+        };
+        let outter_brush_color = {
+            let mut c = stroke.color;
+
+            c[0] *= 1.3;
+            c[1] *= 1.3;
+            c[2] *= 1.3;
+
+            c
+        };
+
+        haha(outter_brush_color, 10.0);
+        haha(inner_brush_color, 2.5);
+    }
+
+    fn wipe_pigment_by_stroke(&self,
+                              states: &States,
+                              canvas: &Texture2d,
+                              stroke: &OneStroke) {
+        if stroke.anchors.is_empty() {
+            return;
+        }
+
+        let pixel_scale = self.pixel_scale(states);
+        let wipe_distance = 120.0 * pixel_scale;
+        let brush = &stroke.brush;
+
+        let mut stroke_anchors_iter = stroke.anchors.iter();
+        let mut prev_stroke_anchor = stroke_anchors_iter.next().unwrap();
+
+        for stroke_anchor in stroke_anchors_iter {
+            let stroke_start_pos = self.to_pixel_pos(states, &prev_stroke_anchor.pos);
+            let stroke_end_pos = self.to_pixel_pos(states, &stroke_anchor.pos);
+
+            let start_radius = self.caculate_brush_radius(brush, prev_stroke_anchor) *
+                               pixel_scale;
+            let end_radius = self.caculate_brush_radius(brush, stroke_anchor) * pixel_scale;
+
+            // Copy canvas to wipe_tmp_tex, pigment is dragged from at most `wipe_distance` away.
+            let rect = self.segment_rect(stroke_start_pos,
+                                         start_radius,
+                                         stroke_end_pos,
+                                         end_radius,
+                                         wipe_distance);
+            self.copy_rect(canvas, &self.wipe_tmp_tex, &rect);
+
+            // Do actual wipe opeartion.
+            self.draw_segment_pass(&mut canvas.as_surface(),
+                                   &self.wipe_program,
+                                   &uniform!{
+                                       current_tex: &self.wipe_tmp_tex,
+
+                                       stroke_start_pos: stroke_start_pos,
+                                       stroke_end_pos: stroke_end_pos,
+
+                                       start_radius: start_radius,
+                                       end_radius: end_radius,
+
+                                       canvas_size: self.canvas_size(),
+                                       wipe_distance: wipe_distance,
+                                   },
+                                   stroke_start_pos,
+                                   start_radius,
+                                   stroke_end_pos,
+                                   end_radius);
+
+            prev_stroke_anchor = stroke_anchor;
+        }
+    }
+
+    /// Render ink quantity of `stroke` on top of what is already in `stroke_ink_quantity_tex`
+    /// of `target`, only where no earlier stroke left ink. Returns pixels it drew over.
+    ///
+    /// `stroke_ink_quantity_tmp_tex` has to hold a copy of `stroke_ink_quantity_tex`.
+    fn render_stroke_ink_quantity_tex(&self,
+                                      states: &States,
+                                      target: &LayerTextures,
+                                      stroke: &OneStroke,
+                                      texture_set_slots: &[&str])
+                                      -> Option<Rect> {
+        use glium::framebuffer::MultiOutputFrameBuffer;
+
+        let mut drawn: Option<Rect> = None;
+
+        let mut output = MultiOutputFrameBuffer::new(&self.facade,
+                                                     [("o_color", &target.stroke_ink_quantity_tex),
+                                                      ("o_stroke_frame",
+                                                       &target.stroke_frame_tex)]
+                                                         .iter()
+                                                         .cloned())
+            .unwrap();
+
+        let mut draw = |start_pos: [f32; 2],
+                        start_radius: f32,
+                        start_ink_quantity,
+                        start_arc_length: f32,
+                        end_pos: [f32; 2],
+                        end_radius: f32,
+                        end_ink_quantity,
+                        texture_set_slot: f32| {
+
+            self.draw_segment_pass(&mut output,
+                                   &self.stroke_ink_quantity_program,
+                                   &uniform!{
+                                       stroke_outline_tex: &target.stroke_outline_tex,
+                                       stroke_ink_quantity_tmp_tex:
+                                           &self.stroke_ink_quantity_tmp_tex,
+
+                                       start_pos: start_pos,
+                                       end_pos: end_pos,
+
+                                       start_radius: start_radius,
+                                       end_radius: end_radius,
+
+                                       start_ink_quantity: start_ink_quantity,
+                                       end_ink_quantity: end_ink_quantity,
+
+                                       start_arc_length: start_arc_length,
+
+                                       texture_set_slot: texture_set_slot,
+                                   },
+                                   start_pos,
+                                   start_radius,
+                                   end_pos,
+                                   end_radius);
+
+            // Copy to tmp texture for future reference
+            let rect = self.segment_rect(start_pos, start_radius, end_pos, end_radius, 0.0);
+            self.copy_rect(&target.stroke_ink_quantity_tex,
+                           &self.stroke_ink_quantity_tmp_tex,
+                           &rect);
+
+            drawn = Some(match drawn {
+                Some(drawn) => union_rect(drawn, rect),
+                None => rect,
+            });
+        };
+
+        let pixel_scale = self.pixel_scale(states);
+
+        let brush = &stroke.brush;
+        let mut stroke_iter = stroke.anchors.iter();
+        let mut current_ink_quantity = stroke.ink_load.unwrap_or(brush.initial_ink_quantity);
+
+        let texture_set_slot = texture_set_slots.iter()
+            .position(|&name| name == brush.texture_set)
+            .unwrap() as f32 / 255.0;
+
+        // In pixels, so brush texture keeps its size along strokes of any canvas size.
+        let mut arc_length = 0.0;
+
+        if brush.bristle_count > 0 {
+            let arc_lengths = self.caculate_arc_lengths(states, stroke);
+            let radius = |anchor: &StrokeAnchor| self.caculate_brush_radius(brush, anchor);
+            let ink_cost = |start: &StrokeAnchor,
+                            start_radius: f32,
+                            end: &StrokeAnchor,
+                            end_radius: f32| {
+                self.caculate_ink_cost(states, brush, start, start_radius, end, end_radius)
+            };
+            let ink_load = ink::load_fraction(current_ink_quantity, brush.initial_ink_quantity)
+                .max(ink::DRY_BRUSH_INK);
+            let segments = bristle::bristle_segments(stroke, ink_load, radius, ink_cost);
+
+            for segment in segments {
+                let deposit = ink::segment_deposit(&stroke.anchors[segment.anchor],
+                                                   &stroke.anchors[segment.anchor + 1],
+                                                   states.paper_absorbency);
+
+                draw(self.to_pixel_pos(states, &segment.start_pos),
+                     segment.start_radius * pixel_scale,
+                     segment.start_ink_quantity * deposit,
+                     arc_lengths[segment.anchor],
+                     self.to_pixel_pos(states, &segment.end_pos),
+                     segment.end_radius * pixel_scale,
+                     segment.end_ink_quantity * deposit,
+                     texture_set_slot);
+            }
+
+            return drawn;
+        }
+
+        if let Some(mut prev_stroke_anchor) = stroke_iter.next() {
+            for stroke_anchor in stroke_iter {
+                let start_pos = &prev_stroke_anchor.pos;
+                let end_pos = &stroke_anchor.pos;
+
+                let start_radius = self.caculate_brush_radius(brush, prev_stroke_anchor);
+                let end_radius = self.caculate_brush_radius(brush, stroke_anchor);
+
+                let ink_cost = self.caculate_ink_cost(states,
+                                                      brush,
+                                                      prev_stroke_anchor,
+                                                      start_radius,
+                                                      stroke_anchor,
+                                                      end_radius);
+
+                let deposit = ink::segment_deposit(prev_stroke_anchor,
+                                                   stroke_anchor,
+                                                   states.paper_absorbency);
+
+                // Empty brush still leaves dry brush marks.
+                let start_ink_quantity = ink::load_fraction(current_ink_quantity,
+                                                            brush.initial_ink_quantity)
+                    .max(ink::DRY_BRUSH_INK) * deposit;
+                let end_ink_quantity = ink::load_fraction(current_ink_quantity - ink_cost,
+                                                          brush.initial_ink_quantity)
+                    .max(ink::DRY_BRUSH_INK) * deposit;
+
+                current_ink_quantity -= ink_cost;
+
+                let start_pixel_pos = self.to_pixel_pos(states, start_pos);
+                let end_pixel_pos = self.to_pixel_pos(states, end_pos);
+
+                draw(start_pixel_pos,
+                     start_radius * pixel_scale,
+                     start_ink_quantity,
+                     arc_length,
+                     end_pixel_pos,
+                     end_radius * pixel_scale,
+                     end_ink_quantity,
+                     texture_set_slot);
+
+                arc_length += vecmath::vec2_len(vecmath::vec2_sub(end_pixel_pos,
+                                                                  start_pixel_pos));
+
+                prev_stroke_anchor = stroke_anchor;
+            }
+        }
+
+        drawn
+    }
+
+    /// Texture ink quantity inside `rect` with texture sets of `texture_set_slots`, one pass
+    /// per set.
+    ///
+    /// Every pass only draws pixels whose slot in `stroke_ink_quantity_tex` is its own set.
+    fn render_brush_tex(&self,
+                        states: &States,
+                        target: &LayerTextures,
+                        texture_set_slots: &[&str],
+                        rect: &Rect) {
+        use glium::uniforms::Sampler;
+
+        for (slot, &name) in texture_set_slots.iter().enumerate() {
+            // Missing only if `load_texture_sets` was never called for these strokes.
+            let set = match self.texture_sets.get(name) {
+                Some(set) => set,
+                None => continue,
+            };
+
+            let brush_texs = Sampler::new(&set.levels)
+                .wrap_function(glium::uniforms::SamplerWrapFunction::Repeat)
+                .minify_filter(glium::uniforms::MinifySamplerFilter::Nearest)
+                .magnify_filter(glium::uniforms::MagnifySamplerFilter::Nearest);
+
+            target.brush_tex
+                .as_surface()
+                .draw(&self.final_vertex_buffer,
+                      NoIndices(PrimitiveType::TriangleStrip),
+                      &self.black_n_white_brush_program,
+                      &uniform!{
+                          stroke_ink_quantity_tex: &target.stroke_ink_quantity_tex,
+                          stroke_frame_tex: &target.stroke_frame_tex,
+
+                          brush_texs: brush_texs,
+                          level_count: set.level_count as i32,
+                          texture_set_slot: slot as f32 / 255.0,
+
+                          brush_tex_size: 32.0 * self.pixel_scale(states),
+                      },
+                      &glium::DrawParameters {
+                          smooth: Some(Smooth::Fastest),
+                          scissor: Some(*rect),
+                          ..Default::default()
+                      })
+                .expect("failed to draw triangle list");
+        }
+    }
+
+    /// Copy `from` into `watercolor_tmp_tex` so that the next simulation pass can read the old
+    /// value while writing the new one back into `from`.
+    fn backup_to_watercolor_tmp_tex(&self, from: &Texture2d) {
+        from.as_surface().fill(&self.watercolor_tmp_tex.as_surface(),
+                               glium::uniforms::MagnifySamplerFilter::Nearest);
+    }
+
+    fn draw_watercolor_pass<U: glium::uniforms::Uniforms>(&self,
+                                                          target_tex: &Texture2d,
+                                                          program: &Program,
+                                                          uniforms: &U) {
+        target_tex.as_surface()
+            .draw(&self.final_vertex_buffer,
+                  NoIndices(PrimitiveType::TriangleStrip),
+                  program,
+                  uniforms,
+                  &DrawParameters::default())
+            .expect("failed to draw watercolor pass");
+    }
+
+    /// Turn area covered by `stroke_outline_tmp_tex` into wet area filled with water and
+    /// suspended pigment.
+    fn seed_watercolor_wet_area(&self, states: &States) {
+        let water = states.watercolor_water_quantity;
+        let pigment = states.watercolor_pigment_quantity;
+
+        self.backup_to_watercolor_tmp_tex(&self.shallow_water_tex);
+        self.draw_watercolor_pass(&self.shallow_water_tex,
+                                  &self.watercolor_seed_program,
+                                  &uniform!{
+                                      current_tex: &self.watercolor_tmp_tex,
+                                      stroke_mask_tex: &self.stroke_outline_tmp_tex,
+
+                                      seed_add: [0.0, 0.0, water, 0.0f32],
+                                      seed_min: [0.0, 0.0, 0.0, 1.0f32],
+                                  });
+
+        self.backup_to_watercolor_tmp_tex(&self.pidment_deposition_tex);
+        self.draw_watercolor_pass(&self.pidment_deposition_tex,
+                                  &self.watercolor_seed_program,
+                                  &uniform!{
+                                      current_tex: &self.watercolor_tmp_tex,
+                                      stroke_mask_tex: &self.stroke_outline_tmp_tex,
+
+                                      seed_add: [pigment, 0.0, 0.0, 0.0f32],
+                                      seed_min: [0.0, 0.0, 0.0, 0.0f32],
+                                  });
+    }
+
+    /// One step of the fluid simulation: move water, move pigment, transfer pigment between
+    /// water and paper, then let the paper soak water up.
+    fn step_watercolor_simulation(&self, states: &States) {
+        const DT: f32 = 0.5;
+
+        let (w, h) = self.shallow_water_tex.dimensions();
+        let texel_size = [1.0 / w as f32, 1.0 / h as f32];
+
+        self.backup_to_watercolor_tmp_tex(&self.shallow_water_tex);
+        self.draw_watercolor_pass(&self.shallow_water_tex,
+                                  &self.watercolor_velocity_program,
+                                  &uniform!{
+                                      shallow_water_tex: &self.watercolor_tmp_tex,
+                                      capillary_tex: &self.capillary_tex,
+
+                                      texel_size: texel_size,
+
+                                      dt: DT,
+                                      viscosity: states.watercolor_viscosity,
+                                      viscous_drag: states.watercolor_viscous_drag,
+                                      paper_slope: 1.0f32,
+                                  });
+
+        self.backup_to_watercolor_tmp_tex(&self.shallow_water_tex);
+        self.draw_watercolor_pass(&self.shallow_water_tex,
+                                  &self.watercolor_relax_program,
+                                  &uniform!{
+                                      shallow_water_tex: &self.watercolor_tmp_tex,
+                                      capillary_tex: &self.capillary_tex,
+
+                                      texel_size: texel_size,
+
+                                      relaxation: 0.1f32,
+                                      edge_darkening: states.watercolor_edge_darkening,
+                                      saturation_threshold: 0.5f32,
+                                  });
+
+        self.backup_to_watercolor_tmp_tex(&self.pidment_deposition_tex);
+        self.draw_watercolor_pass(&self.pidment_deposition_tex,
+                                  &self.watercolor_advect_program,
+                                  &uniform!{
+                                      shallow_water_tex: &self.shallow_water_tex,
+                                      pigment_tex: &self.watercolor_tmp_tex,
+
+                                      texel_size: texel_size,
+
+                                      dt: DT,
+                                  });
+
+        self.backup_to_watercolor_tmp_tex(&self.pidment_deposition_tex);
+        self.draw_watercolor_pass(&self.pidment_deposition_tex,
+                                  &self.watercolor_transfer_program,
+                                  &uniform!{
+                                      shallow_water_tex: &self.shallow_water_tex,
+                                      pigment_tex: &self.watercolor_tmp_tex,
+                                      capillary_tex: &self.capillary_tex,
+
+                                      density: states.watercolor_pigment_density,
+                                      staining_power: states.watercolor_staining_power,
+                                      granulation: states.watercolor_granulation,
+                                  });
+
+        self.backup_to_watercolor_tmp_tex(&self.capillary_tex);
+        self.draw_watercolor_pass(&self.capillary_tex,
+                                  &self.watercolor_capillary_program,
+                                  &uniform!{
+                                      shallow_water_tex: &self.shallow_water_tex,
+                                      capillary_tex: &self.watercolor_tmp_tex,
+
+                                      texel_size: texel_size,
+
+                                      absorption: states.watercolor_capillary_absorption,
+                                      diffusion_threshold: 0.1f32,
+                                      receive_threshold: 0.05f32,
+                                  });
+    }
+
+    /// Glaze pigment of current wash onto passive layer, then reset water and paper for next
+    /// wash.
+    fn dry_watercolor_wash(&self, target: &LayerTextures, brush_color: [f32; 4]) {
+        self.backup_to_watercolor_tmp_tex(&target.passive_layer_tex);
+        self.draw_watercolor_pass(&target.passive_layer_tex,
+                                  &self.watercolor_dry_program,
+                                  &uniform!{
+                                      passive_layer_tex: &self.watercolor_tmp_tex,
+                                      pigment_tex: &self.pidment_deposition_tex,
+
+                                      brush_color: brush_color,
+                                  });
+
+        self.reset_watercolor_wash();
+    }
+
+    fn reset_watercolor_wash(&self) {
+        self.shallow_water_tex.as_surface().clear_color(0.0, 0.0, 0.0, 0.0);
+        self.pidment_deposition_tex.as_surface().clear_color(0.0, 0.0, 0.0, 0.0);
+        self.paper_tex.as_surface().fill(&self.capillary_tex.as_surface(),
+                                         glium::uniforms::MagnifySamplerFilter::Nearest);
+    }
+
+    /// Every stroke is one wash: its outline seeds a wet area, the fluid simulation runs on
+    /// it, and the result dries onto the passive layer before next stroke is painted.
+    ///
+    /// `strokes` are painted on top of what is already in `passive_layer_tex` of `target`.
+    fn render_watercolor_tex(&self,
+                             states: &States,
+                             target: &LayerTextures,
+                             strokes: &[OneStroke]) {
+        self.reset_watercolor_wash();
+
+        for stroke in strokes {
+            if stroke.anchors.is_empty() {
+                continue;
+            }
+
+            self.render_stroke_outline_on(states,
+                                          &self.stroke_outline_tmp_tex,
+                                          stroke,
+                                          [1.0, 1.0, 1.0, 1.0]);
+            self.seed_watercolor_wet_area(states);
+
+            for _ in 0..states.watercolor_iterations {
+                self.step_watercolor_simulation(states);
+            }
+
+            self.dry_watercolor_wash(target, stroke.color);
+        }
+    }
+
+    fn render_watercolor_brush_tex(&self, target: &LayerTextures) {
+        target.brush_tex.as_surface().clear_color(0.0, 0.0, 0.0, 0.0);
+
+        target.brush_tex
+            .as_surface()
+            .draw(&self.final_vertex_buffer,
+                  NoIndices(PrimitiveType::TriangleStrip),
+                  &self.watercolor_brush_program,
+                  &uniform!{
+                      passive_layer_tex: &target.passive_layer_tex,
+                  },
+                  &DrawParameters::default())
+            .expect("failed to draw triangle list");
+    }
+
+    /// Render every visible layer of `states` and composite them into `brush_preview_tex`.
+    ///
+    /// Every pass draws strokes smoothed by `spline::smooth_stroke`, never the raw anchors.
+    ///
+    /// Hidden layers keep their cached textures, so showing them again is cheap.
+    pub fn construct_brush_tex(&mut self, states: &States) {
+        self.construct_brush_tex_of(states, &states.layers);
+    }
+
+    /// Same as `construct_brush_tex`, but render `layers` instead of layers of `states`, e.g.
+    /// a painting halfway through `replay`.
+    ///
+    /// Layer caches are shared, so while a replay draws a stroke, only that stroke is rendered
+    /// again every frame.
+    pub fn construct_brush_tex_of(&mut self, states: &States, layers: &[Layer]) {
+        let (w, h) = self.brush_preview_tex.dimensions();
+
+        // Errors were already reported if caller loaded texture sets itself.
+        self.load_texture_sets_of(layers);
+
+        let mut caches = std::mem::take(&mut self.layers);
+        caches.truncate(layers.len());
+        while caches.len() < layers.len() {
+            caches.push(LayerCache {
+                textures: LayerTextures::new(&self.facade, w, h),
+                snapshot: LayerTextures::new(&self.facade, w, h),
+                checkpoint: None,
+            });
+        }
+
+        for (layer, cache) in layers.iter().zip(caches.iter_mut()) {
+            if layer.properties.visible {
+                self.construct_layer_tex(states, layer, cache);
+            }
+        }
+
+        self.composite_layers(layers, &caches);
+        self.layers = caches;
+    }
+
+    /// Clear every texture of `target` to a layer without strokes.
+    fn clear_layer_textures(&self, target: &LayerTextures) {
+        target.stroke_outline_tex.as_surface().clear_color(1.0, 1.0, 1.0, 1.0);
+        target.stroke_ink_quantity_tex.as_surface().clear_color(0.0, 0.0, 0.0, 0.0);
+        target.stroke_frame_tex.as_surface().clear_color(0.0, 0.0, 0.0, 0.0);
+        target.passive_layer_tex.as_surface().clear_color(1.0, 1.0, 1.0, 0.0);
+        target.brush_tex.as_surface().clear_color(0.0, 0.0, 0.0, 0.0);
+    }
+
+    /// Copy textures strokes of `render_mode` are drawn on from `from` into `to`.
+    fn copy_layer_textures(&self,
+                           render_mode: RenderMode,
+                           from: &LayerTextures,
+                           to: &LayerTextures) {
+        match render_mode {
+            RenderMode::BlackAndWhite => {
+                self.copy_texture(&from.stroke_outline_tex, &to.stroke_outline_tex);
+                self.copy_texture(&from.stroke_ink_quantity_tex, &to.stroke_ink_quantity_tex);
+                self.copy_texture(&from.stroke_frame_tex, &to.stroke_frame_tex);
+                self.copy_texture(&from.brush_tex, &to.brush_tex);
+            }
+            RenderMode::Colored => {
+                self.copy_texture(&from.passive_layer_tex, &to.passive_layer_tex);
+            }
+        }
+    }
+
+    /// Render `layer` into its own textures.
+    ///
+    /// Strokes are only drawn again from the first one changed since last render, on top of
+    /// the checkpoint snapshot if it is still valid. Black & white strokes get their ink
+    /// quantity right after their outline, so it only depends on strokes up to them and can be
+    /// kept like the outline.
+    fn construct_layer_tex(&self, states: &States, layer: &Layer, cache: &mut LayerCache) {
+        let (unchanged_stroke_count, rendered_stroke_count) = match cache.checkpoint {
+            Some(ref checkpoint) => {
+                (checkpoint.unchanged_stroke_count(states, layer), checkpoint.strokes.len())
+            }
+            None => (None, 0),
+        };
+
+        // Nothing changed since layer was last rendered.
+        if unchanged_stroke_count == Some(layer.strokes.len()) &&
+           rendered_stroke_count == layer.strokes.len() {
+            return;
+        }
+
+        let unchanged_stroke_count = unchanged_stroke_count.unwrap_or(0);
+        let (mut strokes, mut smoothed_strokes, snapshot_stroke_count) =
+            match cache.checkpoint.take() {
+                Some(checkpoint) => {
+                    (checkpoint.strokes,
+                     checkpoint.smoothed_strokes,
+                     checkpoint.snapshot_stroke_count)
+                }
+                None => (Vec::new(), Vec::new(), 0),
+            };
+
+        let target = &cache.textures;
+        let snapshot = &cache.snapshot;
+
+        // Textures hold every rendered stroke, so roll them back unless strokes were only
+        // added.
+        let first_stroke = if unchanged_stroke_count > 0 &&
+                              unchanged_stroke_count == rendered_stroke_count {
+            unchanged_stroke_count
+        } else if snapshot_stroke_count > 0 && snapshot_stroke_count <= unchanged_stroke_count {
+            self.copy_layer_textures(layer.properties.render_mode, snapshot, target);
+            snapshot_stroke_count
+        } else {
+            self.clear_layer_textures(target);
+            0
+        };
+
+        strokes.truncate(unchanged_stroke_count);
+        smoothed_strokes.truncate(unchanged_stroke_count);
+
+        let changed_strokes = &layer.strokes[unchanged_stroke_count..];
+        let spacing = spline::resample_spacing(states.stroke_interpolation_accuracy);
+
+        strokes.extend_from_slice(changed_strokes);
+        smoothed_strokes.extend(changed_strokes.iter()
+            .map(|stroke| spline::smooth_stroke(stroke, spacing)));
+
+        let render_mode = layer.properties.render_mode;
+        // Slot of a texture set only depends on strokes before its first use, so it is the
+        // same for strokes kept in the snapshot.
+        let texture_set_slots = texture_set_slots(&smoothed_strokes);
+        // Draw `strokes` on top of textures which only grow.
+        let accumulate = |strokes: &[OneStroke]| {
+            if strokes.is_empty() {
+                return;
+            }
+
+            match render_mode {
+                RenderMode::BlackAndWhite => {
+                    self.copy_texture(&target.stroke_ink_quantity_tex,
+                                      &self.stroke_ink_quantity_tmp_tex);
+
+                    let mut drawn: Option<Rect> = None;
+
+                    for stroke in strokes {
+                        self.render_stroke_ink_outline_tex(states,
+                                                           target,
+                                                           std::slice::from_ref(stroke));
+
+                        let rect = self.render_stroke_ink_quantity_tex(states,
+                                                                       target,
+                                                                       stroke,
+                                                                       &texture_set_slots);
+
+                        if let Some(rect) = rect {
+                            drawn = Some(match drawn {
+                                Some(drawn) => union_rect(drawn, rect),
+                                None => rect,
+                            });
+                        }
+                    }
+
+                    if let Some(rect) = drawn {
+                        self.render_brush_tex(states, target, &texture_set_slots, &rect);
+                    }
+                }
+                RenderMode::Colored => self.render_watercolor_tex(states, target, strokes),
+            }
+        };
+
+        accumulate(&smoothed_strokes[first_stroke..unchanged_stroke_count]);
+
+        // Next change most likely keeps the same strokes, e.g. only the stroke being drawn or
+        // edited changes again.
+        if unchanged_stroke_count > 0 && unchanged_stroke_count != snapshot_stroke_count {
+            self.copy_layer_textures(render_mode, target, snapshot);
+        }
+
+        accumulate(&smoothed_strokes[unchanged_stroke_count..]);
+
+        if render_mode == RenderMode::Colored {
+            self.render_watercolor_brush_tex(target);
+        }
+
+        cache.checkpoint = Some(CanvasCheckpoint {
+            parameters: SharedRenderParameters::from_states(states),
+            render_mode,
+            canvas_size: states.canvas_size,
+            strokes,
+            smoothed_strokes,
+            snapshot_stroke_count: unchanged_stroke_count,
+        });
+    }
+
+    /// Blend visible layers onto `brush_preview_tex`, bottom layer first.
+    fn composite_layers(&self, layers: &[Layer], caches: &[LayerCache]) {
+        use glium::uniforms::MagnifySamplerFilter;
+
+        self.brush_preview_tex.as_surface().clear_color(0.0, 0.0, 0.0, 0.0);
+
+        for (layer, cache) in layers.iter().zip(caches) {
+            if !layer.properties.visible {
+                continue;
+            }
+
+            // Blend modes need what is below, which can not be read while being drawn to.
+            self.brush_preview_tex
+                .as_surface()
+                .fill(&self.composite_tmp_tex.as_surface(), MagnifySamplerFilter::Nearest);
+
+            self.brush_preview_tex
+                .as_surface()
+                .draw(&self.final_vertex_buffer,
+                      NoIndices(PrimitiveType::TriangleStrip),
+                      &self.layer_composite_program,
+                      &uniform!{
+                          backdrop_tex: &self.composite_tmp_tex,
+                          layer_tex: &cache.textures.brush_tex,
+
+                          opacity: layer.properties.opacity,
+                          blend_mode: layer.properties.blend_mode as i32,
+                      },
+                      &DrawParameters::default())
+                .expect("failed to composite layer");
+        }
+    }
+
+    /// Largest radius of brush footprint at `anchor`.
+    ///
+    /// A tilted brush lays its side down, so footprint grows along the azimuth.
+    fn caculate_brush_radius(&self, brush: &StrokeBrush, anchor: &StrokeAnchor) -> f32 {
+        let radius = self.caculate_upright_radius(brush, anchor);
+
+        match anchor.tilt {
+            Some(tilt) => radius * (1.0 + TILT_SPREAD * tilt.sin()),
+            None => radius,
+        }
+    }
+
+    /// Radius of brush footprint if it was held upright.
+    fn caculate_upright_radius(&self, brush: &StrokeBrush, anchor: &StrokeAnchor) -> f32 {
+        brush.pressure_response.width.evaluate(anchor.pressure) * brush.max_brush_width
+    }
+
+    /// Half width of brush footprint across a stroke moving along `direction`.
+    ///
+    /// Footprint is an ellipse, long axis along azimuth, so a tilted brush dragged sideways
+    /// is wider than one dragged along its tilt.
+    fn caculate_side_radius(&self,
+                            brush: &StrokeBrush,
+                            anchor: &StrokeAnchor,
+                            direction: [f32; 2])
+                            -> f32 {
+        let major = self.caculate_brush_radius(brush, anchor);
+
+        let azimuth = match anchor.azimuth {
+            Some(azimuth) => azimuth,
+            None => return major,
+        };
+
+        let minor = self.caculate_upright_radius(brush, anchor);
+        let normal = [-direction[1], direction[0]];
+        let long_axis = [azimuth.cos(), azimuth.sin()];
+
+        let along_major = major * vecmath::vec2_dot(normal, long_axis);
+        let along_minor = minor * vecmath::vec2_cross(long_axis, normal);
+
+        (along_major * along_major + along_minor * along_minor).sqrt()
+    }
+
+    /// See `ink::segment_cost`.
+    fn caculate_ink_cost(&self,
+                         states: &States,
+                         brush: &StrokeBrush,
+                         start_anchor: &StrokeAnchor,
+                         start_radius: f32,
+                         end_anchor: &StrokeAnchor,
+                         end_radius: f32)
+                         -> f32 {
+        ink::segment_cost(brush,
+                          start_anchor,
+                          start_radius,
+                          end_anchor,
+                          end_radius,
+                          states.paper_absorbency)
+    }
+
+    /// Ink `stroke` takes out of brush reservoir, as drawn after spline smoothing.
+    pub fn stroke_ink_cost(&self, states: &States, stroke: &OneStroke) -> f32 {
+        let spacing = spline::resample_spacing(states.stroke_interpolation_accuracy);
+        let stroke = spline::smooth_stroke(stroke, spacing);
+        let brush = &stroke.brush;
+
+        stroke.anchors
+            .windows(2)
+            .map(|segment| {
+                let (start, end) = (&segment[0], &segment[1]);
+                let start_radius = self.caculate_brush_radius(brush, start);
+                let end_radius = self.caculate_brush_radius(brush, end);
+
+                self.caculate_ink_cost(states, brush, start, start_radius, end, end_radius)
+            })
+            .sum()
+    }
+
+    /// Arc length of `stroke` at every anchor, in pixels.
+    fn caculate_arc_lengths(&self, states: &States, stroke: &OneStroke) -> Vec<f32> {
+        let mut arc_length = 0.0;
+        let mut prev_pos = None;
+
+        stroke.anchors
+            .iter()
+            .map(|anchor| {
+                let pos = self.to_pixel_pos(states, &anchor.pos);
+                if let Some(prev_pos) = prev_pos {
+                    arc_length += vecmath::vec2_len(vecmath::vec2_sub(pos, prev_pos));
+                }
+                prev_pos = Some(pos);
+
+                arc_length
+            })
+            .collect()
+    }
+
+    fn caculate_opacity(&self, brush: &StrokeBrush, pressure: f32) -> f32 {
+        brush.pressure_response.opacity.evaluate(pressure)
+    }
+
+    /// Read `brush_preview_tex` back from GPU, top row first.
+    pub fn read_brush_preview_image(&self) -> image::RgbaImage {
+        let raw: glium::texture::RawImage2d<u8> = self.brush_preview_tex.read();
+        let image = image::ImageBuffer::from_raw(raw.width, raw.height, raw.data.into_owned())
+            .unwrap();
+
+        // OpenGL stores the bottom row first.
+        image::imageops::flip_vertical(&image)
+    }
+
+    pub fn save_brush_preview_png<P: AsRef<Path>>(&self, path: P) -> image::ImageResult<()> {
+        self.read_brush_preview_image().save(path)
+    }
+}
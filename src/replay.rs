@@ -0,0 +1,228 @@
+use spline;
+use states::{Layer, OneStroke};
+
+/// Seconds between anchors whose capture time is unknown.
+const UNTIMED_ANCHOR_INTERVAL: f32 = 1.0 / 30.0;
+/// Pause between strokes when at least one of them has no start time.
+const UNTIMED_STROKE_PAUSE: f32 = 0.3;
+/// Longest pause between strokes, so breaks taken while painting are skipped.
+const MAX_STROKE_PAUSE: f32 = 1.0;
+
+/// When a stroke is drawn during replay.
+struct StrokeTiming {
+    // Seconds from replay start to first anchor.
+    begin: f32,
+    // Seconds from first anchor to every anchor, never decreasing.
+    anchor_times: Vec<f32>,
+}
+
+impl StrokeTiming {
+    fn end(&self) -> f32 {
+        self.begin + self.anchor_times.last().cloned().unwrap_or(0.0)
+    }
+}
+
+/// Seconds from first anchor of `stroke` to every anchor.
+///
+/// Anchors without capture time, e.g. inserted by hand, follow the one before them after
+/// `UNTIMED_ANCHOR_INTERVAL`.
+fn anchor_times(stroke: &OneStroke) -> Vec<f32> {
+    let mut times: Vec<f32> = Vec::with_capacity(stroke.anchors.len());
+
+    for anchor in &stroke.anchors {
+        let time = match (times.last().cloned(), anchor.time) {
+            (None, _) => 0.0,
+            (Some(prev), Some(time)) => {
+                let first = stroke.anchors[0].time.unwrap_or(0.0);
+                (time - first).max(prev)
+            }
+            (Some(prev), None) => prev + UNTIMED_ANCHOR_INTERVAL,
+        };
+        times.push(time);
+    }
+
+    times
+}
+
+/// Painting being drawn again stroke by stroke, the way it was painted.
+///
+/// Strokes of every layer are played in order of their start time, strokes without one come
+/// first in layer order. Anchors are reached at their capture time, long breaks between
+/// strokes are shortened to `MAX_STROKE_PAUSE`.
+pub struct Replay {
+    // Snapshot of painting when replay started, later edits do not change it.
+    layers: Vec<Layer>,
+    // Same layout as strokes of `layers`.
+    timings: Vec<Vec<StrokeTiming>>,
+
+    pub duration: f32,
+    // Seconds of replay shown, from 0 to `duration`.
+    pub time: f32,
+    // 1 plays at original speed.
+    pub speed: f32,
+    pub is_paused: bool,
+}
+
+impl Replay {
+    pub fn new(layers: &[Layer]) -> Self {
+        let mut order: Vec<(usize, usize)> = layers.iter()
+            .enumerate()
+            .flat_map(|(layer, l)| (0..l.strokes.len()).map(move |stroke| (layer, stroke)))
+            .collect();
+        let start_time = |&(layer, stroke): &(usize, usize)| {
+            layers[layer].strokes[stroke].start_time
+        };
+        // Stable, so strokes with equal or unknown start time keep layer order.
+        order.sort_by(|a, b| {
+            let (a, b) = (start_time(a), start_time(b));
+            a.unwrap_or(-1.0).partial_cmp(&b.unwrap_or(-1.0)).unwrap()
+        });
+
+        let mut timings: Vec<Vec<Option<StrokeTiming>>> = layers.iter()
+            .map(|layer| layer.strokes.iter().map(|_| None).collect())
+            .collect();
+
+        let mut clock = 0.0;
+        let mut prev: Option<(f64, f32)> = None;
+        for &(layer, stroke) in &order {
+            let this = &layers[layer].strokes[stroke];
+
+            let pause = match (prev, this.start_time) {
+                // Start times are compared with when the previous stroke ended, not began.
+                (Some((prev_start, prev_length)), Some(start)) => {
+                    let gap = (start - prev_start) as f32 - prev_length;
+                    gap.clamp(0.0, MAX_STROKE_PAUSE)
+                }
+                (None, _) => 0.0,
+                _ => UNTIMED_STROKE_PAUSE,
+            };
+
+            let timing = StrokeTiming {
+                begin: clock + pause,
+                anchor_times: anchor_times(this),
+            };
+            clock = timing.end();
+            prev = this.start_time.map(|start| (start, timing.end() - timing.begin));
+
+            timings[layer][stroke] = Some(timing);
+        }
+
+        Replay {
+            layers: layers.to_vec(),
+            timings: timings.into_iter()
+                .map(|layer| layer.into_iter().map(|timing| timing.unwrap()).collect())
+                .collect(),
+            duration: clock,
+            time: 0.0,
+            speed: 1.0,
+            is_paused: false,
+        }
+    }
+
+    /// Move replay on by `dt` seconds of real time, returns whether anything changed.
+    ///
+    /// Replay pauses once it reaches the end.
+    pub fn advance(&mut self, dt: f32) -> bool {
+        if self.is_paused || self.is_finished() {
+            return false;
+        }
+
+        let time = self.time + dt * self.speed;
+        self.seek(time);
+        if self.is_finished() {
+            self.is_paused = true;
+        }
+
+        true
+    }
+
+    pub fn seek(&mut self, time: f32) {
+        self.time = time.max(0.0).min(self.duration);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.time >= self.duration
+    }
+
+    /// Painting as it looked at current time.
+    ///
+    /// Strokes not started yet are left out and the stroke being drawn ends where the brush
+    /// is. Finished strokes stay a prefix of every layer, so the renderer only draws the
+    /// stroke in progress again, on top of a snapshot of them.
+    pub fn layers_so_far(&self) -> Vec<Layer> {
+        self.layers_at(self.time)
+    }
+
+    /// Painting as it looked `time` seconds into replay.
+    pub fn layers_at(&self, time: f32) -> Vec<Layer> {
+        self.layers
+            .iter()
+            .zip(&self.timings)
+            .map(|(layer, timings)| {
+                let strokes = layer.strokes
+                    .iter()
+                    .zip(timings)
+                    .filter(|&(_, timing)| timing.begin <= time)
+                    .map(|(stroke, timing)| stroke_at(stroke, timing, time - timing.begin))
+                    .collect();
+
+                Layer {
+                    properties: layer.properties.clone(),
+                    strokes,
+                }
+            })
+            .collect()
+    }
+
+    /// Number of strokes started by current time, out of all strokes.
+    pub fn stroke_progress(&self) -> (usize, usize) {
+        let mut progress = (0, 0);
+        for timing in self.timings.iter().flat_map(|layer| layer.iter()) {
+            if timing.begin <= self.time {
+                progress.0 += 1;
+            }
+            progress.1 += 1;
+        }
+
+        progress
+    }
+
+    /// Times at which every stroke is finished, in order.
+    pub fn stroke_end_times(&self) -> Vec<f32> {
+        let mut ends: Vec<f32> = self.timings
+            .iter()
+            .flat_map(|layer| layer.iter().map(|timing| timing.end()))
+            .collect();
+        ends.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        ends
+    }
+}
+
+/// Part of `stroke` drawn `elapsed` seconds after it started.
+fn stroke_at(stroke: &OneStroke, timing: &StrokeTiming, elapsed: f32) -> OneStroke {
+    let times = &timing.anchor_times;
+    let reached = times.iter().take_while(|&&time| time <= elapsed).count();
+    if reached == times.len() {
+        return stroke.clone();
+    }
+
+    let mut partial = OneStroke {
+        color: stroke.color,
+        anchors: stroke.anchors[..reached].to_vec(),
+        brush: stroke.brush.clone(),
+        ink_load: stroke.ink_load,
+        start_time: stroke.start_time,
+    };
+
+    // Brush is somewhere between last anchor reached and the next one.
+    if reached > 0 {
+        let (prev, next) = (times[reached - 1], times[reached]);
+        let t = (elapsed - prev) / (next - prev);
+        partial.anchors.push(spline::lerp_anchor(&stroke.anchors[reached - 1],
+                                                 &stroke.anchors[reached],
+                                                 t));
+    }
+
+    partial
+}
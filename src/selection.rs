@@ -0,0 +1,383 @@
+use vecmath;
+
+use history;
+use history::Command;
+use states::OneStroke;
+use states::States;
+use states::StrokeAnchor;
+use states::StrokeBrush;
+
+/// Canvas units around strokes and handles which still count as a hit.
+const HIT_SLACK: f32 = 4.0;
+/// Distance between rotate handle and top of bounding box.
+const ROTATE_HANDLE_OFFSET: f32 = 25.0;
+/// Duplicated stroke is moved by this so it does not hide the original.
+const DUPLICATE_OFFSET: [f32; 2] = [20.0, 20.0];
+
+/// Scale handle sits outside bounding box, so it never covers the corner anchor.
+const SCALE_HANDLE_OFFSET: f32 = 10.0;
+
+pub const HANDLE_RADIUS: f32 = 6.0;
+/// Radius of anchor dots drawn on canvas.
+pub const ANCHOR_RADIUS: f32 = 3.0;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Handle {
+    Move,
+    Rotate,
+    Scale,
+    // Index of anchor.
+    Anchor(usize),
+}
+
+/// Index of selected stroke in active layer, `None` if it no longer exists, e.g. after undo,
+/// or if active layer is hidden or locked.
+pub fn selected_stroke(states: &States) -> Option<usize> {
+    if !states.active_layer().is_editable() {
+        return None;
+    }
+
+    match states.selected_stroke {
+        Some(index) if index < states.strokes().len() => Some(index),
+        _ => None,
+    }
+}
+
+/// Index of topmost stroke in active layer whose outline covers `pos`.
+pub fn hit_test(states: &States, pos: [f32; 2]) -> Option<usize> {
+    if !states.active_layer().is_editable() {
+        return None;
+    }
+
+    states.strokes().iter().rposition(|stroke| hits_stroke(stroke, pos))
+}
+
+fn hits_stroke(stroke: &OneStroke, pos: [f32; 2]) -> bool {
+    match stroke.anchors.len() {
+        0 => false,
+        1 => {
+            distance(pos, stroke.anchors[0].pos) <= hit_radius(&stroke.brush, &stroke.anchors[0])
+        }
+        _ => hit_segment_of(stroke, pos).is_some(),
+    }
+}
+
+fn hit_radius(brush: &StrokeBrush, anchor: &StrokeAnchor) -> f32 {
+    brush.pressure_response.width.evaluate(anchor.pressure) * brush.max_brush_width + HIT_SLACK
+}
+
+/// First segment of `stroke` covering `pos`, as index of its start anchor and where along the
+/// segment `pos` is.
+///
+/// Every segment is a capsule whose radius changes linearly along it.
+fn hit_segment_of(stroke: &OneStroke, pos: [f32; 2]) -> Option<(usize, f32)> {
+    for (index, segment) in stroke.anchors.windows(2).enumerate() {
+        let (start, end) = (&segment[0], &segment[1]);
+        let (d, t) = distance_to_segment(pos, start.pos, end.pos);
+
+        let start_radius = hit_radius(&stroke.brush, start);
+        let end_radius = hit_radius(&stroke.brush, end);
+        if d <= start_radius + t * (end_radius - start_radius) {
+            return Some((index, t));
+        }
+    }
+
+    None
+}
+
+/// Topmost anchor dot under `pos`, as stroke index and anchor index.
+pub fn hit_anchor(states: &States, pos: [f32; 2]) -> Option<(usize, usize)> {
+    if !states.active_layer().is_editable() {
+        return None;
+    }
+
+    for (stroke_index, stroke) in states.strokes().iter().enumerate().rev() {
+        let anchor_index = stroke.anchors
+            .iter()
+            .position(|anchor| distance(anchor.pos, pos) <= ANCHOR_RADIUS + HIT_SLACK);
+
+        if let Some(anchor_index) = anchor_index {
+            return Some((stroke_index, anchor_index));
+        }
+    }
+
+    None
+}
+
+/// Insert an anchor where `pos` hits topmost stroke, returns stroke index and the new stroke.
+///
+/// Inserted anchor lies on the segment, with pressure interpolated from its neighbours.
+pub fn insert_anchor_at(states: &States, pos: [f32; 2]) -> Option<(usize, OneStroke)> {
+    if !states.active_layer().is_editable() {
+        return None;
+    }
+
+    for (stroke_index, stroke) in states.strokes().iter().enumerate().rev() {
+        if let Some((index, t)) = hit_segment_of(stroke, pos) {
+            let (start, end) = (&stroke.anchors[index], &stroke.anchors[index + 1]);
+
+            let mut anchor = start.clone();
+            anchor.pos = vecmath::vec2_add(start.pos,
+                                           vecmath::vec2_scale(vecmath::vec2_sub(end.pos,
+                                                                                 start.pos),
+                                                               t));
+            anchor.pressure = start.pressure + t * (end.pressure - start.pressure);
+
+            let mut new_stroke = stroke.clone();
+            new_stroke.anchors.insert(index + 1, anchor);
+            return Some((stroke_index, new_stroke));
+        }
+    }
+
+    None
+}
+
+/// Remove anchor from stroke, whole stroke is deleted with its last anchor.
+pub fn delete_anchor(states: &mut States, stroke_index: usize, anchor_index: usize) {
+    let layer = states.active_layer;
+    let old = states.strokes()[stroke_index].clone();
+
+    if old.anchors.len() <= 1 {
+        history::execute(states,
+                         Command::DeleteStroke {
+                             layer,
+                             index: stroke_index,
+                             stroke: old,
+                         });
+        states.selected_stroke = None;
+        return;
+    }
+
+    let mut new = old.clone();
+    new.anchors.remove(anchor_index);
+    history::execute(states,
+                     Command::EditStroke {
+                         layer,
+                         index: stroke_index,
+                         old,
+                         new,
+                     });
+}
+
+/// Nudge pressure of an anchor, e.g. by scroll wheel.
+pub fn adjust_anchor_pressure(states: &mut States,
+                              stroke_index: usize,
+                              anchor_index: usize,
+                              delta: f32) {
+    let layer = states.active_layer;
+    let old = match states.strokes()
+        .get(stroke_index)
+        .and_then(|stroke| stroke.anchors.get(anchor_index)) {
+        Some(anchor) => anchor.pressure,
+        None => return,
+    };
+    let new = (old + delta).clamp(0.0, 1.0);
+
+    if new != old {
+        history::execute(states,
+                         Command::EditPressure {
+                             layer,
+                             stroke_index,
+                             anchor_index,
+                             old,
+                             new,
+                         });
+    }
+}
+
+/// Axis aligned bounding box of anchors, as min and max corner.
+pub fn stroke_bounds(stroke: &OneStroke) -> Option<([f32; 2], [f32; 2])> {
+    let first = match stroke.anchors.first() {
+        Some(anchor) => anchor.pos,
+        None => return None,
+    };
+
+    let bounds = stroke.anchors.iter().fold((first, first), |(min, max), anchor| {
+        ([min[0].min(anchor.pos[0]), min[1].min(anchor.pos[1])],
+         [max[0].max(anchor.pos[0]), max[1].max(anchor.pos[1])])
+    });
+
+    Some(bounds)
+}
+
+/// Where rotate and scale handles of `stroke` are drawn.
+pub fn handle_positions(stroke: &OneStroke) -> Option<[(Handle, [f32; 2]); 2]> {
+    stroke_bounds(stroke).map(|(min, max)| {
+        [(Handle::Rotate, [(min[0] + max[0]) / 2.0, min[1] - ROTATE_HANDLE_OFFSET]),
+         (Handle::Scale, [max[0] + SCALE_HANDLE_OFFSET, max[1] + SCALE_HANDLE_OFFSET])]
+    })
+}
+
+/// Handle of stroke no.`index` under `pos`, anywhere inside bounding box moves it.
+pub fn hit_handle(states: &States, index: usize, pos: [f32; 2]) -> Option<Handle> {
+    let stroke = &states.strokes()[index];
+
+    if let Some(handles) = handle_positions(stroke) {
+        for &(handle, handle_pos) in &handles {
+            if distance(pos, handle_pos) <= HANDLE_RADIUS + HIT_SLACK {
+                return Some(handle);
+            }
+        }
+    }
+
+    let (min, max) = stroke_bounds(stroke)?;
+
+    let is_inside = (0..2).all(|i| pos[i] >= min[i] - HIT_SLACK && pos[i] <= max[i] + HIT_SLACK);
+    if is_inside { Some(Handle::Move) } else { None }
+}
+
+/// Transform handle being dragged.
+pub struct Drag {
+    pub layer: usize,
+    pub index: usize,
+    pub handle: Handle,
+    pub original: OneStroke,
+
+    start_pos: [f32; 2],
+    // Rotate and scale happen around center of bounding box.
+    pivot: [f32; 2],
+}
+
+impl Drag {
+    pub fn new(states: &States, index: usize, handle: Handle, start_pos: [f32; 2]) -> Self {
+        let original = states.strokes()[index].clone();
+        let pivot = match stroke_bounds(&original) {
+            Some((min, max)) => [(min[0] + max[0]) / 2.0, (min[1] + max[1]) / 2.0],
+            None => start_pos,
+        };
+
+        Drag {
+            layer: states.active_layer,
+            index,
+            handle,
+            original,
+
+            start_pos,
+            pivot,
+        }
+    }
+
+    /// Dragged stroke, `None` if it was deleted or undone in the middle of dragging.
+    pub fn stroke_mut<'a>(&self, states: &'a mut States) -> Option<&'a mut OneStroke> {
+        states.layers.get_mut(self.layer).and_then(|layer| layer.strokes.get_mut(self.index))
+    }
+
+    /// Original stroke transformed by dragging handle to `pos`.
+    pub fn transformed(&self, pos: [f32; 2]) -> OneStroke {
+        let mut stroke = self.original.clone();
+
+        match self.handle {
+            Handle::Move => {
+                let offset = vecmath::vec2_sub(pos, self.start_pos);
+                for anchor in &mut stroke.anchors {
+                    anchor.pos = vecmath::vec2_add(anchor.pos, offset);
+                }
+            }
+            Handle::Rotate => {
+                let angle = angle_around(self.pivot, pos) -
+                            angle_around(self.pivot, self.start_pos);
+                let (sin, cos) = angle.sin_cos();
+
+                for anchor in &mut stroke.anchors {
+                    let v = vecmath::vec2_sub(anchor.pos, self.pivot);
+                    anchor.pos = vecmath::vec2_add(self.pivot,
+                                                   [v[0] * cos - v[1] * sin,
+                                                    v[0] * sin + v[1] * cos]);
+                    anchor.azimuth = anchor.azimuth.map(|azimuth| azimuth + angle);
+                }
+            }
+            Handle::Anchor(index) => {
+                let offset = vecmath::vec2_sub(pos, self.start_pos);
+                stroke.anchors[index].pos = vecmath::vec2_add(stroke.anchors[index].pos, offset);
+            }
+            Handle::Scale => {
+                let start_distance = distance(self.start_pos, self.pivot).max(1e-3);
+                let factor = distance(pos, self.pivot) / start_distance;
+
+                for anchor in &mut stroke.anchors {
+                    let v = vecmath::vec2_sub(anchor.pos, self.pivot);
+                    anchor.pos = vecmath::vec2_add(self.pivot, vecmath::vec2_scale(v, factor));
+                }
+            }
+        }
+
+        stroke
+    }
+}
+
+pub fn delete_selected(states: &mut States) {
+    if let Some(index) = selected_stroke(states) {
+        let layer = states.active_layer;
+        let stroke = states.strokes()[index].clone();
+        history::execute(states,
+                         Command::DeleteStroke {
+                             layer,
+                             index,
+                             stroke,
+                         });
+    }
+
+    states.selected_stroke = None;
+}
+
+/// Duplicate becomes the new selection.
+pub fn duplicate_selected(states: &mut States) {
+    if let Some(index) = selected_stroke(states) {
+        let mut stroke = states.strokes()[index].clone();
+        for anchor in &mut stroke.anchors {
+            anchor.pos = vecmath::vec2_add(anchor.pos, DUPLICATE_OFFSET);
+        }
+
+        let layer = states.active_layer;
+        history::execute(states,
+                         Command::AddStroke {
+                             layer,
+                             stroke,
+                         });
+        states.selected_stroke = Some(states.strokes().len() - 1);
+    }
+}
+
+/// Restyle selected stroke with the brush new strokes are drawn with.
+pub fn apply_brush_to_selected(states: &mut States) {
+    if let Some(index) = selected_stroke(states) {
+        let layer = states.active_layer;
+        let old = states.strokes()[index].clone();
+
+        let mut new = old.clone();
+        new.brush = StrokeBrush::from_states(states);
+
+        if new != old {
+            history::execute(states,
+                             Command::EditStroke {
+                                 layer,
+                                 index,
+                                 old,
+                                 new,
+                             });
+        }
+    }
+}
+
+fn distance(a: [f32; 2], b: [f32; 2]) -> f32 {
+    vecmath::vec2_len(vecmath::vec2_sub(a, b))
+}
+
+fn angle_around(pivot: [f32; 2], pos: [f32; 2]) -> f32 {
+    (pos[1] - pivot[1]).atan2(pos[0] - pivot[0])
+}
+
+/// Distance from `pos` to segment, and where along the segment the closest point is.
+fn distance_to_segment(pos: [f32; 2], start: [f32; 2], end: [f32; 2]) -> (f32, f32) {
+    let segment = vecmath::vec2_sub(end, start);
+    let len_sq = vecmath::vec2_square_len(segment);
+
+    let t = if len_sq > 0.0 {
+        (vecmath::vec2_dot(vecmath::vec2_sub(pos, start), segment) / len_sq).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+
+    let closest = vecmath::vec2_add(start, vecmath::vec2_scale(segment, t));
+    (distance(pos, closest), t)
+}
@@ -0,0 +1,118 @@
+use vecmath;
+
+use states::OneStroke;
+use states::StrokeAnchor;
+
+/// Anchors closer than this are duplicates, they would give segments without direction.
+const DUPLICATE_DISTANCE: f32 = 1e-3;
+
+/// Drop anchors of `stroke` which deviate less than `tolerance` canvas units from the
+/// Ramer-Douglas-Peucker simplified stroke.
+///
+/// Pressure counts as a third axis scaled by `max_brush_width` of the stroke brush, so anchors
+/// where the brush swells or thins are kept even on straight lines.
+pub fn simplify_stroke(stroke: &OneStroke, tolerance: f32) -> OneStroke {
+    let max_brush_width = stroke.brush.max_brush_width;
+
+    let mut anchors: Vec<StrokeAnchor> = Vec::with_capacity(stroke.anchors.len());
+    for anchor in &stroke.anchors {
+        let is_duplicate = anchors.last()
+            .is_some_and(|last| distance(&last.pos, &anchor.pos) < DUPLICATE_DISTANCE);
+
+        if !is_duplicate {
+            anchors.push(anchor.clone());
+        }
+    }
+
+    if anchors.len() > 2 && tolerance > 0.0 {
+        let points: Vec<[f32; 3]> = anchors.iter()
+            .map(|a| [a.pos[0], a.pos[1], a.pressure * max_brush_width])
+            .collect();
+
+        let mut keep = vec![false; points.len()];
+        keep[0] = true;
+        keep[points.len() - 1] = true;
+        mark_kept_points(&points, 0, points.len() - 1, tolerance, &mut keep);
+
+        anchors = anchors.into_iter()
+            .zip(keep)
+            .filter(|&(_, keep)| keep)
+            .map(|(anchor, _)| anchor)
+            .collect();
+    }
+
+    OneStroke {
+        color: stroke.color,
+        anchors,
+        brush: stroke.brush.clone(),
+        ink_load: stroke.ink_load,
+        start_time: stroke.start_time,
+    }
+}
+
+/// Ramer-Douglas-Peucker simplified closed `outline`, its first point is not repeated at the
+/// end.
+pub fn simplify_outline(outline: &[[f32; 2]], tolerance: f32) -> Vec<[f32; 2]> {
+    if outline.len() <= 3 || tolerance <= 0.0 {
+        return outline.to_vec();
+    }
+
+    // Ending where it starts closes the outline.
+    let points: Vec<[f32; 3]> = outline.iter()
+        .chain(outline.first())
+        .map(|p| [p[0], p[1], 0.0])
+        .collect();
+
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    mark_kept_points(&points, 0, points.len() - 1, tolerance, &mut keep);
+
+    outline.iter()
+        .zip(keep)
+        .filter(|&(_, keep)| keep)
+        .map(|(point, _)| *point)
+        .collect()
+}
+
+fn mark_kept_points(points: &[[f32; 3]],
+                    first: usize,
+                    last: usize,
+                    tolerance: f32,
+                    keep: &mut [bool]) {
+    if last <= first + 1 {
+        return;
+    }
+
+    let (mut farthest, mut max_distance) = (first, 0.0);
+    for i in first + 1..last {
+        let d = distance_to_segment(points[i], points[first], points[last]);
+        if d > max_distance {
+            farthest = i;
+            max_distance = d;
+        }
+    }
+
+    if max_distance > tolerance {
+        keep[farthest] = true;
+        mark_kept_points(points, first, farthest, tolerance, keep);
+        mark_kept_points(points, farthest, last, tolerance, keep);
+    }
+}
+
+fn distance(a: &[f32; 2], b: &[f32; 2]) -> f32 {
+    vecmath::vec2_len(vecmath::vec2_sub(*a, *b))
+}
+
+fn distance_to_segment(p: [f32; 3], a: [f32; 3], b: [f32; 3]) -> f32 {
+    let ab = vecmath::vec3_sub(b, a);
+    let ap = vecmath::vec3_sub(p, a);
+
+    let len_sq = vecmath::vec3_square_len(ab);
+    let t = if len_sq > 0.0 {
+        (vecmath::vec3_dot(ap, ab) / len_sq).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+
+    vecmath::vec3_len(vecmath::vec3_sub(ap, vecmath::vec3_scale(ab, t)))
+}
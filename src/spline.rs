@@ -0,0 +1,126 @@
+use interpolation;
+use vecmath;
+
+use states::OneStroke;
+use states::StrokeAnchor;
+
+/// Spacing of resampled anchors in canvas units when interpolation accuracy is 1.
+const BASE_SPACING: f32 = 40.0;
+/// Spline is flattened this many times finer than resampling spacing before measuring length.
+const FLATTEN_RATIO: f32 = 4.0;
+
+/// Distance between anchors of a smoothed stroke.
+pub fn resample_spacing(interpolation_accuracy: f32) -> f32 {
+    BASE_SPACING / interpolation_accuracy.max(1.0)
+}
+
+/// Replace polyline of `stroke` by a Catmull-Rom spline through its anchors, resampled into
+/// anchors evenly `spacing` apart along arc length.
+///
+/// Pressure, tilt, azimuth and capture time are interpolated along with position.
+pub fn smooth_stroke(stroke: &OneStroke, spacing: f32) -> OneStroke {
+    let anchors = &stroke.anchors;
+    if anchors.len() < 2 {
+        return stroke.clone();
+    }
+
+    let dense = flatten(anchors, spacing / FLATTEN_RATIO);
+
+    OneStroke {
+        color: stroke.color,
+        anchors: resample(&dense, spacing),
+        brush: stroke.brush.clone(),
+        ink_load: stroke.ink_load,
+        start_time: stroke.start_time,
+    }
+}
+
+/// Sample the spline densely enough that it is straight between samples.
+fn flatten(anchors: &[StrokeAnchor], step: f32) -> Vec<StrokeAnchor> {
+    let last = anchors.len() - 1;
+    let mut dense = vec![anchors[0].clone()];
+
+    for i in 0..last {
+        // End points are repeated so spline passes through first and last anchor.
+        let p0 = &anchors[if i == 0 { 0 } else { i - 1 }];
+        let p1 = &anchors[i];
+        let p2 = &anchors[i + 1];
+        let p3 = &anchors[if i + 1 == last { last } else { i + 2 }];
+
+        let chord = vecmath::vec2_len(vecmath::vec2_sub(p2.pos, p1.pos));
+        let subdivisions = ((chord / step).ceil() as usize).max(1);
+
+        for j in 1..subdivisions + 1 {
+            let t = j as f32 / subdivisions as f32;
+
+            let mut anchor = lerp_anchor(p1, p2, t);
+            anchor.pos = catmull_rom(p0.pos, p1.pos, p2.pos, p3.pos, t);
+            dense.push(anchor);
+        }
+    }
+
+    dense
+}
+
+/// Walk along `dense` and emit an anchor every `spacing` of arc length.
+fn resample(dense: &[StrokeAnchor], spacing: f32) -> Vec<StrokeAnchor> {
+    let mut resampled = vec![dense[0].clone()];
+
+    // Arc length left until next sample.
+    let mut remaining = spacing;
+
+    for segment in dense.windows(2) {
+        let (start, end) = (&segment[0], &segment[1]);
+        let length = vecmath::vec2_len(vecmath::vec2_sub(end.pos, start.pos));
+
+        let mut travelled = 0.0;
+        while length - travelled >= remaining {
+            travelled += remaining;
+            remaining = spacing;
+
+            resampled.push(lerp_anchor(start, end, travelled / length));
+        }
+
+        remaining -= length - travelled;
+    }
+
+    // Keep the real end of stroke unless a sample already landed on it.
+    let last = &dense[dense.len() - 1];
+    let tail = vecmath::vec2_len(vecmath::vec2_sub(last.pos, resampled[resampled.len() - 1].pos));
+    if tail > 1e-3 {
+        resampled.push(last.clone());
+    }
+
+    resampled
+}
+
+fn catmull_rom(p0: [f32; 2], p1: [f32; 2], p2: [f32; 2], p3: [f32; 2], t: f32) -> [f32; 2] {
+    let (t2, t3) = (t * t, t * t * t);
+
+    let axis = |i: usize| {
+        let a = 2.0 * p1[i];
+        let b = -p0[i] + p2[i];
+        let c = 2.0 * p0[i] - 5.0 * p1[i] + 4.0 * p2[i] - p3[i];
+        let d = -p0[i] + 3.0 * p1[i] - 3.0 * p2[i] + p3[i];
+
+        0.5 * (a + b * t + c * t2 + d * t3)
+    };
+
+    [axis(0), axis(1)]
+}
+
+/// Anchor `t` of the way from `a` to `b`, values only one of them knows are kept as is.
+pub fn lerp_anchor(a: &StrokeAnchor, b: &StrokeAnchor, t: f32) -> StrokeAnchor {
+    let lerp_option = |a: Option<f32>, b: Option<f32>| match (a, b) {
+        (Some(a), Some(b)) => Some(interpolation::lerp(&a, &b, &t)),
+        (a, b) => a.or(b),
+    };
+
+    StrokeAnchor {
+        pos: interpolation::lerp(&a.pos, &b.pos, &t),
+        pressure: interpolation::lerp(&a.pressure, &b.pressure, &t),
+        tilt: lerp_option(a.tilt, b.tilt),
+        azimuth: lerp_option(a.azimuth, b.azimuth),
+        time: lerp_option(a.time, b.time),
+    }
+}
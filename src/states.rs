@@ -1,28 +1,173 @@
 use std::default::Default;
 
-#[derive(Clone)]
+use imgui::ImString;
+
+use bristle;
+use build_up::BuildUpSettings;
+use curve::PressureResponse;
+use history::History;
+use preset;
+use preset::BrushPreset;
+use replay::Replay;
+use texture_generator::GeneratorParameters;
+use texture_set;
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct StrokeAnchor {
     pub pos: [f32; 2],
 
     // Range from 0.0 to 1.0
     pub pressure: f32,
+
+    // Angle between pen and canvas normal in radians, no input backend reports it yet.
+    #[serde(default)]
+    pub tilt: Option<f32>,
+    // Direction pen leans to in radians, measured from x axis of canvas.
+    #[serde(default)]
+    pub azimuth: Option<f32>,
+
+    // Seconds since stroke started when anchor was captured, unknown for documents before
+    // version 8.
+    #[serde(default)]
+    pub time: Option<f32>,
 }
 
 impl StrokeAnchor {
     pub fn new(pos: &[f32; 2], pressure: f32) -> Self {
-        assert!(pressure >= 0.0 && pressure <= 1.0);
+        assert!((0.0..=1.0).contains(&pressure));
 
         Self {
             pos: *pos,
-            pressure: pressure,
+            pressure,
+            tilt: None,
+            azimuth: None,
+            time: None,
         }
     }
+
+    pub fn with_time(mut self, time: f32) -> Self {
+        self.time = Some(time);
+        self
+    }
+}
+
+/// Part of the brush a stroke is drawn with, snapshotted into the stroke so that changing the
+/// brush later does not restyle strokes already drawn.
+///
+/// Render mode is left out on purpose. Black & white and colored strokes are painted into
+/// different textures of their layer, so it is a property of the whole layer, see
+/// `LayerProperties`, and every stroke takes the mode of the layer it is in.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct StrokeBrush {
+    pub max_brush_width: f32,
+
+    pub initial_ink_quantity: f32,
+    pub ink_quantity_friction: f32,
+
+    pub pressure_response: PressureResponse,
+
+    // Name of brush texture set, see `texture_set`.
+    #[serde(default = "texture_set::default_texture_set")]
+    pub texture_set: String,
+
+    // Black & white strokes are painted by this many bristles, see `bristle`. 0 paints them
+    // with one solid tip, as every stroke before bristles were added.
+    #[serde(default)]
+    pub bristle_count: u32,
+    // How close bristles bunch together under light pressure, 0 keeps them spread.
+    #[serde(default)]
+    pub bristle_spread: f32,
+    // Fraction of ink left below which bristles split into clumps.
+    #[serde(default)]
+    pub bristle_split: f32,
 }
 
-#[derive(Clone)]
+impl StrokeBrush {
+    /// Current brush of `states`, which new strokes are drawn with.
+    pub fn from_states(states: &States) -> Self {
+        StrokeBrush {
+            max_brush_width: states.max_brush_width,
+            initial_ink_quantity: states.initial_ink_quantity,
+            ink_quantity_friction: states.ink_quantity_friction,
+            pressure_response: states.pressure_response.clone(),
+            texture_set: states.texture_set.clone(),
+            bristle_count: states.bristle_count,
+            bristle_spread: states.bristle_spread,
+            bristle_split: states.bristle_split,
+        }
+    }
+
+    /// Make this the brush new strokes are drawn with.
+    pub fn apply_to(&self, states: &mut States) {
+        states.max_brush_width = self.max_brush_width;
+        states.initial_ink_quantity = self.initial_ink_quantity;
+        states.ink_quantity_friction = self.ink_quantity_friction;
+        states.pressure_response = self.pressure_response.clone();
+        states.texture_set = self.texture_set.clone();
+        states.bristle_count = self.bristle_count;
+        states.bristle_spread = self.bristle_spread;
+        states.bristle_split = self.bristle_split;
+    }
+
+    /// Name and value of the first setting this brush can not paint with, e.g. from a hand
+    /// edited file.
+    pub fn invalid_value(&self) -> Option<(&'static str, f32)> {
+        let checks = [("max brush width", self.max_brush_width, self.max_brush_width > 0.0),
+                      ("initial ink quantity",
+                       self.initial_ink_quantity,
+                       self.initial_ink_quantity >= 0.0),
+                      ("ink quantity friction",
+                       self.ink_quantity_friction,
+                       self.ink_quantity_friction >= 0.0),
+                      ("bristle count",
+                       self.bristle_count as f32,
+                       self.bristle_count <= bristle::MAX_BRISTLE_COUNT),
+                      ("bristle spread",
+                       self.bristle_spread,
+                       (0.0..=1.0).contains(&self.bristle_spread)),
+                      ("bristle split",
+                       self.bristle_split,
+                       (0.0..=1.0).contains(&self.bristle_split))];
+
+        checks.iter()
+            .find(|&&(_, value, valid)| !valid || !value.is_finite())
+            .map(|&(name, value, _)| (name, value))
+    }
+}
+
+impl Default for StrokeBrush {
+    fn default() -> Self {
+        StrokeBrush {
+            max_brush_width: 15.,
+            initial_ink_quantity: 100.,
+            ink_quantity_friction: 1.,
+            pressure_response: PressureResponse::default(),
+            texture_set: texture_set::default_texture_set(),
+            bristle_count: 0,
+            bristle_spread: 0.5,
+            bristle_split: 0.3,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct OneStroke {
     pub color: [f32; 4],
     pub anchors: Vec<StrokeAnchor>,
+
+    // Missing in documents before version 4, filled in from the document brush on load.
+    #[serde(default)]
+    pub brush: StrokeBrush,
+
+    // Ink left in brush reservoir when stroke started, see `ink`. `None` starts with a full
+    // brush, as every stroke did before documents version 7.
+    #[serde(default)]
+    pub ink_load: Option<f32>,
+
+    // Seconds since unix epoch when stroke started, orders strokes of every layer for
+    // `replay`. Unknown for documents before version 9.
+    #[serde(default)]
+    pub start_time: Option<f64>,
 }
 
 impl OneStroke {
@@ -35,19 +180,198 @@ impl OneStroke {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum RenderMode {
     BlackAndWhite,
     Colored,
 }
 
-pub struct States {
+/// How a layer is combined with layers below it, see `shaders/layer_composite.fs`.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BlendMode {
+    Normal,
+    Multiply,
+    Screen,
+    Darken,
+    Lighten,
+}
+
+/// Everything about a layer except its strokes.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct LayerProperties {
+    pub name: String,
+
+    pub visible: bool,
+    // Locked layers can not be drawn on or edited.
+    pub locked: bool,
+
+    // Range from 0.0 to 1.0
+    pub opacity: f32,
+    pub blend_mode: BlendMode,
     pub render_mode: RenderMode,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Layer {
+    pub properties: LayerProperties,
+    pub strokes: Vec<OneStroke>,
+}
+
+impl Layer {
+    pub fn new(name: String, render_mode: RenderMode) -> Self {
+        Layer {
+            properties: LayerProperties {
+                name,
+                visible: true,
+                locked: false,
+                opacity: 1.0,
+                blend_mode: BlendMode::Normal,
+                render_mode,
+            },
+            strokes: Vec::new(),
+        }
+    }
+
+    /// Whether strokes may be added to or edited on this layer.
+    pub fn is_editable(&self) -> bool {
+        self.properties.visible && !self.properties.locked
+    }
+}
+
+/// Every parameter affecting how strokes are rendered.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct BrushParameters {
+    pub max_recording_cooldown: f32,
+
+    pub initial_ink_quantity: f32,
+    pub ink_quantity_friction: f32,
+
+    pub max_brush_width: f32,
+
+    pub stroke_line_radius: f32,
+    pub stroke_interpolation_accuracy: f32,
+    pub stroke_speed_factor: f32,
+    #[serde(default = "default_paper_absorbency")]
+    pub paper_absorbency: f32,
+
+    #[serde(default)]
+    pub pressure_response: PressureResponse,
+    #[serde(default = "texture_set::default_texture_set")]
+    pub texture_set: String,
+    #[serde(default)]
+    pub bristle_count: u32,
+    #[serde(default)]
+    pub bristle_spread: f32,
+    #[serde(default)]
+    pub bristle_split: f32,
+
+    pub watercolor_iterations: i32,
+    pub watercolor_water_quantity: f32,
+    pub watercolor_pigment_quantity: f32,
+    pub watercolor_viscosity: f32,
+    pub watercolor_viscous_drag: f32,
+    pub watercolor_edge_darkening: f32,
+    pub watercolor_pigment_density: f32,
+    pub watercolor_staining_power: f32,
+    pub watercolor_granulation: f32,
+    pub watercolor_capillary_absorption: f32,
+}
+
+// Documents before version 8 were drawn as if paper was half absorbent.
+fn default_paper_absorbency() -> f32 {
+    0.5
+}
+
+impl BrushParameters {
+    pub fn from_states(states: &States) -> Self {
+        BrushParameters {
+            max_recording_cooldown: states.max_recording_cooldown,
+            initial_ink_quantity: states.initial_ink_quantity,
+            ink_quantity_friction: states.ink_quantity_friction,
+            max_brush_width: states.max_brush_width,
+            stroke_line_radius: states.stroke_line_radius,
+            stroke_interpolation_accuracy: states.stroke_interpolation_accuracy,
+            stroke_speed_factor: states.stroke_speed_factor,
+            paper_absorbency: states.paper_absorbency,
+            pressure_response: states.pressure_response.clone(),
+            texture_set: states.texture_set.clone(),
+            bristle_count: states.bristle_count,
+            bristle_spread: states.bristle_spread,
+            bristle_split: states.bristle_split,
+            watercolor_iterations: states.watercolor_iterations,
+            watercolor_water_quantity: states.watercolor_water_quantity,
+            watercolor_pigment_quantity: states.watercolor_pigment_quantity,
+            watercolor_viscosity: states.watercolor_viscosity,
+            watercolor_viscous_drag: states.watercolor_viscous_drag,
+            watercolor_edge_darkening: states.watercolor_edge_darkening,
+            watercolor_pigment_density: states.watercolor_pigment_density,
+            watercolor_staining_power: states.watercolor_staining_power,
+            watercolor_granulation: states.watercolor_granulation,
+            watercolor_capillary_absorption: states.watercolor_capillary_absorption,
+        }
+    }
+
+    pub fn apply_to(&self, states: &mut States) {
+        states.max_recording_cooldown = self.max_recording_cooldown;
+        states.initial_ink_quantity = self.initial_ink_quantity;
+        states.ink_quantity_friction = self.ink_quantity_friction;
+        states.max_brush_width = self.max_brush_width;
+        states.stroke_line_radius = self.stroke_line_radius;
+        states.stroke_interpolation_accuracy = self.stroke_interpolation_accuracy;
+        states.stroke_speed_factor = self.stroke_speed_factor;
+        states.paper_absorbency = self.paper_absorbency;
+        states.pressure_response = self.pressure_response.clone();
+        states.texture_set = self.texture_set.clone();
+        states.bristle_count = self.bristle_count;
+        states.bristle_spread = self.bristle_spread;
+        states.bristle_split = self.bristle_split;
+        states.watercolor_iterations = self.watercolor_iterations;
+        states.watercolor_water_quantity = self.watercolor_water_quantity;
+        states.watercolor_pigment_quantity = self.watercolor_pigment_quantity;
+        states.watercolor_viscosity = self.watercolor_viscosity;
+        states.watercolor_viscous_drag = self.watercolor_viscous_drag;
+        states.watercolor_edge_darkening = self.watercolor_edge_darkening;
+        states.watercolor_pigment_density = self.watercolor_pigment_density;
+        states.watercolor_staining_power = self.watercolor_staining_power;
+        states.watercolor_granulation = self.watercolor_granulation;
+        states.watercolor_capillary_absorption = self.watercolor_capillary_absorption;
+    }
+
+    /// Part of these parameters new strokes snapshot, see `StrokeBrush`.
+    pub fn stroke_brush(&self) -> StrokeBrush {
+        StrokeBrush {
+            max_brush_width: self.max_brush_width,
+            initial_ink_quantity: self.initial_ink_quantity,
+            ink_quantity_friction: self.ink_quantity_friction,
+            pressure_response: self.pressure_response.clone(),
+            texture_set: self.texture_set.clone(),
+            bristle_count: self.bristle_count,
+            bristle_spread: self.bristle_spread,
+            bristle_split: self.bristle_split,
+        }
+    }
+}
+
+pub struct States {
+    // Size of canvas, strokes are recorded in this coordinate space.
+    pub canvas_size: [u32; 2],
+    // Edited by imgui, applied to window on demand.
+    pub canvas_size_input: [i32; 2],
+    pub need_resize_canvas: bool,
 
     pub is_recording_trajectory: bool,
     pub current_recording_cooldown: f32,
     pub max_recording_cooldown: f32,
 
+    // Guess pressure from mouse speed, tablet pressure is always used when available.
+    pub simulate_pressure: bool,
+
+    // Pointer has to move this far from last anchor before a new one is recorded.
+    pub min_anchor_distance: f32,
+    // Tolerance of simplification run on committed strokes, 0 keeps every anchor.
+    pub simplify_tolerance: f32,
+
+    // Brush new strokes are drawn with, see `StrokeBrush`.
     pub initial_ink_quantity: f32,
     pub ink_quantity_friction: f32,
 
@@ -57,8 +381,38 @@ pub struct States {
     pub stroke_interpolation_accuracy: f32,
     pub stroke_speed_factor: f32,
 
+    // How much ink paper draws out of brush, from 0 to 1, see `ink::segment_cost`.
+    pub paper_absorbency: f32,
+
+    pub pressure_response: PressureResponse,
+    pub texture_set: String,
+    pub bristle_count: u32,
+    pub bristle_spread: f32,
+    pub bristle_split: f32,
+
+    // Watercolor simulation parameters, see Curtis et al. 1997.
+    pub watercolor_iterations: i32,
+    pub watercolor_water_quantity: f32,
+    pub watercolor_pigment_quantity: f32,
+    pub watercolor_viscosity: f32,
+    pub watercolor_viscous_drag: f32,
+    pub watercolor_edge_darkening: f32,
+    pub watercolor_pigment_density: f32,
+    pub watercolor_staining_power: f32,
+    pub watercolor_granulation: f32,
+    pub watercolor_capillary_absorption: f32,
+
     pub recording_stroke_anchors: OneStroke,
-    pub stroke_records: Vec<OneStroke>,
+    // Bottom layer first, never empty.
+    pub layers: Vec<Layer>,
+    // Index into `layers` of the layer strokes are drawn on and edited in.
+    pub active_layer: usize,
+
+    // Index into strokes of active layer, may be stale after undo, see
+    // `selection::selected_stroke`.
+    pub selected_stroke: Option<usize>,
+    // Anchor under cursor as stroke index and anchor index, only while anchors are shown.
+    pub hovered_anchor: Option<(usize, usize)>,
 
     pub show_anchors: bool,
     pub show_stroke_lines: bool,
@@ -68,31 +422,115 @@ pub struct States {
     pub show_stroke_outline_preview: bool,
 
     pub need_update_brush_preview: bool,
+
+    // Ink left in brush, carried over from one stroke to the next, see `ink`.
+    pub ink_reservoir: f32,
+    // Seconds an empty brush has to dwell in ink to be full again.
+    pub dip_refill_time: f32,
+    pub is_dipping: bool,
+
+    // Painting being replayed instead of edited, see `replay`.
+    pub replay: Option<Replay>,
+
+    pub build_up_settings: BuildUpSettings,
+    // Edited by imgui, a GIF file or a directory of PNG files.
+    pub build_up_path_input: ImString,
+    // Export needs the renderer, so it is done on next render.
+    pub need_export_build_up: bool,
+    // Outcome of last export, shown below the export button.
+    pub build_up_status: Option<String>,
+
+    pub history: History,
+
+    pub brush_presets: Vec<BrushPreset>,
+    pub selected_preset: Option<usize>,
+    // Edited by imgui.
+    pub preset_name_input: ImString,
+    pub preset_path_input: ImString,
+    pub preset_error: Option<String>,
+
+    // Found by last scan of `texture_set::TEXTURE_SET_DIR`, built-in set first.
+    pub texture_set_names: Vec<String>,
+    // Drop every loaded texture set so they are read from disk again on next render.
+    pub need_reload_texture_sets: bool,
+    pub texture_set_error: Option<String>,
+
+    pub texture_generator: GeneratorParameters,
+    // Edited by imgui.
+    pub texture_generator_name_input: ImString,
+    pub texture_export_path_input: ImString,
+
+    // Path of opened document, `None` if never saved.
+    pub document_path: Option<String>,
+    // Edited by imgui.
+    pub document_path_input: ImString,
+    pub document_error: Option<String>,
+
+    // Edited by imgui.
+    pub svg_path_input: ImString,
+    // Outlines are computed by the renderer, so export is done on next render.
+    pub need_export_svg: bool,
+    pub svg_export_status: Option<String>,
 }
 
 impl Default for States {
     fn default() -> Self {
+        let brush = StrokeBrush::default();
+
         States {
-            render_mode: RenderMode::BlackAndWhite,
+            canvas_size: [900, 900],
+            canvas_size_input: [900, 900],
+            need_resize_canvas: false,
 
             is_recording_trajectory: false,
             current_recording_cooldown: 0.,
             max_recording_cooldown: 0.033,
 
-            initial_ink_quantity: 100.,
-            ink_quantity_friction: 1.,
+            simulate_pressure: true,
 
-            max_brush_width: 15.,
+            min_anchor_distance: 3.0,
+            simplify_tolerance: 0.5,
+
+            initial_ink_quantity: brush.initial_ink_quantity,
+            ink_quantity_friction: brush.ink_quantity_friction,
+
+            max_brush_width: brush.max_brush_width,
 
             stroke_line_radius: 1.0,
             stroke_interpolation_accuracy: 10.,
             stroke_speed_factor: 2.0,
 
+            paper_absorbency: 0.5,
+
+            pressure_response: brush.pressure_response.clone(),
+            texture_set: brush.texture_set.clone(),
+            bristle_count: brush.bristle_count,
+            bristle_spread: brush.bristle_spread,
+            bristle_split: brush.bristle_split,
+
+            watercolor_iterations: 50,
+            watercolor_water_quantity: 0.5,
+            watercolor_pigment_quantity: 0.3,
+            watercolor_viscosity: 0.1,
+            watercolor_viscous_drag: 0.01,
+            watercolor_edge_darkening: 0.03,
+            watercolor_pigment_density: 0.05,
+            watercolor_staining_power: 1.0,
+            watercolor_granulation: 0.5,
+            watercolor_capillary_absorption: 0.02,
+
             recording_stroke_anchors: OneStroke {
                 color: [0.0; 4],
                 anchors: Vec::new(),
+                brush: brush.clone(),
+                ink_load: None,
+                start_time: None,
             },
-            stroke_records: Vec::new(),
+            layers: vec![Layer::new("layer 0".to_string(), RenderMode::BlackAndWhite)],
+            active_layer: 0,
+
+            selected_stroke: None,
+            hovered_anchor: None,
 
             show_anchors: true,
             show_stroke_lines: true,
@@ -102,6 +540,62 @@ impl Default for States {
             show_stroke_outline_preview: false,
 
             need_update_brush_preview: false,
+
+            ink_reservoir: brush.initial_ink_quantity,
+            dip_refill_time: 1.0,
+            is_dipping: false,
+
+            replay: None,
+
+            build_up_settings: BuildUpSettings::default(),
+            build_up_path_input: ImString::with_capacity(256),
+            need_export_build_up: false,
+            build_up_status: None,
+
+            history: History::default(),
+
+            brush_presets: preset::builtin_presets(),
+            selected_preset: None,
+            preset_name_input: ImString::with_capacity(64),
+            preset_path_input: ImString::with_capacity(256),
+            preset_error: None,
+
+            texture_set_names: texture_set::available_texture_sets(),
+            need_reload_texture_sets: false,
+            texture_set_error: None,
+
+            texture_generator: GeneratorParameters::default(),
+            texture_generator_name_input: ImString::with_capacity(64),
+            texture_export_path_input: ImString::with_capacity(256),
+
+            document_path: None,
+            document_path_input: ImString::with_capacity(256),
+            document_error: None,
+
+            svg_path_input: ImString::with_capacity(256),
+            need_export_svg: false,
+            svg_export_status: None,
         }
     }
 }
+
+impl States {
+    pub fn active_layer(&self) -> &Layer {
+        &self.layers[self.active_layer]
+    }
+
+    /// Strokes of active layer.
+    pub fn strokes(&self) -> &[OneStroke] {
+        &self.active_layer().strokes
+    }
+
+    /// Switch layer being edited, selection does not carry over to another layer.
+    pub fn set_active_layer(&mut self, index: usize) {
+        if index != self.active_layer {
+            self.selected_stroke = None;
+            self.hovered_anchor = None;
+        }
+
+        self.active_layer = index.min(self.layers.len() - 1);
+    }
+}
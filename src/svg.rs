@@ -0,0 +1,353 @@
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::f32;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use glium::backend::Facade;
+use vecmath;
+
+use renderer::{OutlineShape, Renderer};
+use simplify;
+use states::{BlendMode, OneStroke, RenderMode, States};
+
+/// Distance between samples outlines are traced at, in canvas units.
+const SAMPLE_SPACING: f32 = 0.5;
+/// Outline points closer than this to a straight line through their neighbours are dropped.
+const SIMPLIFY_TOLERANCE: f32 = 0.05;
+/// Samples of distance field around every shape, so each side of an outline is sampled.
+const SHAPE_MARGIN: usize = 2;
+
+/// Signed distance to shapes of one stroke, negative inside, sampled on a grid.
+struct DistanceField {
+    origin: [f32; 2],
+    // Number of samples along x and y.
+    size: [usize; 2],
+    // Row by row, far from every shape is `f32::MAX`.
+    values: Vec<f32>,
+}
+
+impl DistanceField {
+    fn value(&self, x: usize, y: usize) -> f32 {
+        self.values[y * self.size[0] + x]
+    }
+
+    fn position(&self, x: usize, y: usize) -> [f32; 2] {
+        [self.origin[0] + x as f32 * SAMPLE_SPACING, self.origin[1] + y as f32 * SAMPLE_SPACING]
+    }
+}
+
+fn bounding_box(shape: &OutlineShape) -> ([f32; 2], [f32; 2]) {
+    match *shape {
+        OutlineShape::Circle { center, radius } => {
+            ([center[0] - radius, center[1] - radius], [center[0] + radius, center[1] + radius])
+        }
+        OutlineShape::Quad(ref corners) => {
+            let mut min = corners[0];
+            let mut max = corners[0];
+            for corner in &corners[1..] {
+                min = [min[0].min(corner[0]), min[1].min(corner[1])];
+                max = [max[0].max(corner[0]), max[1].max(corner[1])];
+            }
+
+            (min, max)
+        }
+    }
+}
+
+fn distance_to_segment(p: [f32; 2], a: [f32; 2], b: [f32; 2]) -> f32 {
+    let ab = vecmath::vec2_sub(b, a);
+    let ap = vecmath::vec2_sub(p, a);
+
+    let len_sq = vecmath::vec2_square_len(ab);
+    let t = if len_sq > 0.0 {
+        (vecmath::vec2_dot(ap, ab) / len_sq).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+
+    vecmath::vec2_len(vecmath::vec2_sub(ap, vecmath::vec2_scale(ab, t)))
+}
+
+fn signed_distance(shape: &OutlineShape, p: [f32; 2]) -> f32 {
+    match *shape {
+        OutlineShape::Circle { center, radius } => {
+            vecmath::vec2_len(vecmath::vec2_sub(p, center)) - radius
+        }
+        OutlineShape::Quad(ref corners) => {
+            let mut distance = f32::MAX;
+            let (mut left, mut right) = (false, false);
+            for i in 0..4 {
+                let (a, b) = (corners[i], corners[(i + 1) % 4]);
+                distance = distance.min(distance_to_segment(p, a, b));
+
+                let side = vecmath::vec2_cross(vecmath::vec2_sub(b, a), vecmath::vec2_sub(p, a));
+                left |= side > 0.0;
+                right |= side < 0.0;
+            }
+
+            // Quad is convex, so points on the same side of every edge are inside.
+            if left && right { distance } else { -distance }
+        }
+    }
+}
+
+/// Distance field of the union of `shapes`, `None` if there are none.
+///
+/// Every shape only updates samples around itself, samples next to an outline are always
+/// updated by the shape it belongs to.
+fn distance_field(shapes: &[OutlineShape]) -> Option<DistanceField> {
+    let boxes: Vec<_> = shapes.iter().map(bounding_box).collect();
+    let (first_min, first_max) = match boxes.first() {
+        Some(&bounds) => bounds,
+        None => return None,
+    };
+
+    let (min, max) = boxes.iter().fold((first_min, first_max), |(min, max), &(lo, hi)| {
+        ([min[0].min(lo[0]), min[1].min(lo[1])], [max[0].max(hi[0]), max[1].max(hi[1])])
+    });
+
+    // Outermost samples stay outside of every shape, so every outline is closed.
+    let padding = (SHAPE_MARGIN + 1) as f32 * SAMPLE_SPACING;
+    let origin = [min[0] - padding, min[1] - padding];
+    let size = [((max[0] - min[0] + 2.0 * padding) / SAMPLE_SPACING).ceil() as usize + 1,
+                ((max[1] - min[1] + 2.0 * padding) / SAMPLE_SPACING).ceil() as usize + 1];
+
+    let mut field = DistanceField {
+        origin,
+        size,
+        values: vec![f32::MAX; size[0] * size[1]],
+    };
+
+    for (shape, &(lo, hi)) in shapes.iter().zip(&boxes) {
+        let sample_range = |lo: f32, hi: f32, axis: usize| {
+            let first = ((lo - origin[axis]) / SAMPLE_SPACING).floor() as usize - SHAPE_MARGIN;
+            let last = ((hi - origin[axis]) / SAMPLE_SPACING).ceil() as usize + SHAPE_MARGIN;
+            first..last.min(size[axis] - 1) + 1
+        };
+
+        for y in sample_range(lo[1], hi[1], 1) {
+            for x in sample_range(lo[0], hi[0], 0) {
+                let distance = signed_distance(shape, field.position(x, y));
+                let value = &mut field.values[y * size[0] + x];
+                *value = value.min(distance);
+            }
+        }
+    }
+
+    Some(field)
+}
+
+/// Edge between two neighbouring samples, `(0, x, y)` runs along x from sample `(x, y)`,
+/// `(1, x, y)` along y.
+type GridEdge = (u8, usize, usize);
+
+/// Closed outlines where `field` crosses zero, by marching squares.
+///
+/// Outlines of holes are traced too, so they have to be filled with the even-odd rule.
+fn trace_outlines(field: &DistanceField) -> Vec<Vec<[f32; 2]>> {
+    // Where outline crosses every edge and which edges it continues to.
+    let mut crossings: HashMap<GridEdge, ([f32; 2], Vec<GridEdge>)> = HashMap::new();
+    let mut order: Vec<GridEdge> = Vec::new();
+
+    let crossing = |edge: GridEdge| {
+        let (axis, x, y) = edge;
+        let (x1, y1) = if axis == 0 { (x + 1, y) } else { (x, y + 1) };
+
+        let (a, b) = (field.value(x, y), field.value(x1, y1));
+        let t = a / (a - b);
+        let (pa, pb) = (field.position(x, y), field.position(x1, y1));
+
+        [pa[0] + (pb[0] - pa[0]) * t, pa[1] + (pb[1] - pa[1]) * t]
+    };
+
+    for y in 0..field.size[1] - 1 {
+        for x in 0..field.size[0] - 1 {
+            // Corners and the edges between them, counterclockwise from `(x, y)`.
+            let inside = [field.value(x, y) < 0.0,
+                          field.value(x + 1, y) < 0.0,
+                          field.value(x + 1, y + 1) < 0.0,
+                          field.value(x, y + 1) < 0.0];
+            let edges = [(0, x, y), (1, x + 1, y), (0, x, y + 1), (1, x, y)];
+
+            let crossed: Vec<usize> = (0..4)
+                .filter(|&i| inside[i] != inside[(i + 1) % 4])
+                .collect();
+            let pairs = match crossed.len() {
+                2 => vec![(crossed[0], crossed[1])],
+                // Saddle, center decides whether the two inside corners are connected.
+                4 => {
+                    let center = (field.value(x, y) + field.value(x + 1, y) +
+                                  field.value(x + 1, y + 1) + field.value(x, y + 1)) /
+                                 4.0;
+                    if inside[1] == (center < 0.0) {
+                        vec![(3, 0), (1, 2)]
+                    } else {
+                        vec![(0, 1), (2, 3)]
+                    }
+                }
+                _ => Vec::new(),
+            };
+
+            for (a, b) in pairs {
+                let (a, b) = (edges[a], edges[b]);
+                for &(edge, other) in &[(a, b), (b, a)] {
+                    if let Entry::Vacant(entry) = crossings.entry(edge) {
+                        entry.insert((crossing(edge), Vec::new()));
+                        order.push(edge);
+                    }
+                    crossings.get_mut(&edge).unwrap().1.push(other);
+                }
+            }
+        }
+    }
+
+    // Every crossing joins exactly two cells, so following unvisited neighbours goes around
+    // one outline.
+    let mut visited = HashSet::new();
+    let mut outlines = Vec::new();
+    for &start in &order {
+        if visited.contains(&start) {
+            continue;
+        }
+
+        let mut outline = Vec::new();
+        let mut current = Some(start);
+        while let Some(edge) = current {
+            visited.insert(edge);
+
+            let &(pos, ref neighbours) = &crossings[&edge];
+            outline.push(pos);
+            current = neighbours.iter().cloned().find(|neighbour| !visited.contains(neighbour));
+        }
+
+        if outline.len() >= 3 {
+            outlines.push(outline);
+        }
+    }
+
+    outlines
+}
+
+/// Outline of the union of `shapes` as closed polylines in canvas units, holes included.
+pub fn outline_paths(shapes: &[OutlineShape]) -> Vec<Vec<[f32; 2]>> {
+    let field = match distance_field(shapes) {
+        Some(field) => field,
+        None => return Vec::new(),
+    };
+
+    trace_outlines(&field)
+        .iter()
+        .map(|outline| simplify::simplify_outline(outline, SIMPLIFY_TOLERANCE))
+        .filter(|outline| outline.len() >= 3)
+        .collect()
+}
+
+/// SVG path data of `outlines`, one closed subpath each.
+fn path_data(outlines: &[Vec<[f32; 2]>]) -> String {
+    let mut data = String::new();
+    for outline in outlines {
+        for (index, point) in outline.iter().enumerate() {
+            let command = if index == 0 { "M" } else { "L" };
+            write!(data, "{}{:.2} {:.2} ", command, point[0], point[1]).unwrap();
+        }
+        data.push('Z');
+    }
+
+    data
+}
+
+/// `rgb` as `#rrggbb`, every channel from 0 to 1.
+fn hex_color(rgb: &[f32]) -> String {
+    let channel = |c: f32| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+
+    format!("#{:02x}{:02x}{:02x}", channel(rgb[0]), channel(rgb[1]), channel(rgb[2]))
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn blend_mode_name(blend_mode: BlendMode) -> &'static str {
+    match blend_mode {
+        BlendMode::Normal => "normal",
+        BlendMode::Multiply => "multiply",
+        BlendMode::Screen => "screen",
+        BlendMode::Darken => "darken",
+        BlendMode::Lighten => "lighten",
+    }
+}
+
+/// Fill color and opacity of `stroke`.
+///
+/// Black & white layers are painted with black ink. Opacity follows pressure as in
+/// `render_stroke_outline_on`, averaged over the whole stroke as a path has only one.
+fn stroke_fill(stroke: &OneStroke, render_mode: RenderMode) -> (String, f32) {
+    let opacity_curve = &stroke.brush.pressure_response.opacity;
+    let pressure_opacity = stroke.anchors
+        .iter()
+        .map(|anchor| opacity_curve.evaluate(anchor.pressure))
+        .sum::<f32>() / stroke.anchors.len().max(1) as f32;
+
+    match render_mode {
+        RenderMode::BlackAndWhite => ("#000000".to_string(), pressure_opacity),
+        RenderMode::Colored => (hex_color(&stroke.color), stroke.color[3] * pressure_opacity),
+    }
+}
+
+/// Write ink outlines of every visible layer into an SVG file, one path per stroke.
+///
+/// Outlines are the union of the shapes `render_stroke_ink_outline_tex` draws, so texture,
+/// wipe and diffusion are not part of them. Layers become groups with their opacity and blend
+/// mode.
+pub fn export<F: Facade, P: AsRef<Path>>(renderer: &Renderer<F>,
+                                         states: &States,
+                                         path: P)
+                                         -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let (w, h) = (states.canvas_size[0], states.canvas_size[1]);
+
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(out,
+             concat!(r#"<svg xmlns="http://www.w3.org/2000/svg" "#,
+                     r#"width="{}" height="{}" viewBox="0 0 {} {}">"#),
+             w,
+             h,
+             w,
+             h)?;
+
+    for layer in states.layers.iter().filter(|layer| layer.properties.visible) {
+        let properties = &layer.properties;
+        writeln!(out,
+                 r#"  <g opacity="{}" style="mix-blend-mode:{}">"#,
+                 properties.opacity,
+                 blend_mode_name(properties.blend_mode))?;
+        writeln!(out, "    <title>{}</title>", escape_xml(&properties.name))?;
+
+        for stroke in &layer.strokes {
+            let outlines = outline_paths(&renderer.stroke_outline_shapes(states, stroke));
+            if outlines.is_empty() {
+                continue;
+            }
+
+            let (fill, opacity) = stroke_fill(stroke, properties.render_mode);
+            writeln!(out,
+                     r#"    <path d="{}" fill="{}" fill-opacity="{:.3}" fill-rule="evenodd"/>"#,
+                     path_data(&outlines),
+                     fill,
+                     opacity)?;
+        }
+
+        writeln!(out, "  </g>")?;
+    }
+
+    writeln!(out, "</svg>")?;
+
+    Ok(())
+}
@@ -0,0 +1,196 @@
+use std::f32::consts::PI;
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+
+use image::{Rgba, RgbaImage};
+use serde_json;
+
+use texture_set::TextureSetError;
+
+/// Largest `GeneratorParameters::size` loaded from a file, as levels are generated on every
+/// load.
+const MAX_SIZE: u32 = 1024;
+/// Most levels loaded from a file, same as the generator panel offers.
+pub const MAX_LEVEL_COUNT: u32 = 16;
+
+/// Parameters of a procedurally generated texture set.
+///
+/// Saved as `<name>.json` in `texture_set::TEXTURE_SET_DIR`, levels are generated again on load
+/// so the same parameters always give the same set.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct GeneratorParameters {
+    // Width and height of every level, in pixels.
+    pub size: u32,
+    pub level_count: u32,
+
+    // Number of bristle streaks across one tile.
+    pub bristle_count: u32,
+    // 0 is a wet brush leaving solid streaks, 1 a dry one breaking up into specks.
+    pub dryness: f32,
+    // Length of ink breakups along a bristle, in pixels.
+    pub noise_scale: f32,
+    // Angle of bristle streaks in degrees, counterclockwise from x axis.
+    pub fiber_direction: f32,
+
+    pub seed: u32,
+}
+
+impl Default for GeneratorParameters {
+    fn default() -> Self {
+        // Close to the built-in set.
+        GeneratorParameters {
+            size: 64,
+            level_count: 5,
+            bristle_count: 24,
+            dryness: 0.5,
+            noise_scale: 8.0,
+            fiber_direction: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+/// Integer hash mapped into [0, 1].
+fn hash(seed: u32, a: i32, b: i32) -> f32 {
+    let mut h = seed.wrapping_mul(0x9e3779b1) ^ (a as u32).wrapping_mul(0x85ebca6b) ^
+                (b as u32).wrapping_mul(0xc2b2ae35);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846ca68b);
+    h ^= h >> 16;
+
+    h as f32 / u32::MAX as f32
+}
+
+/// Smooth 1D value noise along bristle `bristle`.
+fn bristle_noise(seed: u32, bristle: i32, t: f32) -> f32 {
+    let i = t.floor();
+    let f = t - i;
+    let f = f * f * (3.0 - 2.0 * f);
+
+    let a = hash(seed, bristle, i as i32);
+    let b = hash(seed, bristle, i as i32 + 1);
+    a + (b - a) * f
+}
+
+/// How much ink lands on `(x, y)`, not tileable yet.
+fn ink_density(params: &GeneratorParameters, x: f32, y: f32) -> f32 {
+    let angle = params.fiber_direction * PI / 180.0;
+    let along = x * angle.cos() + y * angle.sin();
+    let across = -x * angle.sin() + y * angle.cos();
+
+    let bristle_width = params.size as f32 / params.bristle_count.max(1) as f32;
+    let bristle_pos = across / bristle_width;
+    let bristle = bristle_pos.floor();
+
+    // Bristles hold most ink at their center and leave thin gaps between each other.
+    let offset = 2.0 * (bristle_pos - bristle) - 1.0;
+    let profile = 1.0 - offset * offset;
+
+    // Some bristles carry less ink, and every bristle runs dry in places along its way.
+    let load = hash(params.seed, bristle as i32, -1);
+    let breakup = bristle_noise(params.seed, bristle as i32, along / params.noise_scale.max(1.0));
+
+    let dryness = params.dryness.clamp(0.0, 1.0);
+    profile * (1.0 - dryness + dryness * load) * (1.0 - dryness + dryness * breakup)
+}
+
+/// `ink_density` cross-faded with its copies one tile away, so levels repeat seamlessly.
+fn tileable_ink_density(params: &GeneratorParameters, x: f32, y: f32) -> f32 {
+    let size = params.size as f32;
+    let (wx, wy) = (x / size, y / size);
+
+    (1.0 - wx) * (1.0 - wy) * ink_density(params, x, y) +
+    wx * (1.0 - wy) * ink_density(params, x - size, y) +
+    (1.0 - wx) * wy * ink_density(params, x, y - size) +
+    wx * wy * ink_density(params, x - size, y - size)
+}
+
+/// Fraction of pixels covered by `level`, dry brushes leave more paper on low levels.
+fn coverage(dryness: f32, level: u32, level_count: u32) -> f32 {
+    let exponent = 1.0 + 2.0 * (1.0 - dryness.clamp(0.0, 1.0));
+    let t = (level + 1) as f32 / level_count as f32;
+
+    1.0 - (1.0 - t).powf(exponent)
+}
+
+/// Levels in the same layout as texture set files, lowest level first.
+///
+/// Covered pixels are opaque black, every level covers the pixels of the level below it.
+pub fn generate(params: &GeneratorParameters) -> Vec<RgbaImage> {
+    let size = params.size.max(1);
+    let pixel_count = (size * size) as usize;
+
+    let density: Vec<f32> = (0..pixel_count)
+        .map(|i| {
+            let (x, y) = (i as u32 % size, i as u32 / size);
+            tileable_ink_density(params, x as f32 + 0.5, y as f32 + 0.5)
+        })
+        .collect();
+
+    // Pixels receiving most ink are covered first.
+    let mut order: Vec<usize> = (0..pixel_count).collect();
+    order.sort_by(|&a, &b| density[b].partial_cmp(&density[a]).unwrap());
+    let mut rank = vec![0; pixel_count];
+    for (r, &i) in order.iter().enumerate() {
+        rank[i] = r;
+    }
+
+    let level_count = params.level_count.max(1);
+    (0..level_count)
+        .map(|level| {
+            let covered = coverage(params.dryness, level, level_count) * pixel_count as f32;
+            let covered = covered.round() as usize;
+
+            RgbaImage::from_fn(size, size, |x, y| {
+                if rank[(y * size + x) as usize] < covered {
+                    Rgba([0, 0, 0, 255])
+                } else {
+                    Rgba([0, 0, 0, 0])
+                }
+            })
+        })
+        .collect()
+}
+
+/// Parameters saved in `path`, size and level count of hand-edited files are checked so
+/// generating levels stays within memory.
+pub fn load_parameters<P: AsRef<Path>>(path: P) -> Result<GeneratorParameters, TextureSetError> {
+    let params: GeneratorParameters = serde_json::from_reader(File::open(path)?)?;
+
+    if params.size == 0 || params.size > MAX_SIZE {
+        return Err(TextureSetError::OutOfRange("size", params.size));
+    }
+    if params.level_count == 0 || params.level_count > MAX_LEVEL_COUNT {
+        return Err(TextureSetError::OutOfRange("level count", params.level_count));
+    }
+
+    Ok(params)
+}
+
+pub fn save_parameters<P: AsRef<Path>>(params: &GeneratorParameters,
+                                       path: P)
+                                       -> Result<(), TextureSetError> {
+    if let Some(dir) = path.as_ref().parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut out = File::create(path)?;
+    serde_json::to_writer_pretty(&mut out, params)?;
+
+    Ok(())
+}
+
+/// Write `levels` into `dir` as `level0.png`, `level1.png`, ..., loadable as texture set.
+pub fn export_png<P: AsRef<Path>>(levels: &[RgbaImage], dir: P) -> Result<(), TextureSetError> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+
+    for (index, level) in levels.iter().enumerate() {
+        level.save(dir.join(format!("level{}.png", index)))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,194 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Cursor;
+use std::path::Path;
+
+use image;
+use image::{ImageFormat, RgbaImage};
+use serde_json;
+
+use texture_generator;
+
+/// Name of texture set compiled into the binary, always available.
+pub const BUILTIN_TEXTURE_SET: &str = "default";
+
+/// Texture sets are looked up here, either as a directory `<name>/` holding `level0.png`,
+/// `level1.png`, ..., as a single `<name>.png` with every level side by side as square tiles, or
+/// as `<name>.json` holding `texture_generator::GeneratorParameters`.
+pub const TEXTURE_SET_DIR: &str = "assets/brushes";
+
+/// Lowest level is used where the brush is almost out of ink.
+pub fn builtin_levels() -> Vec<RgbaImage> {
+    let load = |bytes: &[u8]| {
+        image::load(Cursor::new(bytes), ImageFormat::Png)
+            .unwrap()
+            .to_rgba8()
+    };
+
+    vec![load(include_bytes!("assets/level0.png")),
+         load(include_bytes!("assets/level1.png")),
+         load(include_bytes!("assets/level2.png")),
+         load(include_bytes!("assets/level3.png")),
+         load(include_bytes!("assets/level4.png"))]
+}
+
+pub fn default_texture_set() -> String {
+    BUILTIN_TEXTURE_SET.to_string()
+}
+
+pub enum TextureSetError {
+    Io(io::Error),
+    Image(image::ImageError),
+    Parse(serde_json::Error),
+    NoLevels,
+    // Every level has to be as large as the first one.
+    SizeMismatch(u32, u32),
+    // Generator parameter with its value, outside of what `texture_generator` accepts.
+    OutOfRange(&'static str, u32),
+}
+
+impl fmt::Display for TextureSetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TextureSetError::Io(ref err) => write!(f, "io error: {}", err),
+            TextureSetError::Image(ref err) => write!(f, "image error: {}", err),
+            TextureSetError::Parse(ref err) => write!(f, "parse error: {}", err),
+            TextureSetError::NoLevels => write!(f, "no level textures found"),
+            TextureSetError::SizeMismatch(w, h) => {
+                write!(f, "level of size {}x{} differs from first level", w, h)
+            }
+            TextureSetError::OutOfRange(name, value) => {
+                write!(f, "{} of {} is out of range", name, value)
+            }
+        }
+    }
+}
+
+impl fmt::Debug for TextureSetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl From<io::Error> for TextureSetError {
+    fn from(err: io::Error) -> Self {
+        TextureSetError::Io(err)
+    }
+}
+
+impl From<image::ImageError> for TextureSetError {
+    fn from(err: image::ImageError) -> Self {
+        TextureSetError::Image(err)
+    }
+}
+
+impl From<serde_json::Error> for TextureSetError {
+    fn from(err: serde_json::Error) -> Self {
+        TextureSetError::Parse(err)
+    }
+}
+
+/// Names of every texture set, built-in set first.
+pub fn available_texture_sets() -> Vec<String> {
+    let mut names = vec![default_texture_set()];
+
+    let entries = match fs::read_dir(TEXTURE_SET_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return names,
+    };
+
+    let mut found: Vec<String> = entries.filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let path = entry.path();
+            let is_file_set = path.extension().is_some_and(|ext| ext == "png" || ext == "json");
+            if !path.is_dir() && !is_file_set {
+                return None;
+            }
+
+            path.file_stem().and_then(|stem| stem.to_str()).map(|stem| stem.to_string())
+        })
+        .filter(|name| name != BUILTIN_TEXTURE_SET)
+        .collect();
+    found.sort();
+    found.dedup();
+
+    names.extend(found);
+    names
+}
+
+/// Level images of texture set `name`, lowest level first.
+pub fn load_levels(name: &str) -> Result<Vec<RgbaImage>, TextureSetError> {
+    if name == BUILTIN_TEXTURE_SET {
+        return Ok(builtin_levels());
+    }
+
+    let dir = Path::new(TEXTURE_SET_DIR).join(name);
+    let strip = dir.with_extension("png");
+    let levels = if dir.is_dir() {
+        load_level_files(&dir)?
+    } else if strip.is_file() {
+        split_strip(image::open(strip)?.to_rgba8())
+    } else {
+        let params = texture_generator::load_parameters(dir.with_extension("json"))?;
+        texture_generator::generate(&params)
+    };
+
+    check_levels(levels)
+}
+
+/// Read `level<N>.png` of `dir`, ordered by N.
+fn load_level_files(dir: &Path) -> Result<Vec<RgbaImage>, TextureSetError> {
+    let mut numbered = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        let number = path.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| if name.starts_with("level") && name.ends_with(".png") {
+                name["level".len()..name.len() - ".png".len()].parse::<u32>().ok()
+            } else {
+                None
+            });
+
+        if let Some(number) = number {
+            numbered.push((number, path));
+        }
+    }
+    numbered.sort_by_key(|&(number, _)| number);
+
+    let mut levels = Vec::with_capacity(numbered.len());
+    for (_, path) in numbered {
+        levels.push(image::open(path)?.to_rgba8());
+    }
+
+    Ok(levels)
+}
+
+/// Cut a strip of square tiles, as tall as the strip, into one level per tile.
+fn split_strip(mut strip: RgbaImage) -> Vec<RgbaImage> {
+    let (w, h) = strip.dimensions();
+    if h == 0 {
+        return Vec::new();
+    }
+
+    (0..w / h)
+        .map(|i| image::imageops::crop(&mut strip, i * h, 0, h, h).to_image())
+        .collect()
+}
+
+fn check_levels(levels: Vec<RgbaImage>) -> Result<Vec<RgbaImage>, TextureSetError> {
+    let size = match levels.first() {
+        Some(level) => level.dimensions(),
+        None => return Err(TextureSetError::NoLevels),
+    };
+
+    for level in &levels {
+        let (w, h) = level.dimensions();
+        if (w, h) != size {
+            return Err(TextureSetError::SizeMismatch(w, h));
+        }
+    }
+
+    Ok(levels)
+}
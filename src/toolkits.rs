@@ -1,19 +1,37 @@
 use std::default::Default;
+use std::path::Path;
 use std::time::Instant;
 
 use glium::Surface;
 use glium::backend::Facade;
-use glium;
 use imgui::*;
-use imgui::glium_renderer::{Renderer, RendererResult};
+use imgui::Key as ImGuiKey;
+use imgui_glium_renderer::{Renderer, RendererError};
 use piston::input::keyboard::Key;
 use piston::input::mouse::MouseButton;
-use piston::input::{Input, Button, Motion};
+use piston::input::{Input, Button, ButtonArgs, ButtonState, Motion, RenderArgs};
 use rand;
 use rand::Rng;
 
+use bristle;
+use build_up::{FrameFormat, FrameTiming};
+use curve::PressureCurve;
+use document;
+use history;
+use history::Command;
+use ink;
+use preset;
+use preset::BrushPreset;
+use replay::Replay;
+use selection;
+use simplify;
+use states::BlendMode;
+use states::BrushParameters;
+use states::Layer;
 use states::States;
 use states::RenderMode;
+use texture_generator;
+use texture_set;
 
 mod gui {
     use super::*;
@@ -26,16 +44,42 @@ mod gui {
             build_edit_panel(ui, states);
         }
         if ui.collapsing_header(im_str!("Parameters")).build() {
-            build_parameters_control_panel(ui, states);
+            record_parameters_change(states, |states| build_parameters_control_panel(ui, states));
         }
         if ui.collapsing_header(im_str!("View")).build() {
-            build_view_panel(ui, states);
+            record_parameters_change(states, |states| build_view_panel(ui, states));
         }
         if ui.collapsing_header(im_str!("Brush")).build() {
             build_brush_panel(ui, states);
         }
+        if ui.collapsing_header(im_str!("Selection")).build() {
+            build_selection_panel(ui, states);
+        }
+        if ui.collapsing_header(im_str!("Watercolor")).build() {
+            record_parameters_change(states, |states| build_watercolor_panel(ui, states));
+        }
 
         build_stroke_manipulation_panel(ui, states);
+        build_history_panel(ui, states);
+        build_layers_panel(ui, states);
+        build_preset_panel(ui, states);
+        build_timeline_panel(ui, states);
+        record_parameters_change(states, |states| build_texture_set_panel(ui, states));
+        record_parameters_change(states, |states| build_pressure_curve_panel(ui, states));
+    }
+
+    /// Record every parameter modified by `build_panel` into history.
+    fn record_parameters_change<F: FnOnce(&mut States)>(states: &mut States, build_panel: F) {
+        let old = BrushParameters::from_states(states);
+        build_panel(states);
+        let new = BrushParameters::from_states(states);
+
+        if old != new {
+            states.history.record(Command::ChangeParameters {
+                old: Box::new(old),
+                new: Box::new(new),
+            });
+        }
     }
 
     fn build_hello_panel(ui: &Ui, states: &States) {
@@ -43,48 +87,145 @@ mod gui {
         ui.text(im_str!("This...is...imgui-rs!"));
         ui.separator();
 
-        let mouse_pos = ui.imgui().mouse_pos();
-        ui.text(im_str!("Mouse Position: ({:.1},{:.1})", mouse_pos.0, mouse_pos.1));
+        let mouse_pos = ui.io().mouse_pos;
+        ui.text(im_str!("Mouse Position: ({:.1},{:.1})", mouse_pos[0], mouse_pos[1]));
         ui.text(im_str!("is recording: {}", states.is_recording_trajectory));
         ui.text(im_str!("recording cooldown: {}", states.current_recording_cooldown));
-        ui.text(im_str!("recording num: {}", states.stroke_records.len()));
+        ui.text(im_str!("recording num: {}", states.strokes().len()));
     }
 
     fn build_edit_panel(ui: &Ui, states: &mut States) {
-        if ui.button(im_str!("clear all"), ImVec2::new(0., 0.)) {
-            states.need_update_brush_preview = true;
-            states.stroke_records.clear();
+        // Stroke edits only touch active layer, and never a hidden or locked one.
+        let layer = states.active_layer;
+        let editable = states.active_layer().is_editable();
+
+        if ui.button(im_str!("clear layer"), [0., 0.]) && editable &&
+           !states.strokes().is_empty() {
+            let strokes = states.strokes().to_vec();
+            history::execute(states,
+                             Command::ClearStrokes {
+                                 layer,
+                                 strokes,
+                             });
         }
 
-        if ui.button(im_str!("clear last"), ImVec2::new(0., 0.)) {
-            states.need_update_brush_preview = true;
-            states.stroke_records.pop();
+        if ui.button(im_str!("clear last"), [0., 0.]) && editable {
+            if let Some(stroke) = states.strokes().last().cloned() {
+                let index = states.strokes().len() - 1;
+                history::execute(states,
+                                 Command::DeleteStroke {
+                                     layer,
+                                     index,
+                                     stroke,
+                                 });
+            }
+        }
+
+        if ui.button(im_str!("simplify layer"), [0., 0.]) && editable {
+            let old = states.strokes().to_vec();
+            let new: Vec<_> = old.iter()
+                .map(|stroke| simplify::simplify_stroke(stroke, states.simplify_tolerance))
+                .collect();
+
+            if old != new {
+                history::execute(states,
+                                 Command::ReplaceStrokes {
+                                     layer,
+                                     old,
+                                     new,
+                                 });
+            }
+        }
+
+        if ui.button(im_str!("undo"), [0., 0.]) {
+            history::undo(states);
+        }
+
+        ui.same_line(0.);
+        if ui.button(im_str!("redo"), [0., 0.]) {
+            history::redo(states);
         }
 
-        if ui.button(im_str!("update preview"), ImVec2::new(0., 0.)) {
+        if ui.button(im_str!("update preview"), [0., 0.]) {
             states.need_update_brush_preview = true;
         }
+
+        ui.separator();
+        build_document_panel(ui, states);
     }
 
-    fn build_view_panel(ui: &Ui, states: &mut States) {
-        {
-            let mut render_mode_i32 = states.render_mode as i32;
+    fn build_document_panel(ui: &Ui, states: &mut States) {
+        ui.text(im_str!("document: {}",
+                        states.document_path.as_ref().map_or("(unsaved)", |p| p.as_str())));
+        ui.input_text(im_str!("path"), &mut states.document_path_input).build();
 
-            if ui.combo(im_str!("render mode"),
-                        &mut render_mode_i32,
-                        &[im_str!("black & white"), im_str!("colored")],
-                        10) {
+        let input_path = states.document_path_input.to_str().to_string();
 
-                states.need_update_brush_preview = true;
+        let save_to = |states: &mut States, path: String| {
+            match document::save(states, &path) {
+                Ok(_) => {
+                    states.document_path = Some(path);
+                    states.document_error = None;
+                }
+                Err(err) => states.document_error = Some(format!("failed to save: {}", err)),
             }
+        };
 
-            match render_mode_i32 {
-                0 => states.render_mode = RenderMode::BlackAndWhite,
-                1 => states.render_mode = RenderMode::Colored,
-                _ => panic!("should not happen"),
+        if ui.button(im_str!("save"), [0., 0.]) {
+            match states.document_path.clone() {
+                Some(path) => save_to(states, path),
+                None if !input_path.is_empty() => save_to(states, input_path.clone()),
+                None => states.document_error = Some("no path to save to".to_string()),
+            }
+        }
+
+        ui.same_line(0.);
+        if ui.button(im_str!("save as"), [0., 0.]) {
+            if input_path.is_empty() {
+                states.document_error = Some("no path to save to".to_string());
+            } else {
+                save_to(states, input_path.clone());
+            }
+        }
+
+        ui.same_line(0.);
+        if ui.button(im_str!("open"), [0., 0.]) {
+            match document::load(&input_path) {
+                Ok(document) => {
+                    document.apply_to(states);
+                    states.document_path = Some(input_path.clone());
+                    states.document_error = None;
+                }
+                Err(err) => states.document_error = Some(format!("failed to open: {}", err)),
             }
         }
 
+        if let Some(ref err) = states.document_error {
+            ui.text_colored([1.0, 0.3, 0.3, 1.0], im_str!("{}", err));
+        }
+
+        // Ink outlines as vector paths, e.g. for plotters.
+        ui.separator();
+        ui.input_text(im_str!("svg path"), &mut states.svg_path_input).build();
+        if ui.button(im_str!("export svg"), [0., 0.]) {
+            states.need_export_svg = true;
+        }
+
+        if let Some(ref status) = states.svg_export_status {
+            ui.text(im_str!("{}", status));
+        }
+    }
+
+    fn build_view_panel(ui: &Ui, states: &mut States) {
+        ui.input_int2(im_str!("canvas size"), &mut states.canvas_size_input).build();
+        if ui.button(im_str!("apply canvas size"), [0., 0.]) {
+            // Documents with larger canvases could not be opened again.
+            let max = document::MAX_CANVAS_SIZE as i32;
+            states.canvas_size_input[0] = states.canvas_size_input[0].clamp(1, max);
+            states.canvas_size_input[1] = states.canvas_size_input[1].clamp(1, max);
+            states.need_resize_canvas = true;
+        }
+
         ui.checkbox(im_str!("show anchors"), &mut states.show_anchors);
         ui.checkbox(im_str!("show lines"), &mut states.show_stroke_lines);
 
@@ -99,105 +240,784 @@ mod gui {
 
 
     fn build_brush_panel(ui: &Ui, states: &mut States) {
-        ui.color_edit4(im_str!("color"), &mut states.recording_stroke_anchors.color).build();
-        if ui.button(im_str!("random color"), ImVec2::new(0., 0.)) {
+        let old_color = states.recording_stroke_anchors.color;
+        if ColorEdit::new(im_str!("color"), &mut states.recording_stroke_anchors.color).build(ui) {
+            states.history.record(Command::ChangeColor {
+                layer: 0,
+                stroke_index: None,
+                old: old_color,
+                new: states.recording_stroke_anchors.color,
+            });
+        }
+
+        if ui.button(im_str!("random color"), [0., 0.]) {
             let mut rng = rand::thread_rng();
-            let rand_color = [rng.gen_range(0.0, 1.0),
-                              rng.gen_range(0.0, 1.0),
-                              rng.gen_range(0.0, 1.0),
-                              rng.gen_range(0.0, 1.0)];
-
-            states.recording_stroke_anchors.color = rand_color;
-        }
-    }
-
-    fn build_stroke_manipulation_panel(ui: &Ui, states: &mut States) {
-        ui.window(im_str!("Hello world"))
-            .size((300.0, 100.0), ImGuiSetCond_FirstUseEver)
-            .build(|| {
-                // Build slider to manipulate stroke anchors
-                for (stroke_index, stroke) in &mut states.stroke_records.iter_mut().enumerate() {
-                    ui.text(im_str!("Stroke no.{}", stroke_index));
-                    ui.separator();
-
-                    let need_update = &mut states.need_update_brush_preview;
-                    for (index, anchor) in stroke.anchors.iter_mut().enumerate() {
-                        *need_update |= ui.slider_float(im_str!("{}-{}", stroke_index, index),
-                                          &mut anchor.pressure,
-                                          0.0,
-                                          1.0)
-                            .build();
+            let rand_color = [rng.gen_range(0.0..1.0),
+                              rng.gen_range(0.0..1.0),
+                              rng.gen_range(0.0..1.0),
+                              rng.gen_range(0.0..1.0)];
+
+            history::execute(states,
+                             Command::ChangeColor {
+                                 layer: 0,
+                                 stroke_index: None,
+                                 old: old_color,
+                                 new: rand_color,
+                             });
+        }
+
+        ui.separator();
+        ui.text(im_str!("ink left: {:.1} / {:.1}",
+                        states.ink_reservoir,
+                        ink::capacity(states)));
+        ui.text(im_str!("hold D or right click palette to dip"));
+        Slider::new(im_str!("full refill time"), 0.0..=5.0).build(ui, &mut states.dip_refill_time);
+    }
+
+    fn build_selection_panel(ui: &Ui, states: &mut States) {
+        let index = match selection::selected_stroke(states) {
+            Some(index) => index,
+            None => {
+                ui.text(im_str!("no stroke selected, left click one on canvas"));
+                return;
+            }
+        };
+
+        ui.text(im_str!("stroke no.{}", index));
+
+        let layer = states.active_layer;
+        let old_color = states.strokes()[index].color;
+        if ColorEdit::new(im_str!("stroke color"), &mut states.layers[layer].strokes[index].color)
+            .build(ui) {
+            let new_color = states.strokes()[index].color;
+            states.history.record(Command::ChangeColor {
+                layer,
+                stroke_index: Some(index),
+                old: old_color,
+                new: new_color,
+            });
+            states.need_update_brush_preview = true;
+        }
+
+        if ui.button(im_str!("delete"), [0., 0.]) {
+            selection::delete_selected(states);
+        }
+
+        ui.same_line(0.);
+        if ui.button(im_str!("duplicate"), [0., 0.]) {
+            selection::duplicate_selected(states);
+        }
+
+        if ui.button(im_str!("apply current brush"), [0., 0.]) {
+            selection::apply_brush_to_selected(states);
+        }
+    }
+
+    fn build_watercolor_panel(ui: &Ui, states: &mut States) {
+        let need_update = &mut states.need_update_brush_preview;
+        *need_update |= Slider::new(im_str!("iterations"), 1..=200)
+            .build(ui, &mut states.watercolor_iterations);
+
+        *need_update |= Slider::new(im_str!("water quantity"), 0.0..=1.0)
+            .build(ui, &mut states.watercolor_water_quantity);
+
+        *need_update |= Slider::new(im_str!("pigment quantity"), 0.0..=1.0)
+            .build(ui, &mut states.watercolor_pigment_quantity);
+
+        *need_update |= Slider::new(im_str!("viscosity"), 0.0..=0.25)
+            .build(ui, &mut states.watercolor_viscosity);
+
+        *need_update |= Slider::new(im_str!("viscous drag"), 0.0..=0.5)
+            .build(ui, &mut states.watercolor_viscous_drag);
+
+        *need_update |= Slider::new(im_str!("edge darkening"), 0.0..=0.1)
+            .build(ui, &mut states.watercolor_edge_darkening);
+
+        *need_update |= Slider::new(im_str!("pigment density"), 0.0..=0.5)
+            .build(ui, &mut states.watercolor_pigment_density);
+
+        *need_update |= Slider::new(im_str!("staining power"), 0.1..=10.0)
+            .build(ui, &mut states.watercolor_staining_power);
+
+        *need_update |= Slider::new(im_str!("granulation"), 0.0..=1.0)
+            .build(ui, &mut states.watercolor_granulation);
+
+        *need_update |= Slider::new(im_str!("capillary absorption"), 0.0..=0.1)
+            .build(ui, &mut states.watercolor_capillary_absorption);
+    }
+
+    fn build_stroke_manipulation_panel(ui: &Ui, states: &States) {
+        Window::new(im_str!("Anchors"))
+            .size([300.0, 200.0], Condition::FirstUseEver)
+            .build(ui, || {
+                ui.text(im_str!("drag anchor to move it"));
+                ui.text(im_str!("shift + click on stroke to insert anchor"));
+                ui.text(im_str!("alt + click on anchor to delete it"));
+                ui.text(im_str!("scroll over anchor to change pressure"));
+                ui.separator();
+
+                if let Some((stroke_index, anchor_index)) = states.hovered_anchor {
+                    if let Some(anchor) = states.strokes()
+                        .get(stroke_index)
+                        .and_then(|stroke| stroke.anchors.get(anchor_index)) {
+                        ui.text(im_str!("hovered: stroke no.{} anchor no.{} pressure {:.2}",
+                                        stroke_index,
+                                        anchor_index,
+                                        anchor.pressure));
                     }
                 }
             });
     }
 
-    fn build_parameters_control_panel(ui: &Ui, states: &mut States) {
-        ui.slider_float(im_str!("poke interval"),
-                          &mut states.max_recording_cooldown,
-                          0.033,
-                          1.0)
-            .build();
+    fn build_pressure_curve_panel(ui: &Ui, states: &mut States) {
+        Window::new(im_str!("Pressure Curves"))
+            .size([300.0, 300.0], Condition::FirstUseEver)
+            .build(ui, || {
+                let response = &mut states.pressure_response;
 
-        let need_update = &mut states.need_update_brush_preview;
-        *need_update |= ui.slider_float(im_str!("stroke line radius"),
-                          &mut states.stroke_line_radius,
-                          0.1,
-                          1.0)
-            .build();
+                let mut changed = false;
+                changed |= build_pressure_curve_editor(ui, "width", &mut response.width);
+                changed |= build_pressure_curve_editor(ui, "ink flow", &mut response.ink_flow);
+                changed |= build_pressure_curve_editor(ui, "opacity", &mut response.opacity);
 
-        *need_update |= ui.slider_float(im_str!("max brush width"),
-                          &mut states.max_brush_width,
-                          1.0,
-                          50.0)
-            .build();
+                states.need_update_brush_preview |= changed;
+            });
+    }
 
-        *need_update |= ui.slider_float(im_str!("initial ink quantity"),
-                          &mut states.initial_ink_quantity,
-                          0.0,
-                          100.0)
-            .build();
+    /// Plot of `curve` followed by sliders of its control points, returns whether it changed.
+    fn build_pressure_curve_editor(ui: &Ui, name: &str, curve: &mut PressureCurve) -> bool {
+        const PLOT_SAMPLES: usize = 64;
 
-        *need_update |= ui.slider_float(im_str!("ink quantity friction"),
-                          &mut states.ink_quantity_friction,
-                          0.001,
-                          0.01)
-            .build();
+        if !ui.collapsing_header(&im_str!("{}", name)).build() {
+            return false;
+        }
 
-        *need_update |= ui.slider_float(im_str!("stroke speed factor"),
-                          &mut states.stroke_speed_factor,
-                          0.1,
-                          5.0)
+        let samples: Vec<f32> = (0..PLOT_SAMPLES)
+            .map(|i| curve.evaluate(i as f32 / (PLOT_SAMPLES - 1) as f32))
+            .collect();
+        ui.plot_lines(&im_str!("##{}", name), &samples)
+            .scale_min(0.0)
+            .scale_max(1.0)
+            .graph_size([0., 80.])
             .build();
 
-        *need_update |= ui.slider_float(im_str!("stroke interpolation accuracy"),
-                          &mut states.stroke_interpolation_accuracy,
-                          1.,
-                          50.)
-            .build();
+        let mut changed = false;
+        let mut removed_index = None;
+
+        let last_index = curve.points().len() - 1;
+        for index in 0..last_index + 1 {
+            let mut point = curve.points()[index];
+
+            // Not short-circuited, both sliders have to be built.
+            if Slider::new(&im_str!("in##{}-{}", name, index), 0.0..=1.0)
+                .build(ui, &mut point[0]) |
+               Slider::new(&im_str!("out##{}-{}", name, index), 0.0..=1.0)
+                .build(ui, &mut point[1]) {
+                curve.set_point(index, point);
+                changed = true;
+            }
+
+            if index > 0 && index < last_index &&
+               ui.small_button(&im_str!("remove##{}-{}", name, index)) {
+                removed_index = Some(index);
+            }
+        }
+
+        if let Some(index) = removed_index {
+            curve.remove_point(index);
+            changed = true;
+        }
+
+        if ui.small_button(&im_str!("add point##{}", name)) {
+            curve.insert_point();
+            changed = true;
+        }
+
+        changed
+    }
+
+    fn build_history_panel(ui: &Ui, states: &mut States) {
+        Window::new(im_str!("History"))
+            .size([300.0, 200.0], Condition::FirstUseEver)
+            .build(ui, || {
+                let descriptions: Vec<String> = states.history
+                    .commands()
+                    .iter()
+                    .map(|command| command.description())
+                    .collect();
+                let cursor = states.history.cursor();
+
+                if Selectable::new(im_str!("0: initial"))
+                    .selected(cursor == 0)
+                    .build(ui) {
+                    history::jump_to(states, 0);
+                }
+
+                for (index, description) in descriptions.iter().enumerate() {
+                    if Selectable::new(&im_str!("{}: {}", index + 1, description))
+                        .selected(cursor == index + 1)
+                        .build(ui) {
+                        history::jump_to(states, index + 1);
+                    }
+                }
+            });
+    }
+
+    fn build_preset_panel(ui: &Ui, states: &mut States) {
+        Window::new(im_str!("Brush Presets"))
+            .size([300.0, 300.0], Condition::FirstUseEver)
+            .build(ui, || {
+                let mut applied = None;
+                for (index, preset) in states.brush_presets.iter().enumerate() {
+                    if Selectable::new(&im_str!("{}##preset{}", preset.name, index))
+                        .selected(states.selected_preset == Some(index))
+                        .build(ui) {
+                        applied = Some(index);
+                    }
+                }
+
+                // Clicking a preset makes it the current brush.
+                if let Some(index) = applied {
+                    let preset = states.brush_presets[index].clone();
+                    preset::apply(states, &preset);
+                    states.selected_preset = Some(index);
+                }
+
+                ui.separator();
+                build_preset_library_buttons(ui, states);
+
+                ui.separator();
+                build_preset_file_buttons(ui, states);
+
+                if let Some(ref err) = states.preset_error {
+                    ui.text_colored([1.0, 0.3, 0.3, 1.0], im_str!("{}", err));
+                }
+            });
+    }
+
+    /// Replay how the painting was made, with play, pause, scrub and speed controls, and export
+    /// it frame by frame.
+    fn build_timeline_panel(ui: &Ui, states: &mut States) {
+        Window::new(im_str!("Timeline"))
+            .size([300.0, 250.0], Condition::FirstUseEver)
+            .build(ui, || {
+                if states.replay.is_none() {
+                    if ui.button(im_str!("replay painting"), [0., 0.]) {
+                        states.replay = Some(Replay::new(&states.layers));
+                        states.need_update_brush_preview = true;
+                    }
+                } else {
+                    build_replay_controls(ui, states);
+                }
+
+                ui.separator();
+                build_build_up_export_controls(ui, states);
+            });
+    }
+
+    fn build_replay_controls(ui: &Ui, states: &mut States) {
+        let mut changed = false;
+        let mut stop = false;
+        if let Some(ref mut replay) = states.replay {
+            let play_label = if replay.is_paused {
+                im_str!("play")
+            } else {
+                im_str!("pause")
+            };
+            if ui.button(play_label, [0., 0.]) {
+                // Playing a finished replay starts it over.
+                if replay.is_paused && replay.is_finished() {
+                    replay.seek(0.0);
+                    changed = true;
+                }
+                replay.is_paused = !replay.is_paused;
+            }
+
+            ui.same_line(0.);
+            if ui.button(im_str!("restart"), [0., 0.]) {
+                replay.seek(0.0);
+                replay.is_paused = false;
+                changed = true;
+            }
+
+            ui.same_line(0.);
+            stop = ui.button(im_str!("stop"), [0., 0.]);
+
+            // Scrubbing pauses, so the painting stays where it was dragged to.
+            let mut time = replay.time;
+            if Slider::new(im_str!("time"), 0.0..=replay.duration)
+                .display_format(im_str!("%.1f s"))
+                .build(ui, &mut time) {
+                replay.seek(time);
+                replay.is_paused = true;
+                changed = true;
+            }
+
+            Slider::new(im_str!("speed"), 0.1..=10.0)
+                .display_format(im_str!("%.2fx"))
+                .power(2.0)
+                .build(ui, &mut replay.speed);
+            ui.same_line(0.);
+            if ui.button(im_str!("1x"), [0., 0.]) {
+                replay.speed = 1.0;
+            }
+
+            let (started, total) = replay.stroke_progress();
+            ui.text(im_str!("stroke {} of {}, {:.1} of {:.1} s",
+                            started,
+                            total,
+                            replay.time,
+                            replay.duration));
+        }
+
+        if stop {
+            states.replay = None;
+        }
+        if changed || stop {
+            states.need_update_brush_preview = true;
+        }
+    }
+
+    /// Export how the painting was built up, see `build_up`.
+    fn build_build_up_export_controls(ui: &Ui, states: &mut States) {
+        {
+            let settings = &mut states.build_up_settings;
+
+            let mut format_index = settings.format as usize;
+            ComboBox::new(im_str!("format"))
+                .build_simple_string(ui,
+                                     &mut format_index,
+                                     &[im_str!("animated gif"), im_str!("png sequence")]);
+            settings.format = match format_index {
+                0 => FrameFormat::Gif,
+                1 => FrameFormat::PngSequence,
+                _ => panic!("should not happen"),
+            };
+
+            let mut timing_index = settings.timing as usize;
+            ComboBox::new(im_str!("frames"))
+                .build_simple_string(ui,
+                                     &mut timing_index,
+                                     &[im_str!("every stroke"), im_str!("fixed interval")]);
+            settings.timing = match timing_index {
+                0 => FrameTiming::EveryStroke,
+                1 => FrameTiming::FixedInterval,
+                _ => panic!("should not happen"),
+            };
+
+            if settings.timing == FrameTiming::FixedInterval {
+                Slider::new(im_str!("interval"), 0.05..=5.0)
+                    .display_format(im_str!("%.2f s"))
+                    .build(ui, &mut settings.interval);
+            }
+            if settings.format == FrameFormat::Gif {
+                Slider::new(im_str!("frame delay"), 0.02..=2.0)
+                    .display_format(im_str!("%.2f s"))
+                    .build(ui, &mut settings.frame_delay);
+            }
+        }
+
+        ui.input_text(im_str!("output"), &mut states.build_up_path_input).build();
+        if ui.button(im_str!("export build-up"), [0., 0.]) {
+            states.need_export_build_up = true;
+        }
+
+        if let Some(ref status) = states.build_up_status {
+            ui.text(im_str!("{}", status));
+        }
+    }
+
+    fn build_texture_set_panel(ui: &Ui, states: &mut States) {
+        Window::new(im_str!("Brush Textures"))
+            .size([300.0, 200.0], Condition::FirstUseEver)
+            .build(ui, || {
+                ui.text(im_str!("sets in \"{}\"", texture_set::TEXTURE_SET_DIR));
+
+                // Only strokes drawn afterwards use the chosen set.
+                let mut chosen = None;
+                for name in &states.texture_set_names {
+                    if Selectable::new(&im_str!("{}", name))
+                        .selected(*name == states.texture_set)
+                        .build(ui) {
+                        chosen = Some(name.clone());
+                    }
+                }
+                if let Some(name) = chosen {
+                    states.texture_set = name;
+                }
+
+                if !states.texture_set_names.contains(&states.texture_set) {
+                    ui.text(im_str!("current set \"{}\" not found", states.texture_set));
+                }
+
+                // Pick up new sets, and changes to sets already loaded.
+                if ui.small_button(im_str!("rescan")) {
+                    states.texture_set_names = texture_set::available_texture_sets();
+                    states.need_reload_texture_sets = true;
+                }
+
+                ui.separator();
+                build_texture_generator_controls(ui, states);
+
+                if let Some(ref err) = states.texture_set_error {
+                    ui.text_colored([1.0, 0.3, 0.3, 1.0], im_str!("{}", err));
+                }
+            });
+    }
+
+    fn build_texture_generator_controls(ui: &Ui, states: &mut States) {
+        {
+            let params = &mut states.texture_generator;
+
+            let mut bristle_count = params.bristle_count as i32;
+            Slider::new(im_str!("bristle count"), 1..=64).build(ui, &mut bristle_count);
+            params.bristle_count = bristle_count as u32;
+
+            Slider::new(im_str!("dryness"), 0.0..=1.0).build(ui, &mut params.dryness);
+            Slider::new(im_str!("noise scale"), 1.0..=64.0).build(ui, &mut params.noise_scale);
+            Slider::new(im_str!("fiber direction"), 0.0..=180.0)
+                .build(ui, &mut params.fiber_direction);
+
+            let mut level_count = params.level_count as i32;
+            Slider::new(im_str!("levels"), 1..=texture_generator::MAX_LEVEL_COUNT as i32)
+                .build(ui, &mut level_count);
+            params.level_count = level_count as u32;
+
+            let mut seed = params.seed as i32;
+            Slider::new(im_str!("seed"), 0..=1000).build(ui, &mut seed);
+            params.seed = seed as u32;
+        }
+
+        ui.input_text(im_str!("set name"), &mut states.texture_generator_name_input).build();
+
+        let name = states.texture_generator_name_input.to_str().to_string();
+
+        // Generated sets are stored as parameters and picked up like any other set.
+        if ui.small_button(im_str!("save and use")) {
+            if name.is_empty() || name == texture_set::BUILTIN_TEXTURE_SET {
+                states.texture_set_error = Some("generated set needs a new name".to_string());
+            } else {
+                let path = Path::new(texture_set::TEXTURE_SET_DIR).join(format!("{}.json", name));
+                match texture_generator::save_parameters(&states.texture_generator, path) {
+                    Ok(_) => {
+                        states.texture_set_names = texture_set::available_texture_sets();
+                        states.need_reload_texture_sets = true;
+                        states.texture_set = name;
+                        states.texture_set_error = None;
+                    }
+                    Err(err) => {
+                        states.texture_set_error = Some(format!("failed to save: {}", err));
+                    }
+                }
+            }
+        }
+
+        ui.input_text(im_str!("png directory"), &mut states.texture_export_path_input).build();
+
+        let dir = states.texture_export_path_input.to_str().to_string();
+
+        if ui.small_button(im_str!("export png")) {
+            states.texture_set_error = if dir.is_empty() {
+                Some("export needs a directory".to_string())
+            } else {
+                let levels = texture_generator::generate(&states.texture_generator);
+                texture_generator::export_png(&levels, &dir)
+                    .err()
+                    .map(|err| format!("failed to export: {}", err))
+            };
+        }
+    }
+
+    fn build_preset_library_buttons(ui: &Ui, states: &mut States) {
+        ui.input_text(im_str!("name"), &mut states.preset_name_input).build();
+
+        let name = states.preset_name_input.to_str().to_string();
+
+        if ui.small_button(im_str!("save current brush")) {
+            if name.is_empty() {
+                states.preset_error = Some("preset needs a name".to_string());
+            } else {
+                let preset = BrushPreset::from_states(states, name.clone());
+                preset::merge_into(&mut states.brush_presets, vec![preset]);
+                states.selected_preset = states.brush_presets
+                    .iter()
+                    .position(|preset| preset.name == name);
+                states.preset_error = None;
+            }
+        }
+
+        ui.same_line(0.);
+        if ui.small_button(im_str!("delete preset")) {
+            if let Some(index) = states.selected_preset.take() {
+                if index < states.brush_presets.len() {
+                    states.brush_presets.remove(index);
+                }
+            }
+        }
+    }
+
+    fn build_preset_file_buttons(ui: &Ui, states: &mut States) {
+        ui.input_text(im_str!("preset file"), &mut states.preset_path_input).build();
+
+        let path = states.preset_path_input.to_str().to_string();
+
+        let export = |states: &mut States, presets: Vec<BrushPreset>| {
+            states.preset_error = match preset::export(&presets, &path) {
+                Ok(_) => None,
+                Err(err) => Some(format!("failed to export: {}", err)),
+            };
+        };
+
+        if ui.small_button(im_str!("import")) {
+            match preset::import(&path) {
+                Ok(presets) => {
+                    preset::merge_into(&mut states.brush_presets, presets);
+                    states.preset_error = None;
+                }
+                Err(err) => states.preset_error = Some(format!("failed to import: {}", err)),
+            }
+        }
+
+        ui.same_line(0.);
+        if ui.small_button(im_str!("export selected")) {
+            let selected = states.selected_preset
+                .and_then(|index| states.brush_presets.get(index))
+                .cloned();
+            match selected {
+                Some(preset) => export(states, vec![preset]),
+                None => states.preset_error = Some("no preset selected".to_string()),
+            }
+        }
+
+        ui.same_line(0.);
+        if ui.small_button(im_str!("export all")) {
+            let presets = states.brush_presets.clone();
+            export(states, presets);
+        }
+    }
+
+    /// Layers are listed top first, the way they are stacked on canvas.
+    fn build_layers_panel(ui: &Ui, states: &mut States) {
+        Window::new(im_str!("Layers"))
+            .size([300.0, 300.0], Condition::FirstUseEver)
+            .build(ui, || {
+                for index in (0..states.layers.len()).rev() {
+                    let label = {
+                        let properties = &states.layers[index].properties;
+                        format!("{}{}{}",
+                                properties.name,
+                                if properties.visible { "" } else { " (hidden)" },
+                                if properties.locked { " (locked)" } else { "" })
+                    };
+
+                    if Selectable::new(&im_str!("{}##layer{}", label, index))
+                        .selected(index == states.active_layer)
+                        .build(ui) {
+                        states.set_active_layer(index);
+                    }
+                }
+
+                ui.separator();
+                build_layer_order_buttons(ui, states);
+
+                ui.separator();
+                build_layer_properties(ui, states);
+            });
+    }
+
+    fn build_layer_order_buttons(ui: &Ui, states: &mut States) {
+        let index = states.active_layer;
+        let count = states.layers.len();
+
+        if ui.small_button(im_str!("add")) {
+            let name = (0..)
+                .map(|n| format!("layer {}", n))
+                .find(|name| states.layers.iter().all(|layer| &layer.properties.name != name))
+                .unwrap();
+            let render_mode = states.active_layer().properties.render_mode;
+
+            history::execute(states,
+                             Command::AddLayer {
+                                 index: index + 1,
+                                 layer: Layer::new(name, render_mode),
+                             });
+        }
+
+        // At least one layer is always kept.
+        ui.same_line(0.);
+        if ui.small_button(im_str!("delete")) && count > 1 {
+            let layer = states.active_layer().clone();
+            history::execute(states,
+                             Command::DeleteLayer {
+                                 index,
+                                 layer,
+                             });
+        }
+
+        ui.same_line(0.);
+        if ui.small_button(im_str!("up")) && index + 1 < count {
+            history::execute(states,
+                             Command::MoveLayer {
+                                 from: index,
+                                 to: index + 1,
+                             });
+        }
+
+        ui.same_line(0.);
+        if ui.small_button(im_str!("down")) && index > 0 {
+            history::execute(states,
+                             Command::MoveLayer {
+                                 from: index,
+                                 to: index - 1,
+                             });
+        }
+
+        // Strokes take render mode of their layer, so merging layers of different modes would
+        // repaint strokes of the upper one.
+        let same_render_mode = index > 0 &&
+                               states.layers[index].properties.render_mode ==
+                               states.layers[index - 1].properties.render_mode;
+
+        ui.same_line(0.);
+        if ui.small_button(im_str!("merge down")) && same_render_mode {
+            let upper = states.layers[index].clone();
+            let lower = states.layers[index - 1].clone();
+            history::execute(states,
+                             Command::MergeLayerDown {
+                                 index,
+                                 upper,
+                                 lower,
+                             });
+        }
+
+        if index > 0 && !same_render_mode {
+            ui.text(im_str!("layer below has another render mode, can not merge down"));
+        }
+    }
+
+    fn build_layer_properties(ui: &Ui, states: &mut States) {
+        let index = states.active_layer;
+        let old = states.layers[index].properties.clone();
+
+        {
+            let properties = &mut states.layers[index].properties;
+
+            ui.checkbox(im_str!("visible"), &mut properties.visible);
+            ui.checkbox(im_str!("locked"), &mut properties.locked);
+            Slider::new(im_str!("opacity"), 0.0..=1.0).build(ui, &mut properties.opacity);
+
+            let mut blend_mode_index = properties.blend_mode as usize;
+            ComboBox::new(im_str!("blend mode"))
+                .build_simple_string(ui,
+                                     &mut blend_mode_index,
+                                     &[im_str!("normal"),
+                                       im_str!("multiply"),
+                                       im_str!("screen"),
+                                       im_str!("darken"),
+                                       im_str!("lighten")]);
+            properties.blend_mode = match blend_mode_index {
+                0 => BlendMode::Normal,
+                1 => BlendMode::Multiply,
+                2 => BlendMode::Screen,
+                3 => BlendMode::Darken,
+                4 => BlendMode::Lighten,
+                _ => panic!("should not happen"),
+            };
+
+            let mut render_mode_index = properties.render_mode as usize;
+            ComboBox::new(im_str!("render mode"))
+                .build_simple_string(ui,
+                                     &mut render_mode_index,
+                                     &[im_str!("black & white"), im_str!("colored")]);
+            properties.render_mode = match render_mode_index {
+                0 => RenderMode::BlackAndWhite,
+                1 => RenderMode::Colored,
+                _ => panic!("should not happen"),
+            };
+        }
+
+        let new = states.layers[index].properties.clone();
+        if old != new {
+            states.history.record(Command::ChangeLayer {
+                index,
+                old,
+                new,
+            });
+            states.need_update_brush_preview = true;
+        }
+    }
+
+    fn build_parameters_control_panel(ui: &Ui, states: &mut States) {
+        Slider::new(im_str!("poke interval"), 0.033..=1.0)
+            .build(ui, &mut states.max_recording_cooldown);
+
+        ui.checkbox(im_str!("simulate pressure from speed"),
+                    &mut states.simulate_pressure);
+
+        Slider::new(im_str!("min anchor distance"), 0.0..=20.0)
+            .build(ui, &mut states.min_anchor_distance);
+
+        Slider::new(im_str!("simplify tolerance"), 0.0..=5.0)
+            .build(ui, &mut states.simplify_tolerance);
+
+        // Brush is snapshotted into every stroke, so it only restyles strokes drawn from now on.
+        Slider::new(im_str!("max brush width"), 1.0..=50.0)
+            .build(ui, &mut states.max_brush_width);
+
+        Slider::new(im_str!("initial ink quantity"), 0.0..=100.0)
+            .build(ui, &mut states.initial_ink_quantity);
+
+        Slider::new(im_str!("ink quantity friction"), 0.001..=0.01)
+            .build(ui, &mut states.ink_quantity_friction);
+
+        // 0 bristles paints with one solid tip.
+        let mut bristle_count = states.bristle_count as i32;
+        Slider::new(im_str!("bristles"), 0..=bristle::MAX_BRISTLE_COUNT as i32)
+            .build(ui, &mut bristle_count);
+        states.bristle_count = bristle_count as u32;
+
+        Slider::new(im_str!("bristle spread"), 0.0..=1.0)
+            .build(ui, &mut states.bristle_spread);
+
+        Slider::new(im_str!("bristle split"), 0.0..=1.0)
+            .build(ui, &mut states.bristle_split);
+
+        let need_update = &mut states.need_update_brush_preview;
+        *need_update |= Slider::new(im_str!("stroke line radius"), 0.1..=1.0)
+            .build(ui, &mut states.stroke_line_radius);
+
+        // Absorbent paper drains brush faster and darkens strokes, see `ink::segment_deposit`.
+        *need_update |= Slider::new(im_str!("paper absorbency"), 0.0..=1.0)
+            .build(ui, &mut states.paper_absorbency);
+
+        *need_update |= Slider::new(im_str!("stroke speed factor"), 0.1..=5.0)
+            .build(ui, &mut states.stroke_speed_factor);
+
+        *need_update |= Slider::new(im_str!("stroke interpolation accuracy"), 1.0..=50.0)
+            .build(ui, &mut states.stroke_interpolation_accuracy);
     }
 }
 
-fn setup_imgui_key(imgui: &mut ImGui) {
-    imgui.set_imgui_key(ImGuiKey::Tab, 0);
-    imgui.set_imgui_key(ImGuiKey::LeftArrow, 1);
-    imgui.set_imgui_key(ImGuiKey::RightArrow, 2);
-    imgui.set_imgui_key(ImGuiKey::UpArrow, 3);
-    imgui.set_imgui_key(ImGuiKey::DownArrow, 4);
-    imgui.set_imgui_key(ImGuiKey::PageUp, 5);
-    imgui.set_imgui_key(ImGuiKey::PageDown, 6);
-    imgui.set_imgui_key(ImGuiKey::Home, 7);
-    imgui.set_imgui_key(ImGuiKey::End, 8);
-    imgui.set_imgui_key(ImGuiKey::Delete, 9);
-    imgui.set_imgui_key(ImGuiKey::Backspace, 10);
-    imgui.set_imgui_key(ImGuiKey::Enter, 11);
-    imgui.set_imgui_key(ImGuiKey::Escape, 12);
-    imgui.set_imgui_key(ImGuiKey::A, 13);
-    imgui.set_imgui_key(ImGuiKey::C, 14);
-    imgui.set_imgui_key(ImGuiKey::V, 15);
-    imgui.set_imgui_key(ImGuiKey::X, 16);
-    imgui.set_imgui_key(ImGuiKey::Y, 17);
-    imgui.set_imgui_key(ImGuiKey::Z, 18);
+fn setup_imgui_key(io: &mut Io) {
+    io[ImGuiKey::Tab] = 0;
+    io[ImGuiKey::LeftArrow] = 1;
+    io[ImGuiKey::RightArrow] = 2;
+    io[ImGuiKey::UpArrow] = 3;
+    io[ImGuiKey::DownArrow] = 4;
+    io[ImGuiKey::PageUp] = 5;
+    io[ImGuiKey::PageDown] = 6;
+    io[ImGuiKey::Home] = 7;
+    io[ImGuiKey::End] = 8;
+    io[ImGuiKey::Delete] = 9;
+    io[ImGuiKey::Backspace] = 10;
+    io[ImGuiKey::Enter] = 11;
+    io[ImGuiKey::Escape] = 12;
+    io[ImGuiKey::A] = 13;
+    io[ImGuiKey::C] = 14;
+    io[ImGuiKey::V] = 15;
+    io[ImGuiKey::X] = 16;
+    io[ImGuiKey::Y] = 17;
+    io[ImGuiKey::Z] = 18;
 }
 
 #[derive(Default)]
@@ -208,7 +1028,7 @@ struct MouseButtonStates {
 }
 
 pub struct Toolkits {
-    imgui: ImGui,
+    imgui: Context,
     imgui_renderer: Renderer,
 
     last_frame: Instant,
@@ -216,117 +1036,138 @@ pub struct Toolkits {
     mouse_pos: (i32, i32),
     mouse_button_states: MouseButtonStates,
     mouse_wheel: f32,
+
+    want_capture_mouse: bool,
+    want_capture_keyboard: bool,
 }
 
 impl Toolkits {
-    pub fn new<F: Facade>(window: &F) -> RendererResult<Toolkits> {
-        let mut imgui = ImGui::init();
+    pub fn new<F: Facade>(window: &F) -> Result<Toolkits, RendererError> {
+        let mut imgui = Context::create();
         let imgui_renderer = Renderer::init(&mut imgui, window)?;
 
-        setup_imgui_key(&mut imgui);
+        setup_imgui_key(imgui.io_mut());
 
         let result = Toolkits {
-            imgui: imgui,
-            imgui_renderer: imgui_renderer,
+            imgui,
+            imgui_renderer,
 
             last_frame: Instant::now(),
 
             mouse_pos: (0, 0),
             mouse_button_states: MouseButtonStates::default(),
             mouse_wheel: 0.,
+
+            want_capture_mouse: false,
+            want_capture_keyboard: false,
         };
 
         Ok(result)
     }
 
+    /// Whether mouse is over a window of gui, so canvas should ignore it.
+    pub fn want_capture_mouse(&self) -> bool {
+        self.want_capture_mouse
+    }
+
+    /// Whether a widget of gui, e.g. a text field, takes keyboard input, so canvas shortcuts
+    /// should ignore it.
+    pub fn want_capture_keyboard(&self) -> bool {
+        self.want_capture_keyboard
+    }
+
     pub fn handle_event(&mut self, event: &Input) {
-        fn set_key(imgui: &mut ImGui, key: &Key, pressed: bool) {
+        fn set_key(io: &mut Io, key: Key, pressed: bool) {
             match key {
-                &Key::Tab => imgui.set_key(0, pressed),
-                &Key::Left => imgui.set_key(1, pressed),
-                &Key::Right => imgui.set_key(2, pressed),
-                &Key::Up => imgui.set_key(3, pressed),
-                &Key::Down => imgui.set_key(4, pressed),
-                &Key::PageUp => imgui.set_key(5, pressed),
-                &Key::PageDown => imgui.set_key(6, pressed),
-                &Key::Home => imgui.set_key(7, pressed),
-                &Key::End => imgui.set_key(8, pressed),
-                &Key::Delete => imgui.set_key(9, pressed),
-                &Key::Backspace => imgui.set_key(10, pressed),
-                &Key::Return => imgui.set_key(11, pressed),
-                &Key::Escape => imgui.set_key(12, pressed),
-                &Key::A => imgui.set_key(13, pressed),
-                &Key::C => imgui.set_key(14, pressed),
-                &Key::V => imgui.set_key(15, pressed),
-                &Key::X => imgui.set_key(16, pressed),
-                &Key::Y => imgui.set_key(17, pressed),
-                &Key::Z => imgui.set_key(18, pressed),
-                &Key::LCtrl | &Key::RCtrl => imgui.set_key_ctrl(pressed),
-                &Key::LShift | &Key::RShift => imgui.set_key_shift(pressed),
-                &Key::LAlt | &Key::RAlt => imgui.set_key_alt(pressed),
+                Key::Tab => io.keys_down[0] = pressed,
+                Key::Left => io.keys_down[1] = pressed,
+                Key::Right => io.keys_down[2] = pressed,
+                Key::Up => io.keys_down[3] = pressed,
+                Key::Down => io.keys_down[4] = pressed,
+                Key::PageUp => io.keys_down[5] = pressed,
+                Key::PageDown => io.keys_down[6] = pressed,
+                Key::Home => io.keys_down[7] = pressed,
+                Key::End => io.keys_down[8] = pressed,
+                Key::Delete => io.keys_down[9] = pressed,
+                Key::Backspace => io.keys_down[10] = pressed,
+                Key::Return => io.keys_down[11] = pressed,
+                Key::Escape => io.keys_down[12] = pressed,
+                Key::A => io.keys_down[13] = pressed,
+                Key::C => io.keys_down[14] = pressed,
+                Key::V => io.keys_down[15] = pressed,
+                Key::X => io.keys_down[16] = pressed,
+                Key::Y => io.keys_down[17] = pressed,
+                Key::Z => io.keys_down[18] = pressed,
+                Key::LCtrl | Key::RCtrl => io.key_ctrl = pressed,
+                Key::LShift | Key::RShift => io.key_shift = pressed,
+                Key::LAlt | Key::RAlt => io.key_alt = pressed,
                 _ => {}
             }
-        };
+        }
 
         fn set_mouse_button(mouse_button_states: &mut MouseButtonStates,
-                            mouse_button: &MouseButton,
+                            mouse_button: MouseButton,
                             pressed: bool) {
             match mouse_button {
-                &MouseButton::Left => mouse_button_states.left = pressed,
-                &MouseButton::Right => mouse_button_states.right = pressed,
-                &MouseButton::Middle => mouse_button_states.middle = pressed,
+                MouseButton::Left => mouse_button_states.left = pressed,
+                MouseButton::Right => mouse_button_states.right = pressed,
+                MouseButton::Middle => mouse_button_states.middle = pressed,
                 _ => {}
             }
-        };
-
-        match event {
-            &Input::Press(Button::Keyboard(ref key)) => {
-                set_key(&mut self.imgui, &key, true);
-            }
+        }
 
-            &Input::Release(Button::Keyboard(ref key)) => {
-                set_key(&mut self.imgui, &key, false);
+        match *event {
+            Input::Button(ButtonArgs { state, button: Button::Keyboard(key), .. }) => {
+                set_key(self.imgui.io_mut(), key, state == ButtonState::Press);
             }
 
-            &Input::Move(Motion::MouseCursor(x, y)) => self.mouse_pos = (x as i32, y as i32),
-            &Input::Press(Button::Mouse(ref button)) => {
-                set_mouse_button(&mut self.mouse_button_states, button, true)
-            }
-            &Input::Release(Button::Mouse(ref button)) => {
-                set_mouse_button(&mut self.mouse_button_states, button, false)
+            Input::Move(Motion::MouseCursor([x, y])) => self.mouse_pos = (x as i32, y as i32),
+            Input::Button(ButtonArgs { state, button: Button::Mouse(button), .. }) => {
+                set_mouse_button(&mut self.mouse_button_states,
+                                 button,
+                                 state == ButtonState::Press)
             }
-            &Input::Move(Motion::MouseScroll(_x, y)) => {
+            Input::Move(Motion::MouseScroll([_x, y])) => {
                 self.mouse_wheel = y as f32;
             }
 
-            &Input::Text(ref string) => {
+            Input::Text(ref string) => {
                 if let Some(c) = string.chars().last() {
-                    self.imgui.add_input_character(c);
+                    self.imgui.io_mut().add_input_character(c);
                 }
             }
             _ => {}
         }
     }
 
-    pub fn render<S: Surface>(&mut self,
-                              surface: &mut S,
-                              window: &glium::glutin::Window,
-                              states: &mut States) {
+    pub fn render<S: Surface>(&mut self, surface: &mut S, args: &RenderArgs, states: &mut States) {
         let delta = self.get_time_elapse();
 
-        self.update_mouse();
+        {
+            // Gui is laid out in points, and drawn in pixels.
+            let io = self.imgui.io_mut();
+            io.display_size = [args.window_size[0] as f32, args.window_size[1] as f32];
+            io.display_framebuffer_scale = [args.draw_size[0] as f32 /
+                                            io.display_size[0].max(1.0),
+                                            args.draw_size[1] as f32 /
+                                            io.display_size[1].max(1.0)];
+            io.delta_time = delta;
+        }
 
-        let (size_points, size_pixels) = {
-            let size_points = window.get_inner_size_points().unwrap();
-            let size_pixels = window.get_inner_size_pixels().unwrap();
+        self.update_mouse();
 
-            ((size_points), size_pixels)
-        };
-        let ui = self.imgui.frame(size_points, size_pixels, delta);
+        let ui = self.imgui.frame();
 
         gui::build_ui(&ui, states);
+        self.want_capture_mouse = ui.io().want_capture_mouse;
+        self.want_capture_keyboard = ui.io().want_capture_keyboard;
+
+        // Slider drags keep merging into one history entry until mouse is released.
+        if !self.mouse_button_states.left {
+            states.history.commit_pending();
+        }
 
-        self.imgui_renderer.render(surface, ui).unwrap();
+        self.imgui_renderer.render(surface, ui.render()).unwrap();
     }
 }
 
@@ -340,16 +1181,16 @@ impl Toolkits {
         delta.as_secs() as f32 + delta.subsec_nanos() as f32 / 1_000_000_000.0
     }
 
+    // Piston reports mouse in points already, same as gui.
     fn update_mouse(&mut self) {
-        let scale = self.imgui.display_framebuffer_scale();
-        self.imgui.set_mouse_pos(self.mouse_pos.0 as f32 / scale.0,
-                                 self.mouse_pos.1 as f32 / scale.1);
-        self.imgui.set_mouse_down(&[self.mouse_button_states.left,
-                                    self.mouse_button_states.right,
-                                    self.mouse_button_states.middle,
-                                    false,
-                                    false]);
-        self.imgui.set_mouse_wheel(self.mouse_wheel / scale.1);
+        let io = self.imgui.io_mut();
+        io.mouse_pos = [self.mouse_pos.0 as f32, self.mouse_pos.1 as f32];
+        io.mouse_down = [self.mouse_button_states.left,
+                         self.mouse_button_states.right,
+                         self.mouse_button_states.middle,
+                         false,
+                         false];
+        io.mouse_wheel = self.mouse_wheel;
         self.mouse_wheel = 0.0;
     }
 }